@@ -0,0 +1,704 @@
+//! Forward-mode automatic differentiation with dual numbers
+//!
+//! A dual number `re + du*ε` with `ε² = 0` carries a value together with its derivative along
+//! one direction. Evaluating a residual written generically over the scalar type with dual
+//! arguments gives the exact directional derivative of the residual, without the truncation
+//! and round-off errors of difference quotients.
+
+use std::cmp::Ordering;
+use std::num::FpCategory;
+use std::ops::*;
+
+use ndarray::*;
+use num_traits::{Float, FloatConst, Num, NumCast, One, ToPrimitive, Zero};
+
+use crate::traits::*;
+
+/// Dual number `re + du*ε`
+///
+/// Comparisons and the classification methods of `Float` consider only the real part, so that
+/// branches in the residual are taken as for the real arguments.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Dual<T> {
+    /// real part
+    pub re: T,
+    /// dual part, i.e. the derivative
+    pub du: T,
+}
+
+impl<T: Float> Dual<T> {
+    pub fn new(re: T, du: T) -> Self {
+        Dual { re, du }
+    }
+
+    /// A constant, with zero derivative
+    pub fn constant(re: T) -> Self {
+        Dual { re, du: T::zero() }
+    }
+
+    /// The value of the function with the derivative `df` at re. A constant stays constant, even
+    /// where df is infinite.
+    fn chain(self, re: T, df: T) -> Self {
+        if self.du == T::zero() {
+            Dual::constant(re)
+        } else {
+            Dual {
+                re,
+                du: df * self.du,
+            }
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for Dual<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.re == other.re
+    }
+}
+
+impl<T: PartialOrd> PartialOrd for Dual<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.re.partial_cmp(&other.re)
+    }
+}
+
+impl<T: Float> Neg for Dual<T> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Dual::new(-self.re, -self.du)
+    }
+}
+
+impl<T: Float> Add for Dual<T> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Dual::new(self.re + rhs.re, self.du + rhs.du)
+    }
+}
+
+impl<T: Float> Sub for Dual<T> {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Dual::new(self.re - rhs.re, self.du - rhs.du)
+    }
+}
+
+impl<T: Float> Mul for Dual<T> {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Dual::new(self.re * rhs.re, self.du * rhs.re + self.re * rhs.du)
+    }
+}
+
+impl<T: Float> Div for Dual<T> {
+    type Output = Self;
+
+    fn div(self, rhs: Self) -> Self {
+        let re = self.re / rhs.re;
+        Dual::new(re, (self.du - re * rhs.du) / rhs.re)
+    }
+}
+
+impl<T: Float> Rem for Dual<T> {
+    type Output = Self;
+
+    fn rem(self, rhs: Self) -> Self {
+        // a % b = a - b*trunc(a/b), where trunc has zero derivative almost everywhere
+        let re = self.re % rhs.re;
+        Dual::new(re, self.du - rhs.du * (self.re / rhs.re).trunc())
+    }
+}
+
+/// Implements the binary operator by reference, and the assignment operators by value and
+/// by reference, in terms of the binary operator by value
+macro_rules! forward_ops {
+    ($($op:ident, $method:ident, $op_assign:ident, $method_assign:ident;)+) => {$(
+        impl<'a, T: Float> $op<&'a Dual<T>> for Dual<T> {
+            type Output = Self;
+
+            fn $method(self, rhs: &'a Self) -> Self {
+                $op::$method(self, *rhs)
+            }
+        }
+
+        impl<T: Float> $op_assign for Dual<T> {
+            fn $method_assign(&mut self, rhs: Self) {
+                *self = $op::$method(*self, rhs);
+            }
+        }
+
+        impl<'a, T: Float> $op_assign<&'a Dual<T>> for Dual<T> {
+            fn $method_assign(&mut self, rhs: &'a Self) {
+                *self = $op::$method(*self, *rhs);
+            }
+        }
+    )+};
+}
+
+forward_ops! {
+    Add, add, AddAssign, add_assign;
+    Sub, sub, SubAssign, sub_assign;
+    Mul, mul, MulAssign, mul_assign;
+    Div, div, DivAssign, div_assign;
+    Rem, rem, RemAssign, rem_assign;
+}
+
+impl<T: Float> Zero for Dual<T> {
+    fn zero() -> Self {
+        Dual::constant(T::zero())
+    }
+
+    fn is_zero(&self) -> bool {
+        self.re.is_zero()
+    }
+}
+
+impl<T: Float> One for Dual<T> {
+    fn one() -> Self {
+        Dual::constant(T::one())
+    }
+}
+
+impl<T: Float> Num for Dual<T> {
+    type FromStrRadixErr = T::FromStrRadixErr;
+
+    fn from_str_radix(s: &str, radix: u32) -> Result<Self, Self::FromStrRadixErr> {
+        T::from_str_radix(s, radix).map(Dual::constant)
+    }
+}
+
+impl<T: Float> ToPrimitive for Dual<T> {
+    fn to_i64(&self) -> Option<i64> {
+        self.re.to_i64()
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        self.re.to_u64()
+    }
+
+    fn to_f32(&self) -> Option<f32> {
+        self.re.to_f32()
+    }
+
+    fn to_f64(&self) -> Option<f64> {
+        self.re.to_f64()
+    }
+}
+
+impl<T: Float> NumCast for Dual<T> {
+    fn from<N: ToPrimitive>(n: N) -> Option<Self> {
+        <T as NumCast>::from(n).map(Dual::constant)
+    }
+}
+
+impl<T: Float + 'static> ScalarOperand for Dual<T> {}
+
+/// Implements constant-valued methods in terms of those of T
+macro_rules! constants {
+    ($($method:ident,)+) => {$(
+        #[allow(non_snake_case)]
+        fn $method() -> Self {
+            Dual::constant(T::$method())
+        }
+    )+};
+}
+
+impl<T: Float + FloatConst> FloatConst for Dual<T> {
+    constants! {
+        E, FRAC_1_PI, FRAC_1_SQRT_2, FRAC_2_PI, FRAC_2_SQRT_PI, FRAC_PI_2, FRAC_PI_3, FRAC_PI_4,
+        FRAC_PI_6, FRAC_PI_8, LN_10, LN_2, LOG10_E, LOG2_E, PI, SQRT_2,
+    }
+}
+
+impl<T: Float> Float for Dual<T> {
+    constants! {
+        nan, infinity, neg_infinity, neg_zero, min_value, min_positive_value, max_value, epsilon,
+    }
+
+    fn is_nan(self) -> bool {
+        self.re.is_nan()
+    }
+
+    fn is_infinite(self) -> bool {
+        self.re.is_infinite()
+    }
+
+    fn is_finite(self) -> bool {
+        self.re.is_finite()
+    }
+
+    fn is_normal(self) -> bool {
+        self.re.is_normal()
+    }
+
+    fn classify(self) -> FpCategory {
+        self.re.classify()
+    }
+
+    fn floor(self) -> Self {
+        Dual::constant(self.re.floor())
+    }
+
+    fn ceil(self) -> Self {
+        Dual::constant(self.re.ceil())
+    }
+
+    fn round(self) -> Self {
+        Dual::constant(self.re.round())
+    }
+
+    fn trunc(self) -> Self {
+        Dual::constant(self.re.trunc())
+    }
+
+    fn fract(self) -> Self {
+        Dual::new(self.re.fract(), self.du)
+    }
+
+    fn abs(self) -> Self {
+        if self.re.is_sign_negative() {
+            -self
+        } else {
+            self
+        }
+    }
+
+    fn signum(self) -> Self {
+        Dual::constant(self.re.signum())
+    }
+
+    fn is_sign_positive(self) -> bool {
+        self.re.is_sign_positive()
+    }
+
+    fn is_sign_negative(self) -> bool {
+        self.re.is_sign_negative()
+    }
+
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        self * a + b
+    }
+
+    fn recip(self) -> Self {
+        let re = self.re.recip();
+        self.chain(re, -re * re)
+    }
+
+    fn powi(self, n: i32) -> Self {
+        if n == 0 {
+            return Self::one();
+        }
+        let n_re = T::from(n).unwrap();
+        self.chain(self.re.powi(n), n_re * self.re.powi(n - 1))
+    }
+
+    fn powf(self, n: Self) -> Self {
+        let re = self.re.powf(n.re);
+        // d(x^n) = n x^(n-1) dx + x^n ln(x) dn, where each term is omitted for a constant x or n,
+        // so that e.g. x^0.5 at x = 0 and negative x with constant n are allowed
+        let mut du = T::zero();
+        if self.du != T::zero() {
+            du = n.re * self.re.powf(n.re - T::one()) * self.du;
+        }
+        if n.du != T::zero() {
+            du = du + re * self.re.ln() * n.du;
+        }
+        Dual::new(re, du)
+    }
+
+    fn sqrt(self) -> Self {
+        let re = self.re.sqrt();
+        self.chain(re, (re + re).recip())
+    }
+
+    fn exp(self) -> Self {
+        let re = self.re.exp();
+        self.chain(re, re)
+    }
+
+    fn exp2(self) -> Self {
+        let re = self.re.exp2();
+        self.chain(re, re * T::from(2).unwrap().ln())
+    }
+
+    fn ln(self) -> Self {
+        self.chain(self.re.ln(), self.re.recip())
+    }
+
+    fn log(self, base: Self) -> Self {
+        self.ln() / base.ln()
+    }
+
+    fn log2(self) -> Self {
+        self.chain(self.re.log2(), (self.re * T::from(2).unwrap().ln()).recip())
+    }
+
+    fn log10(self) -> Self {
+        self.chain(
+            self.re.log10(),
+            (self.re * T::from(10).unwrap().ln()).recip(),
+        )
+    }
+
+    fn to_degrees(self) -> Self {
+        let re = self.re.to_degrees();
+        self.chain(re, T::one().to_degrees())
+    }
+
+    fn to_radians(self) -> Self {
+        let re = self.re.to_radians();
+        self.chain(re, T::one().to_radians())
+    }
+
+    fn max(self, other: Self) -> Self {
+        if self.re.is_nan() || other.re > self.re {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn min(self, other: Self) -> Self {
+        if self.re.is_nan() || other.re < self.re {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn abs_sub(self, other: Self) -> Self {
+        if self.re > other.re {
+            self - other
+        } else {
+            Self::zero()
+        }
+    }
+
+    fn cbrt(self) -> Self {
+        let re = self.re.cbrt();
+        self.chain(re, (T::from(3).unwrap() * re * re).recip())
+    }
+
+    fn hypot(self, other: Self) -> Self {
+        let re = self.re.hypot(other.re);
+        if self.du == T::zero() && other.du == T::zero() {
+            return Dual::constant(re);
+        }
+        Dual::new(re, (self.re * self.du + other.re * other.du) / re)
+    }
+
+    fn sin(self) -> Self {
+        self.chain(self.re.sin(), self.re.cos())
+    }
+
+    fn cos(self) -> Self {
+        self.chain(self.re.cos(), -self.re.sin())
+    }
+
+    fn tan(self) -> Self {
+        let re = self.re.tan();
+        self.chain(re, T::one() + re * re)
+    }
+
+    fn asin(self) -> Self {
+        let df = (T::one() - self.re * self.re).sqrt().recip();
+        self.chain(self.re.asin(), df)
+    }
+
+    fn acos(self) -> Self {
+        let df = -(T::one() - self.re * self.re).sqrt().recip();
+        self.chain(self.re.acos(), df)
+    }
+
+    fn atan(self) -> Self {
+        self.chain(self.re.atan(), (T::one() + self.re * self.re).recip())
+    }
+
+    fn atan2(self, other: Self) -> Self {
+        let (y, x) = (self.re, other.re);
+        if self.du == T::zero() && other.du == T::zero() {
+            return Dual::constant(y.atan2(x));
+        }
+        Dual::new(y.atan2(x), (x * self.du - y * other.du) / (x * x + y * y))
+    }
+
+    fn sin_cos(self) -> (Self, Self) {
+        (self.sin(), self.cos())
+    }
+
+    fn exp_m1(self) -> Self {
+        self.chain(self.re.exp_m1(), self.re.exp())
+    }
+
+    fn ln_1p(self) -> Self {
+        self.chain(self.re.ln_1p(), (T::one() + self.re).recip())
+    }
+
+    fn sinh(self) -> Self {
+        self.chain(self.re.sinh(), self.re.cosh())
+    }
+
+    fn cosh(self) -> Self {
+        self.chain(self.re.cosh(), self.re.sinh())
+    }
+
+    fn tanh(self) -> Self {
+        let re = self.re.tanh();
+        self.chain(re, T::one() - re * re)
+    }
+
+    fn asinh(self) -> Self {
+        let df = (self.re * self.re + T::one()).sqrt().recip();
+        self.chain(self.re.asinh(), df)
+    }
+
+    fn acosh(self) -> Self {
+        let df = (self.re * self.re - T::one()).sqrt().recip();
+        self.chain(self.re.acosh(), df)
+    }
+
+    fn atanh(self) -> Self {
+        self.chain(self.re.atanh(), (T::one() - self.re * self.re).recip())
+    }
+
+    fn integer_decode(self) -> (u64, i16, i8) {
+        self.re.integer_decode()
+    }
+}
+
+/// Calculate the iteration matrix `jac = dF/dy + cj*dF/dy'` of a `GenericResidual` model
+/// exactly, by evaluating the residual with dual numbers once per column j, with derivative
+/// direction e_j in y and cj*e_j in y'.
+pub fn jacobian<M, S1, S2, S3>(
+    model: &mut M,
+    tt: M::Scalar,
+    cj: M::Scalar,
+    yy: &ArrayBase<S1, Ix1>,
+    yp: &ArrayBase<S2, Ix1>,
+    jac: &mut ArrayBase<S3, Ix2>,
+) -> Result<(), ModelError>
+where
+    M: GenericResidual,
+    M::Scalar: FloatConst + num_traits::NumAssignRef + ScalarOperand + std::fmt::Debug,
+    S1: Data<Elem = M::Scalar>,
+    S2: Data<Elem = M::Scalar>,
+    S3: DataMut<Elem = M::Scalar>,
+{
+    let mut yy_dual = yy.mapv(Dual::constant);
+    let mut yp_dual = yp.mapv(Dual::constant);
+    let mut rr_dual = Array::zeros(yy.len());
+
+    for j in 0..yy.len() {
+        yy_dual[j].du = M::Scalar::one();
+        yp_dual[j].du = cj;
+        model.residual_generic(
+            Dual::constant(tt),
+            yy_dual.view(),
+            yp_dual.view(),
+            rr_dual.view_mut(),
+        )?;
+        yy_dual[j].du = M::Scalar::zero();
+        yp_dual[j].du = M::Scalar::zero();
+
+        Zip::from(jac.column_mut(j))
+            .and(&rr_dual)
+            .apply(|jac, rr: &Dual<M::Scalar>| *jac = rr.du);
+    }
+    Ok(())
+}
+
+/// Calculate the product `jv = (dF/dy + cj*dF/dy') v` of a `GenericResidual` model exactly, by
+/// a single evaluation of the residual with dual numbers in the direction (v, cj*v).
+pub fn jac_times_vec<M, S1, S2, S3, S4>(
+    model: &mut M,
+    tt: M::Scalar,
+    cj: M::Scalar,
+    yy: &ArrayBase<S1, Ix1>,
+    yp: &ArrayBase<S2, Ix1>,
+    v: &ArrayBase<S3, Ix1>,
+    jv: &mut ArrayBase<S4, Ix1>,
+) -> Result<(), ModelError>
+where
+    M: GenericResidual,
+    M::Scalar: FloatConst + num_traits::NumAssignRef + ScalarOperand + std::fmt::Debug,
+    S1: Data<Elem = M::Scalar>,
+    S2: Data<Elem = M::Scalar>,
+    S3: Data<Elem = M::Scalar>,
+    S4: DataMut<Elem = M::Scalar>,
+{
+    let yy_dual = Array::from_shape_fn(yy.len(), |i| Dual::new(yy[i], v[i]));
+    let yp_dual = Array::from_shape_fn(yp.len(), |i| Dual::new(yp[i], cj * v[i]));
+    let mut rr_dual = Array::zeros(yy.len());
+    model.residual_generic(
+        Dual::constant(tt),
+        yy_dual.view(),
+        yp_dual.view(),
+        rr_dual.view_mut(),
+    )?;
+    Zip::from(jv)
+        .and(&rr_dual)
+        .apply(|jv, rr: &Dual<M::Scalar>| *jv = rr.du);
+    Ok(())
+}
+
+/// Adapter implementing `IdaModel` for a `GenericResidual` model, with the exact Jacobian and
+/// Jacobian-vector product computed with dual numbers by `jacobian()` and `jac_times_vec()`.
+#[derive(Clone, Debug)]
+pub struct AutoJacobian<M> {
+    pub model: M,
+}
+
+impl<M> AutoJacobian<M> {
+    pub fn new(model: M) -> Self {
+        AutoJacobian { model }
+    }
+}
+
+impl<M: GenericResidual> ModelSpec for AutoJacobian<M> {
+    type Scalar = M::Scalar;
+    type Dim = M::Dim;
+
+    fn model_size(&self) -> usize {
+        self.model.model_size()
+    }
+}
+
+impl<M> IdaModel for AutoJacobian<M>
+where
+    M: GenericResidual,
+    M::Scalar: FloatConst + num_traits::NumAssignRef + ScalarOperand + std::fmt::Debug,
+{
+    fn residual<S1, S2, S3>(
+        &mut self,
+        tt: Self::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>,
+    {
+        self.model
+            .residual_generic(tt, yy.view(), yp.view(), rr.view_mut())
+    }
+
+    fn jacobian<S1, S2, S3, S4>(
+        &mut self,
+        tt: Self::Scalar,
+        cj: Self::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        jac: &mut ArrayBase<S4, Ix2>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>,
+    {
+        Some(jacobian(&mut self.model, tt, cj, yy, yp, jac))
+    }
+
+    fn jac_times_vec<S1, S2, S3, S4>(
+        &mut self,
+        tt: Self::Scalar,
+        cj: Self::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        v: &ArrayBase<S3, Ix1>,
+        jv: &mut ArrayBase<S4, Ix1>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>,
+    {
+        Some(jac_times_vec(&mut self.model, tt, cj, yy, yp, v, jv))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorenz63::Lorenz63;
+    use nearly_eq::*;
+
+    #[test]
+    fn test_dual_derivatives() {
+        let x = Dual::new(0.7f64, 1.0);
+        let y = Dual::new(1.3f64, 0.0);
+
+        // Compare with the analytic derivatives d/dx at x = 0.7
+        let cases = [
+            ((x * x).sin() / y, 2.0 * 0.7 * (0.49f64).cos() / 1.3),
+            (x.exp() * x.ln(), 0.7f64.exp() * (0.7f64.ln() + 1.0 / 0.7)),
+            (x.sqrt().powi(3), 1.5 * 0.7f64.sqrt()),
+            (x.powf(y), 1.3 * 0.7f64.powf(0.3)),
+            (y.powf(x), 1.3f64.powf(0.7) * 1.3f64.ln()),
+            (x.atan2(y), 1.3 / (0.49 + 1.69)),
+            (x.tanh(), 1.0 - 0.7f64.tanh().powi(2)),
+            (x.recip(), -1.0 / 0.49),
+        ];
+        for (f, df) in cases.iter() {
+            assert_nearly_eq!(f.du, *df, 1e-14);
+        }
+        assert_eq!((-x).abs().du, 1.0);
+        assert!(x < y && x.max(y) == y);
+
+        // Constants stay constant where the derivative is infinite
+        let zero = Dual::constant(0.0f64);
+        let one = Dual::constant(1.0f64);
+        for f in [
+            zero.sqrt(),
+            zero.cbrt(),
+            zero.powf(Dual::constant(0.5)),
+            zero.ln(),
+            one.asin(),
+            (-one).acos(),
+            one.acosh(),
+            zero.hypot(zero),
+            zero.atan2(zero),
+        ]
+        .iter()
+        {
+            assert_eq!(f.du, 0.0);
+        }
+    }
+
+    #[test]
+    fn test_auto_jacobian() {
+        let mut hand = Lorenz63::default();
+        let mut auto = AutoJacobian::new(Lorenz63::default());
+        let (yy, yp) = (array![1.0, 2.0, 3.0], array![0.5, -1.0, 2.0]);
+        let mut rr = Array::zeros(3);
+        let mut rr_auto = Array::zeros(3);
+        hand.residual(0.0, &yy, &yp, &mut rr).unwrap();
+        auto.residual(0.0, &yy, &yp, &mut rr_auto).unwrap();
+        assert_eq!(rr, rr_auto);
+
+        let mut jac = Array::zeros((3, 3));
+        let mut jac_auto = Array::zeros((3, 3));
+        hand.jacobian(0.0, 2.5, &yy, &yp, &rr, &mut jac)
+            .unwrap()
+            .unwrap();
+        auto.jacobian(0.0, 2.5, &yy, &yp, &rr, &mut jac_auto)
+            .unwrap()
+            .unwrap();
+        assert_eq!(jac, jac_auto);
+
+        let v = array![0.3, -0.2, 1.1];
+        let mut jv = Array::zeros(3);
+        auto.jac_times_vec(0.0, 2.5, &yy, &yp, &v, &mut jv)
+            .unwrap()
+            .unwrap();
+        assert_nearly_eq!(jv, jac.dot(&v), 1e-14);
+    }
+}
@@ -0,0 +1,244 @@
+//! Two-dimensional heat equation on the unit square, discretized by central differences on an
+//! M by M grid with homogeneous Dirichlet boundary conditions (the `idaHeat2D_bnd` example from
+//! Sundials).
+//!
+//! The interior points give the differential equations u' = u_xx + u_yy, and the boundary points
+//! the algebraic equations u = 0. With the unknowns ordered as `u[i + M*j]`, the Jacobian has
+//! upper and lower half-bandwidths M.
+//!
+//! For the `Bbd` preconditioner, the grid is decomposed into `nsub` subdomains of whole grid
+//! rows (consecutive j).
+
+use ndarray::*;
+
+use crate::linear::{BandMatrix, SparseMatrix};
+use crate::traits::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Heat2D {
+    /// number of grid points in each direction
+    pub mgrid: usize,
+    /// number of subdomains
+    pub nsub: usize,
+    /// 1 / dx^2
+    coeff: f64,
+}
+
+impl Heat2D {
+    pub fn new(mgrid: usize) -> Self {
+        let dx = 1.0 / (mgrid - 1) as f64;
+        Heat2D {
+            mgrid,
+            nsub: 1,
+            coeff: 1.0 / (dx * dx),
+        }
+    }
+
+    /// Half-bandwidth of the Jacobian
+    pub fn bandwidth(&self) -> usize {
+        self.mgrid
+    }
+
+    fn is_boundary(&self, i: usize, j: usize) -> bool {
+        i == 0 || j == 0 || i == self.mgrid - 1 || j == self.mgrid - 1
+    }
+
+    /// Initial profile u = 16 x (1 - x) y (1 - y), with consistent u'
+    pub fn initial_values(&mut self) -> (Array1<f64>, Array1<f64>) {
+        let m = self.mgrid;
+        let dx = 1.0 / (m - 1) as f64;
+        let uu = Array::from_shape_fn(m * m, |loc| {
+            let x = (loc % m) as f64 * dx;
+            let y = (loc / m) as f64 * dx;
+            16.0 * x * (1.0 - x) * y * (1.0 - y)
+        });
+
+        // up = -F(uu, 0) in the interior, and 0 on the boundary
+        let mut up = Array::zeros(m * m);
+        let mut rr = Array::zeros(m * m);
+        self.residual(0.0, &uu, &up, &mut rr).unwrap();
+        for loc in 0..m * m {
+            if !self.is_boundary(loc % m, loc / m) {
+                up[loc] = -rr[loc];
+            }
+        }
+        (uu, up)
+    }
+
+    /// Differential (interior) and algebraic (boundary) components
+    pub fn id(&self) -> Array1<bool> {
+        let m = self.mgrid;
+        Array::from_shape_fn(m * m, |loc| !self.is_boundary(loc % m, loc / m))
+    }
+
+    /// Grid rows of subdomain k
+    fn subdomain_rows(&self, k: usize) -> std::ops::Range<usize> {
+        k * self.mgrid / self.nsub..(k + 1) * self.mgrid / self.nsub
+    }
+
+    /// Residual of the equations on the grid rows `rows`, into `rr[loc - M*rows.start]`
+    fn residual_rows<S1, S2, S3>(
+        &self,
+        rows: std::ops::Range<usize>,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &mut ArrayBase<S3, Ix1>,
+    ) where
+        S1: Data<Elem = f64>,
+        S2: Data<Elem = f64>,
+        S3: DataMut<Elem = f64>,
+    {
+        let m = self.mgrid;
+        let offset = m * rows.start;
+        for j in rows {
+            for i in 0..m {
+                let loc = i + m * j;
+                rr[loc - offset] = if self.is_boundary(i, j) {
+                    yy[loc]
+                } else {
+                    let dif1 = yy[loc - 1] + yy[loc + 1] - 2.0 * yy[loc];
+                    let dif2 = yy[loc - m] + yy[loc + m] - 2.0 * yy[loc];
+                    yp[loc] - self.coeff * (dif1 + dif2)
+                };
+            }
+        }
+    }
+
+    /// Calls `set(row, col, value)` for each non-zero of the iteration matrix
+    fn fill_jacobian(&self, cj: f64, mut set: impl FnMut(usize, usize, f64)) {
+        let m = self.mgrid;
+        for j in 0..m {
+            for i in 0..m {
+                let loc = i + m * j;
+                if self.is_boundary(i, j) {
+                    set(loc, loc, 1.0);
+                } else {
+                    set(loc, loc, cj + 4.0 * self.coeff);
+                    set(loc, loc - 1, -self.coeff);
+                    set(loc, loc + 1, -self.coeff);
+                    set(loc, loc - m, -self.coeff);
+                    set(loc, loc + m, -self.coeff);
+                }
+            }
+        }
+    }
+}
+
+impl ModelSpec for Heat2D {
+    type Scalar = f64;
+    type Dim = Ix1;
+
+    fn model_size(&self) -> usize {
+        self.mgrid * self.mgrid
+    }
+}
+
+impl IdaModel for Heat2D {
+    fn residual<S1, S2, S3>(
+        &mut self,
+        _tt: f64,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>,
+    {
+        // rr = u on the boundary, and up - (central difference) in the interior
+        self.residual_rows(0..self.mgrid, yy, yp, rr);
+        Ok(())
+    }
+
+    fn jacobian<S1, S2, S3, S4>(
+        &mut self,
+        _tt: f64,
+        cj: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        jac: &mut ArrayBase<S4, Ix2>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>,
+    {
+        self.fill_jacobian(cj, |i, j, v| jac[[i, j]] = v);
+        Some(Ok(()))
+    }
+}
+
+impl BandJacobian for Heat2D {
+    fn jacobian_band<S1, S2, S3>(
+        &mut self,
+        _tt: f64,
+        cj: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        jac: &mut BandMatrix<Self::Scalar>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+    {
+        self.fill_jacobian(cj, |i, j, v| jac.set(i, j, v));
+        Some(Ok(()))
+    }
+}
+
+impl LocalResidual for Heat2D {
+    fn subdomain_sizes(&self) -> Vec<usize> {
+        (0..self.nsub)
+            .map(|k| self.mgrid * self.subdomain_rows(k).len())
+            .collect()
+    }
+
+    /// The residual on the subdomain, i.e. G = F
+    fn local_residual<S1, S2, S3>(
+        &mut self,
+        block: usize,
+        _tt: f64,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        gval: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>,
+    {
+        self.residual_rows(self.subdomain_rows(block), yy, yp, gval);
+        Ok(())
+    }
+}
+
+impl SparseJacobian for Heat2D {
+    fn sparsity_pattern(&self) -> Vec<(usize, usize)> {
+        let mut pattern = Vec::new();
+        self.fill_jacobian(0.0, |i, j, _| pattern.push((i, j)));
+        pattern
+    }
+
+    fn jacobian_sparse<S1, S2, S3>(
+        &mut self,
+        _tt: f64,
+        cj: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        jac: &mut SparseMatrix<Self::Scalar>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+    {
+        self.fill_jacobian(cj, |i, j, v| jac.set(i, j, v));
+        Some(Ok(()))
+    }
+}
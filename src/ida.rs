@@ -3,6 +3,7 @@ use ndarray::*;
 
 use failure::Fail;
 
+use crate::linear::{Dense, JacobianPattern, LinearContext, LinearSolver, LinearSolverError};
 use crate::traits::*;
 
 /// hmax_inv default value
@@ -30,11 +31,22 @@ const EPCON: f64 = 0.33;
 const MAXBACKS: u32 = 100;
 /// constant for updating Jacobian/preconditioner
 const XRATE: f64 = 0.25;
+/// max. number of Newton iterations per step attempt
+const MAXIT: u32 = 4;
+/// max. Newton convergence rate before the iteration is declared divergent
+const RATEMAX: f64 = 0.9;
+/// alpha-condition constant of the IC linesearch
+const ALPHALS: f64 = 0.0001;
+/// factor for comparing the step size against hmin
+const ONEPSM: f64 = 1.000001;
 
 #[derive(Debug, Fail)]
-enum IdaError {
+pub enum IdaError {
     // LSETUP_ERROR_NONRECVR
-    // IDA_ERR_FAIL
+    /// IDA_ERR_FAIL
+    #[fail(display = "The error test occurred too many times or with |h| = hmin")]
+    ErrorTestFail {},
+
     /// IDA_REP_RES_ERR:
     #[fail(
         display = "The user's residual function repeatedly returned a recoverable error flag, but the solver was unable to recover"
@@ -42,8 +54,19 @@ enum IdaError {
     RepeatedResidualError {},
 
     /// IDA_ILL_INPUT
-    #[fail(display = "One of the input arguments was illegal. See printed message")]
-    IllegalInput {},
+    #[fail(display = "One of the input arguments was illegal: {}", msg)]
+    IllegalInput { msg: String },
+
+    /// IDA_TOO_MUCH_WORK
+    #[fail(
+        display = "At t = {}, the maximum number of steps was taken before reaching tout",
+        t
+    )]
+    TooMuchWork { t: f64 },
+
+    /// IDA_TOO_MUCH_ACC
+    #[fail(display = "At t = {}, too much accuracy was requested", t)]
+    TooMuchAccuracy { t: f64 },
 
     /// IDA_LINIT_FAIL
     #[fail(display = "The linear solver's init routine failed")]
@@ -82,7 +105,7 @@ enum IdaError {
     /// IDA_CONSTR_FAIL
     /// The inequality constraints were violated, and the solver was unable to recover.
     #[fail(
-        display = "IDACalcIC was unable to find a solution satisfying the inequality constraints"
+        display = "The inequality constraints were violated and the solver was unable to recover"
     )]
     ConstraintFail {},
 
@@ -93,9 +116,13 @@ enum IdaError {
     LinesearchFail {},
 
     /// IDA_CONV_FAIL
-    #[fail(display = "IDACalcIC failed to get convergence of the Newton iterations")]
+    #[fail(display = "The Newton iterations failed to converge repeatedly or with |h| = hmin")]
     ConvergenceFail {},
 
+    /// IDA_RTFUNC_FAIL
+    #[fail(display = "The rootfinding function failed in an unrecoverable manner")]
+    RootFunctionFail {},
+
     ///MSG_BAD_K
     #[fail(display = "Illegal value for k.")]
     BadK {},
@@ -108,9 +135,251 @@ enum IdaError {
     BadTimeValue { t: f64, tdiff: f64, tcurr: f64 },
 }
 
+/// Recoverable failures of a single step attempt. These are not returned to the user; instead the
+/// step is retried with a smaller step size, see `handle_n_flag()`.
+#[derive(Debug, Fail)]
+enum IdaRecoverable {
+    /// IDA_NCONV_RECVR
+    #[fail(display = "The Newton iteration failed to converge")]
+    NonlinearConvergence {},
+
+    /// IDA_LSETUP_RECVR
+    #[fail(display = "The linear solver's setup routine had a recoverable error")]
+    LinearSetup {},
+
+    /// IDA_LSOLVE_RECVR
+    #[fail(display = "The linear solver's solve routine had a recoverable error")]
+    LinearSolve {},
+
+    /// ERROR_TEST_FAIL
+    #[fail(display = "The local error test failed")]
+    ErrorTest {},
+
+    /// IDA_RES_RECVR
+    #[fail(display = "The residual function had a recoverable error")]
+    Residual {},
+
+    /// IDA_CONSTR_RECVR
+    #[fail(display = "The corrected solution violated the inequality constraints")]
+    Constraint {},
+}
+
+/// Recoverable failures of the IC nonlinear solver. These are retried with a smaller h in
+/// `calc_ic()`, and otherwise mapped to an `IdaError` by `ic_fail_flag()`.
+#[derive(Debug, Fail)]
+enum IdaIcFailure {
+    /// IC_FAIL_RECOV
+    #[fail(display = "The residual or linear solver routine had a recoverable error")]
+    FailRecov {},
+
+    /// IC_CONSTR_FAILED
+    #[fail(display = "The Newton step could not be reduced to satisfy the constraints")]
+    ConstrFailed {},
+
+    /// IC_LINESRCH_FAILED
+    #[fail(display = "The linesearch failed to find an acceptable step")]
+    LinesrchFailed {},
+
+    /// IC_CONV_FAIL
+    #[fail(display = "The Newton iteration failed to converge")]
+    ConvFail {},
+
+    /// IC_SLOW_CONVRG
+    #[fail(display = "The Newton iteration converged too slowly")]
+    SlowConvrg {},
+}
+
+/// Specifies which components are computed by `Ida::calc_ic()`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IcOption {
+    /// IDA_YA_YDP_INIT: compute the algebraic components of y and the differential components of
+    /// y', given the differential components of y. Requires the component types set by
+    /// `Ida::set_id()`.
+    YaYdpInit,
+    /// IDA_Y_INIT: compute all components of y, given y'
+    YInit,
+}
+
+/// Inequality constraint on a component of y, see `Ida::set_constraints()`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Constraint {
+    /// No constraint (0.0 in IDA)
+    None,
+    /// y >= 0 (1.0 in IDA)
+    NonNegative,
+    /// y > 0 (2.0 in IDA)
+    Positive,
+    /// y <= 0 (-1.0 in IDA)
+    NonPositive,
+    /// y < 0 (-2.0 in IDA)
+    Negative,
+}
+
+impl Constraint {
+    /// Whether the value y satisfies the constraint
+    pub fn is_satisfied<T: num_traits::Float>(self, y: T) -> bool {
+        match self {
+            Constraint::None => true,
+            Constraint::NonNegative => y >= T::zero(),
+            Constraint::Positive => y > T::zero(),
+            Constraint::NonPositive => y <= T::zero(),
+            Constraint::Negative => y < T::zero(),
+        }
+    }
+}
+
+/// Maps an `IdaRecoverable` failure to `to`, and passes any other error through. Used by the IC
+/// calculation, which handles recoverable failures differently from `Ida::step()`.
+fn map_recoverable<E: Fail>(err: failure::Error, to: E) -> failure::Error {
+    match err.downcast::<IdaRecoverable>() {
+        Ok(_) => to.into(),
+        Err(err) => err,
+    }
+}
+
+/// Specifies how `Ida::solve()` advances the solution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Task {
+    /// IDA_NORMAL: take internal steps until tout is passed, and interpolate the solution at tout
+    Normal,
+    /// IDA_ONE_STEP: take one internal step, and return the solution at the new internal time
+    OneStep,
+}
+
+/// Successful return status of `Ida::solve()`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolveStatus {
+    /// IDA_SUCCESS
+    Success,
+    /// IDA_TSTOP_RETURN: the integration stopped at tstop
+    TstopReturn,
+    /// IDA_ROOT_RETURN: a root of one or more root functions was found. Holds the indices of the
+    /// functions with a root, whose directions are given by `Ida::get_root_info()`.
+    RootReturn(Vec<usize>),
+}
+
+/// A user-supplied function that computes the error weight vector `ewt` from the current solution
+/// `y`, see `Ida::wf_tolerances()`. All weights must be positive.
+pub type ErrorWeightFn<F> = fn(
+    &F,
+    ArrayView1<<F as ModelSpec>::Scalar>,
+    ArrayViewMut1<<F as ModelSpec>::Scalar>,
+) -> Result<(), failure::Error>;
+
+/// Action taken by the integrator after an `EventFn` has handled a root
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventAction {
+    /// Continue the integration unchanged. Any changes to y and y' are discarded.
+    Continue,
+    /// Restart the integration from the modified y and y' at the root, as by `Ida::reinit()`
+    Reinit,
+    /// Restart the integration as for `Reinit`, after computing consistent initial conditions
+    /// from the modified y and y' with `Ida::calc_ic()`
+    ReinitCalcIc(IcOption),
+}
+
+/// A user-supplied event action hook, see `Ida::set_event_fn()`. It is called at each root
+/// found by `Ida::solve()`, with the model, the time of the root, the indices of the root
+/// functions with a root, and y and y' at the root. It may modify y, y', and the model, e.g. to
+/// switch a discrete mode of the model, and returns how the integration proceeds.
+pub type EventFn<F> = fn(
+    &mut F,
+    <F as ModelSpec>::Scalar,
+    &[usize],
+    ArrayViewMut1<<F as ModelSpec>::Scalar>,
+    ArrayViewMut1<<F as ModelSpec>::Scalar>,
+) -> Result<EventAction, failure::Error>;
+
+/// Optional inputs of the integrator, see `Ida::set_options()`. The defaults are those of IDA.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdaOptions<T> {
+    /// maximum order of the BDF method, in 1..=5 (IDASetMaxOrd)
+    pub maxord: usize,
+    /// maximum number of internal steps in one call to `Ida::solve()`, or 0 for no limit
+    /// (IDASetMaxNumSteps)
+    pub mxstep: u64,
+    /// initial step size, or 0 to estimate it (IDASetInitStep)
+    pub hin: T,
+    /// lower bound on the magnitude of the step size (IDASetMinStep)
+    pub hmin: T,
+    /// upper bound on the magnitude of the step size, infinite by default (IDASetMaxStep)
+    pub hmax: T,
+    /// maximum number of error test failures in one step (IDASetMaxErrTestFails)
+    pub maxnef: u64,
+    /// maximum number of nonlinear solver convergence failures in one step (IDASetMaxConvFails)
+    pub maxncf: u64,
+    /// maximum number of Newton iterations in one step attempt (IDASetMaxNonlinIters)
+    pub maxcor: u64,
+    /// safety factor in the Newton convergence test (IDASetNonlinConvCoef)
+    pub epcon: T,
+    /// safety factor in the Newton convergence test of `Ida::calc_ic()`
+    /// (IDASetNonlinConvCoefIC)
+    pub epiccon: T,
+    /// maximum number of step size tries in `Ida::calc_ic()` (IDASetMaxNumStepsIC)
+    pub maxnh: u64,
+    /// maximum number of Jacobian evaluations in `Ida::calc_ic()` (IDASetMaxNumJacsIC)
+    pub maxnj: u64,
+    /// maximum number of Newton iterations per Jacobian in `Ida::calc_ic()`
+    /// (IDASetMaxNumItersIC)
+    pub maxnit: u64,
+    /// maximum number of linesearch backtracks per Newton step in `Ida::calc_ic()`
+    /// (IDASetMaxBacksIC)
+    pub maxbacks: u64,
+    /// turn off the linesearch in `Ida::calc_ic()` (IDASetLineSearchOffIC)
+    pub lsoff: bool,
+    /// minimum scaled step length in the linesearch of `Ida::calc_ic()`
+    /// (IDASetStepToleranceIC)
+    pub steptol: T,
+    /// exclude the algebraic components from the local error tests, which requires the component
+    /// types set by `Ida::set_id()` (IDASetSuppressAlg)
+    pub suppressalg: bool,
+}
+
+impl<T: num_traits::Float> Default for IdaOptions<T> {
+    fn default() -> Self {
+        IdaOptions {
+            maxord: MAXORD_DEFAULT,
+            mxstep: MXSTEP_DEFAULT as u64,
+            hin: T::zero(),
+            hmin: T::zero(),
+            // hmax_inv = 0 is an unbounded step size
+            hmax: T::from(HMAX_INV_DEFAULT).unwrap().recip(),
+            maxnef: MXNEF as u64,
+            maxncf: MXNCF as u64,
+            maxcor: MAXIT as u64,
+            epcon: T::from(EPCON).unwrap(),
+            epiccon: T::from(0.01 * EPCON).unwrap(),
+            maxnh: MAXNH as u64,
+            maxnj: MAXNJ as u64,
+            maxnit: MAXNI as u64,
+            maxbacks: MAXBACKS as u64,
+            lsoff: false,
+            steptol: T::epsilon().powf(T::from(2.0 / 3.0).unwrap()),
+            suppressalg: false,
+        }
+    }
+}
+
+/// The tolerances used to compute the error weight vector, see `Ida::ewt_set()`
+#[derive(Debug, Clone)]
+enum Itol<F: IdaModel> {
+    /// IDA_NN: no tolerances have been specified yet
+    NN,
+    /// IDA_SS: scalar relative and absolute tolerances
+    SS { rtol: F::Scalar, atol: F::Scalar },
+    /// IDA_SV: scalar relative tolerance and a vector of absolute tolerances
+    SV {
+        rtol: F::Scalar,
+        atol: Array<F::Scalar, Ix1>,
+    },
+    /// IDA_WF: user-supplied error weight function
+    WF(ErrorWeightFn<F>),
+}
+
 /// Structure containing the parameters for the numerical integration.
+#[allow(non_snake_case)]
 #[derive(Debug, Clone)]
-pub struct Ida<F: IdaModel> {
+pub struct Ida<F: IdaModel, LS: LinearSolver<F> = Dense<<F as ModelSpec>::Scalar>> {
     f: F,
     //dt: <F::Scalar as AssociatedReal>::Real,
     //x: Array<F::Scalar, Ix1>,
@@ -133,13 +402,16 @@ pub struct Ida<F: IdaModel> {
     /// sum of reciprocals of psi values
     ida_gamma: Array1<F::Scalar>,
 
+    /// tolerances used to compute ewt
+    ida_itol: Itol<F>,
+
     // N_Vectors
     /// error weight vector
     ida_ewt: Array<F::Scalar, Ix1>,
     /// work space for y vector (= user's yret)
-    //ida_yy: Array1<<F::Scalar as AssociatedReal>::Real>,
+    ida_yy: Array<F::Scalar, Ix1>,
     /// work space for y' vector (= user's ypret)
-    //ida_yp: Array1<<F::Scalar as AssociatedReal>::Real>,
+    ida_yp: Array<F::Scalar, Ix1>,
     /// predicted y vector
     ida_yypredict: Array<F::Scalar, Ix1>,
     /// predicted y' vector
@@ -147,11 +419,11 @@ pub struct Ida<F: IdaModel> {
     /// residual vector
     ida_delta: Array<F::Scalar, Ix1>,
     /// bit vector for diff./algebraic components
-    ida_id: Array<bool, Ix1>,
+    ida_id: Option<Array<bool, Ix1>>,
     /// vector of inequality constraint options
-    //ida_constraints: Array1<<F::Scalar as AssociatedReal>::Real>,
+    ida_constraints: Array<Constraint, Ix1>,
     /// saved residual vector
-    //ida_savres: Array1<<F::Scalar as AssociatedReal>::Real>,
+    ida_savres: Array<F::Scalar, Ix1>,
     /// accumulated corrections to y vector, but set equal to estimated local errors upon successful return
     ida_ee: Array<F::Scalar, Ix1>,
 
@@ -159,14 +431,74 @@ pub struct Ida<F: IdaModel> {
     //ida_tempv1;      /* work space vector                              */
     //ida_tempv2;      /* work space vector                              */
     //ida_tempv3;      /* work space vector                              */
-    //ida_ynew;        /* work vector for y in IDACalcIC (= tempv2)      */
-    //ida_ypnew;       /* work vector for yp in IDACalcIC (= ee)         */
-    //ida_delnew;      /* work vector for delta in IDACalcIC (= phi[2])  */
-    //ida_dtemp;       /* work vector in IDACalcIC (= phi[3])            */
+    /// work vector for y in IDACalcIC
+    ida_ynew: Array<F::Scalar, Ix1>,
+    /// work vector for yp in IDACalcIC
+    ida_ypnew: Array<F::Scalar, Ix1>,
+    /// work vector for delta in IDACalcIC
+    ida_delnew: Array<F::Scalar, Ix1>,
+
+    // Variables for use by IDACalcIC
+    /// IC calculation option, see `calc_ic()`
+    ida_icopt: IcOption,
+    /// IC calc: index of the system (0 if all components are differential, 1 otherwise)
+    ida_sysindex: usize,
+    /// IC calc: time scale |tout1 - t0|
+    ida_tscale: F::Scalar,
+    /// IC calc: Newton convergence test constant
+    ida_epiccon: F::Scalar,
+    /// IC calc: max. number of h tries
+    ida_maxnh: u64,
+    /// IC calc: max. number of J tries
+    ida_maxnj: u64,
+    /// IC calc: max. number of Newton iterations
+    ida_maxnit: u64,
+    /// IC calc: max. number of backtracks per Newton step
+    ida_maxbacks: u64,
+    /// IC calc: flag to turn off the linesearch
+    ida_lsoff: bool,
+    /// IC calc: minimum step size in the linesearch
+    ida_steptol: F::Scalar,
 
     // Tstop information
     ida_tstopset: bool,
     ida_tstop: F::Scalar,
+    /// sorted times of known discontinuities, at which the integration is restarted
+    ida_tdisc: Vec<F::Scalar>,
+
+    // Rootfinding Data
+    /// number of components of g
+    ida_nrtfn: usize,
+    /// array for root information
+    ida_iroots: Array<i8, Ix1>,
+    /// array specifying direction of zero-crossing
+    ida_rootdir: Array<i8, Ix1>,
+    /// nearest endpoint of interval in root search
+    ida_tlo: F::Scalar,
+    /// farthest endpoint of interval in root search
+    ida_thi: F::Scalar,
+    /// t return value from rootfinder routine
+    ida_trout: F::Scalar,
+    /// saved array of g values at t = tlo
+    ida_glo: Array<F::Scalar, Ix1>,
+    /// saved array of g values at t = thi
+    ida_ghi: Array<F::Scalar, Ix1>,
+    /// array of g values at t = trout
+    ida_grout: Array<F::Scalar, Ix1>,
+    /// copy of tout (if NORMAL mode)
+    ida_toutc: F::Scalar,
+    /// tolerance on root location
+    ida_ttol: F::Scalar,
+    /// copy of parameter itask
+    ida_taskc: Task,
+    /// flag showing whether last step had a root
+    ida_irfnd: bool,
+    /// counter for g evaluations
+    ida_nge: u64,
+    /// array with active/inactive event functions
+    ida_gactive: Array<bool, Ix1>,
+    /// event action hook called at each root
+    ida_event_fn: Option<EventFn<F>>,
 
     // Step Data
     /// current BDF method order
@@ -196,17 +528,26 @@ pub struct Ida<F: IdaModel> {
     ida_tn: F::Scalar,
     /// value of tret previously returned by IDASolve
     ida_tretlast: F::Scalar,
+    /// tolerance scale factor (saved value)
+    ida_tolsf: F::Scalar,
     /// current value of scalar (-alphas/hh) in Jacobian
     ida_cj: F::Scalar,
     /// cj value saved from last successful step
     ida_cjlast: F::Scalar,
-    //realtype ida_cjold;    /* cj value saved from last call to lsetup           */
-    //realtype ida_cjratio;  /* ratio of cj values: cj/cjold                      */
-    //realtype ida_ss;       /* scalar used in Newton iteration convergence test  */
-    //realtype ida_oldnrm;   /* norm of previous nonlinear solver update          */
-    //realtype ida_epsNewt;  /* test constant in Newton convergence test          */
-    //realtype ida_epcon;    /* coeficient of the Newton covergence test          */
-    //realtype ida_toldel;   /* tolerance in direct test on Newton corrections    */
+    /// cj value saved from last call to lsetup
+    ida_cjold: F::Scalar,
+    /// ratio of cj values: cj/cjold
+    ida_cjratio: F::Scalar,
+    /// scalar used in Newton iteration convergence test
+    ida_ss: F::Scalar,
+    /// norm of previous nonlinear solver update
+    ida_oldnrm: F::Scalar,
+    /// test constant in Newton convergence test
+    ida_epsNewt: F::Scalar,
+    /// coeficient of the Newton covergence test
+    ida_epcon: F::Scalar,
+    /// tolerance in direct test on Newton corrections
+    ida_toldel: F::Scalar,
 
     // Limits
     /// max numer of convergence failures
@@ -219,6 +560,10 @@ pub struct Ida<F: IdaModel> {
     //ida_maxord_alloc: u64,
     /// max number of internal steps for one user call
     ida_mxstep: u64,
+    /// max. number of Newton iterations per step attempt
+    ida_maxcor: u64,
+    /// min. step size hmin (default = 0.0)
+    ida_hmin: F::Scalar,
     /// inverse of max. step size hmax (default = 0.0)
     ida_hmax_inv: F::Scalar,
 
@@ -233,14 +578,48 @@ pub struct Ida<F: IdaModel> {
     ida_netf: u64,
     /// number of Newton iterations performed
     ida_nni: u64,
+    /// number of Newton iterations in the current step attempt (or IC Newton iteration)
+    ida_mnewt: u64,
+    /// number of linear iterations performed
+    ida_nli: u64,
+    /// number of linear convergence failures
+    ida_ncfl: u64,
+    /// number of preconditioner setups
+    ida_npe: u64,
+    /// number of preconditioner solves
+    ida_nps: u64,
     /// number of lsetup calls
     ida_nsetups: u64,
+    /// number of backtrack operations in IC linesearch
+    ida_nbacktr: u64,
     // Arrays for Fused Vector Operations
     ida_cvals: Array1<F::Scalar>,
     ida_dvals: Array1<F::Scalar>,
 
-    ida_Xvecs: Array<F::Scalar, Ix2>,
-    ida_Zvecs: Array<F::Scalar, Ix2>,
+    /// linear solver for the Newton iteration
+    ida_ls: LS,
+    /// SUNTRUE once `initial_setup()` has been called
+    ida_SetupDone: bool,
+}
+
+impl<
+        F: IdaModel<
+            Scalar = impl num_traits::Float
+                         + num_traits::float::FloatConst
+                         + num_traits::NumRef
+                         + num_traits::NumAssignRef
+                         + ScalarOperand
+                         + std::fmt::Debug, /*+ IdaConst*/
+        >,
+    > Ida<F, Dense<F::Scalar>>
+{
+    /// Creates a new IdaModel given a ModelSpec, initial Arrays of yy0 and yyp, using the `Dense`
+    /// linear solver.
+    ///
+    /// *Panics" if ModelSpec::Scalar is unable to convert any constant initialization value.
+    pub fn new(f: F, yy0: Array<F::Scalar, Ix1>, yp0: Array<F::Scalar, Ix1>) -> Self {
+        Self::with_linear_solver(f, yy0, yp0, Dense::new())
+    }
 }
 
 impl<
@@ -252,15 +631,22 @@ impl<
                          + ScalarOperand
                          + std::fmt::Debug, /*+ IdaConst*/
         >,
-    > Ida<F>
+        LS: LinearSolver<F>,
+    > Ida<F, LS>
 //where
 //num_traits::float::Float + num_traits::float::FloatConst + num_traits::NumAssignRef + ScalarOperand
 //<<F as ModelSpec>::Dim as Dimension>::Larger: RemoveAxis,
 {
-    /// Creates a new IdaModel given a ModelSpec, initial Arrays of yy0 and yyp
+    /// Creates a new IdaModel given a ModelSpec, initial Arrays of yy0 and yyp, and the linear
+    /// solver `ida_ls` used in the Newton iteration.
     ///
     /// *Panics" if ModelSpec::Scalar is unable to convert any constant initialization value.
-    pub fn new(f: F, yy0: Array<F::Scalar, Ix1>, yp0: Array<F::Scalar, Ix1>) -> Self {
+    pub fn with_linear_solver(
+        f: F,
+        yy0: Array<F::Scalar, Ix1>,
+        yp0: Array<F::Scalar, Ix1>,
+        ida_ls: LS,
+    ) -> Self {
         // Initialize the phi array
         let mut ida_phi = Array::zeros(f.model_size())
             .broadcast([&[MXORDP1], yy0.shape()].concat())
@@ -272,9 +658,12 @@ impl<
         ida_phi.index_axis_mut(Axis(0), 0).assign(&yy0);
         ida_phi.index_axis_mut(Axis(0), 1).assign(&yp0);
 
+        // The root functions are supplied by the model
+        let nrtfn = f.num_roots();
+
         //IDAResFn res, realtype t0, N_Vector yy0, N_Vector yp0
         Self {
-            f: f,
+            f,
             // Set unit roundoff in IDA_mem
             // NOTE: Use F::Scalar::epsilon() instead!
             //ida_uround: UNIT_ROUNDOFF,
@@ -282,23 +671,22 @@ impl<
             // Set default values for integrator optional inputs
             //ida_res:         = NULL,
             //ida_user_data:   = NULL,
-            //ida_itol        = IDA_NN;
-            //ida_user_efun   = SUNFALSE;
-            //ida_efun        = NULL;
-            //ida_edata       = NULL;
+            ida_itol: Itol::NN,
             //ida_ehfun       = IDAErrHandler;
             //ida_eh_data     = IDA_mem;
             //ida_errfp       = stderr;
-            ida_maxord: MAXORD_DEFAULT as usize,
+            ida_maxord: MAXORD_DEFAULT,
             ida_mxstep: MXSTEP_DEFAULT as u64,
+            ida_maxcor: MAXIT as u64,
+            ida_hmin: F::Scalar::zero(),
             ida_hmax_inv: F::Scalar::from(HMAX_INV_DEFAULT).unwrap(),
             ida_hin: F::Scalar::zero(),
-            //ida_epcon       = EPCON;
+            ida_epcon: F::Scalar::from(EPCON).unwrap(),
             ida_maxnef: MXNEF as u64,
             ida_maxncf: MXNCF as u64,
             //ida_suppressalg = SUNFALSE;
-            //ida_id          = NULL;
-            //ida_constraints: Array::zeros(yy0.raw_dim()),
+            ida_id: None,
+            ida_constraints: Array::from_elem(yy0.raw_dim(), Constraint::None),
             ida_constraintsSet: false,
             ida_tstopset: false,
 
@@ -306,13 +694,13 @@ impl<
             //ida_maxord_alloc = MAXORD_DEFAULT;
 
             // Set default values for IC optional inputs
-            //ida_epiccon = PT01 * EPCON;
-            //ida_maxnh   = MAXNH;
-            //ida_maxnj   = MAXNJ;
-            //ida_maxnit  = MAXNI;
-            //ida_maxbacks  = MAXBACKS;
-            //ida_lsoff   = SUNFALSE;
-            //ida_steptol = SUNRpowerR(IDA_mem->ida_uround, TWOTHIRDS);
+            ida_epiccon: F::Scalar::from(0.01 * EPCON).unwrap(),
+            ida_maxnh: MAXNH as u64,
+            ida_maxnj: MAXNJ as u64,
+            ida_maxnit: MAXNI as u64,
+            ida_maxbacks: MAXBACKS as u64,
+            ida_lsoff: false,
+            ida_steptol: F::Scalar::epsilon().powf(F::Scalar::from(2.0 / 3.0).unwrap()),
 
             /* Initialize lrw and liw */
             //ida_lrw = 25 + 5*MXORDP1;
@@ -321,7 +709,7 @@ impl<
             /* Initialize nonlinear solver pointer */
             //IDA_mem->NLS    = NULL;
             //IDA_mem->ownNLS = SUNFALSE;
-            ida_phi: ida_phi,
+            ida_phi,
 
             ida_psi: Array::zeros(MXORDP1),
             ida_alpha: Array::zeros(MXORDP1),
@@ -330,7 +718,6 @@ impl<
             ida_gamma: Array::zeros(MXORDP1),
 
             ida_delta: Array::zeros(yy0.raw_dim()),
-            ida_id: Array::from_elem(yy0.raw_dim(), false),
 
             // Initialize all the counters and other optional output values
             ida_nst: 0,
@@ -338,26 +725,36 @@ impl<
             ida_ncfn: 0,
             ida_netf: 0,
             ida_nni: 0,
+            ida_mnewt: 0,
+            ida_nli: 0,
+            ida_ncfl: 0,
+            ida_npe: 0,
+            ida_nps: 0,
             ida_nsetups: 0,
+            ida_nbacktr: 0,
             ida_kused: 0,
             ida_hused: F::Scalar::zero(),
-            //ida_tolsf: <F::Scalar as AssociatedReal>::Real::from_f64(1.0),
+            ida_tolsf: F::Scalar::one(),
 
-            //ida_nge = 0;
+            ida_nge: 0,
 
-            //ida_irfnd = 0;
+            ida_irfnd: false,
 
             // Initialize root-finding variables
-
-            //ida_glo     = NULL;
-            //ida_ghi     = NULL;
-            //ida_grout   = NULL;
-            //ida_iroots  = NULL;
-            //ida_rootdir = NULL;
-            //ida_gfun    = NULL;
-            //ida_nrtfn   = 0;
-            //ida_gactive  = NULL;
-            //ida_mxgnull  = 1;
+            ida_glo: Array::zeros(nrtfn),
+            ida_ghi: Array::zeros(nrtfn),
+            ida_grout: Array::zeros(nrtfn),
+            ida_iroots: Array::zeros(nrtfn),
+            ida_rootdir: Array::zeros(nrtfn),
+            ida_nrtfn: nrtfn,
+            ida_gactive: Array::from_elem(nrtfn, true),
+            ida_tlo: F::Scalar::zero(),
+            ida_thi: F::Scalar::zero(),
+            ida_trout: F::Scalar::zero(),
+            ida_toutc: F::Scalar::zero(),
+            ida_ttol: F::Scalar::zero(),
+            ida_taskc: Task::Normal,
+            ida_event_fn: None,
 
             // Not from ida.c...
             ida_ewt: Array::zeros(yy0.raw_dim()),
@@ -365,6 +762,7 @@ impl<
             ida_suppressalg: false,
 
             ida_tstop: F::Scalar::zero(),
+            ida_tdisc: Vec::new(),
 
             ida_kk: 0,
             //ida_kused: 0,
@@ -380,1181 +778,4680 @@ impl<
             //ida_hused: <F::Scalar as AssociatedReal>::Real::from_f64(0.0),
             ida_cj: F::Scalar::zero(),
             ida_cjlast: F::Scalar::zero(),
+            ida_cjold: F::Scalar::zero(),
+            ida_cjratio: F::Scalar::one(),
+
+            ida_ss: F::Scalar::zero(),
+            ida_oldnrm: F::Scalar::zero(),
+            ida_epsNewt: F::Scalar::from(EPCON).unwrap(),
+            ida_toldel: F::Scalar::from(0.0001 * EPCON).unwrap(),
 
             ida_cvals: Array::zeros(MXORDP1),
             ida_dvals: Array::zeros(MAXORD_DEFAULT),
 
-            ida_Xvecs: Array::zeros((MXORDP1, yy0.shape()[0])),
-            ida_Zvecs: Array::zeros((MXORDP1, yy0.shape()[0])),
-
             ida_yypredict: Array::zeros(yy0.raw_dim()),
             ida_yppredict: Array::zeros(yy0.raw_dim()),
+            ida_yy: Array::zeros(yy0.raw_dim()),
+            ida_yp: Array::zeros(yy0.raw_dim()),
+            ida_savres: Array::zeros(yy0.raw_dim()),
+
+            ida_ynew: Array::zeros(yy0.raw_dim()),
+            ida_ypnew: Array::zeros(yy0.raw_dim()),
+            ida_delnew: Array::zeros(yy0.raw_dim()),
+
+            ida_icopt: IcOption::YaYdpInit,
+            ida_sysindex: 1,
+            ida_tscale: F::Scalar::zero(),
+
+            ida_ls,
+            ida_SetupDone: false,
         }
     }
 
-    /// This routine performs one internal IDA step, from tn to tn + hh. It calls other routines to do all the work.
+    /// IDAReInit
     ///
-    /// It solves a system of differential/algebraic equations of the form F(t,y,y') = 0, for one step.
-    /// In IDA, tt is used for t, yy is used for y, and yp is used for y'. The function F is supplied
-    /// as 'res' by the user.
+    /// Re-initializes the integration at time t0 from the initial values yy0 and yp0, e.g. after
+    /// a discontinuous change of the state at an event. The divided differences, the order, and
+    /// all counters are reset, so that the next call to `solve()` starts the integration afresh
+    /// with a first order step. All optional inputs, such as the tolerances and constraints,
+    /// are kept.
+    pub fn reinit(
+        &mut self,
+        t0: F::Scalar,
+        yy0: Array<F::Scalar, Ix1>,
+        yp0: Array<F::Scalar, Ix1>,
+    ) -> Result<(), failure::Error> {
+        for (name, len) in &[("yy0", yy0.len()), ("yp0", yp0.len())] {
+            if *len != self.ida_ewt.len() {
+                Err(IdaError::IllegalInput {
+                    msg: format!(
+                        "{} has length {}, but the problem size is {}.",
+                        name,
+                        len,
+                        self.ida_ewt.len()
+                    ),
+                })?;
+            }
+        }
+
+        // Copy the input parameters into IDA memory block
+        self.ida_tn = t0;
+        self.ida_tretlast = t0;
+
+        // Initialize the phi array
+        self.ida_phi.fill(F::Scalar::zero());
+        self.ida_phi.index_axis_mut(Axis(0), 0).assign(&yy0);
+        self.ida_phi.index_axis_mut(Axis(0), 1).assign(&yp0);
+
+        // Initialize all the counters and other optional output values
+        self.ida_nst = 0;
+        self.ida_nre = 0;
+        self.ida_ncfn = 0;
+        self.ida_netf = 0;
+        self.ida_nni = 0;
+        self.ida_nli = 0;
+        self.ida_ncfl = 0;
+        self.ida_npe = 0;
+        self.ida_nps = 0;
+        self.ida_nsetups = 0;
+        self.ida_nbacktr = 0;
+        self.ida_kused = 0;
+        self.ida_hused = F::Scalar::zero();
+        self.ida_tolsf = F::Scalar::one();
+
+        self.ida_nge = 0;
+        self.ida_irfnd = false;
+        self.ida_gactive.fill(true);
+
+        // Initial setup not done yet
+        self.ida_SetupDone = false;
+        Ok(())
+    }
+
+    /// IDASStolerances
     ///
-    /// The methods used are modified divided difference, fixed leading coefficient forms of backward
-    /// differentiation formulas. The code adjusts the stepsize and order to control the local error
-    /// per step.
+    /// Specifies scalar relative and absolute tolerances. The error weights are then computed as
+    /// `ewt[i] = 1 / (rtol * |y[i]| + atol)`.
+    pub fn ss_tolerances(
+        &mut self,
+        rtol: F::Scalar,
+        atol: F::Scalar,
+    ) -> Result<(), failure::Error> {
+        if rtol < F::Scalar::zero() {
+            Err(IdaError::IllegalInput {
+                msg: "rtol < 0 illegal.".into(),
+            })?;
+        }
+        if atol < F::Scalar::zero() {
+            Err(IdaError::IllegalInput {
+                msg: "atol < 0 illegal.".into(),
+            })?;
+        }
+
+        self.ida_itol = Itol::SS { rtol, atol };
+        Ok(())
+    }
+
+    /// IDASVtolerances
     ///
-    /// The main operations done here are as follows:
-    /// * initialize various quantities;
-    /// * setting of multistep method coefficients;
-    /// * solution of the nonlinear system for yy at t = tn + hh;
-    /// * deciding on order reduction and testing the local error;
-    /// * attempting to recover from failure in nonlinear solver or error test;
-    /// * resetting stepsize and order for the next step.
-    /// * updating phi and other state data if successful;
+    /// Specifies a scalar relative tolerance and a vector of absolute tolerances, one per solution
+    /// component. The error weights are then computed as `ewt[i] = 1 / (rtol * |y[i]| + atol[i])`.
+    pub fn sv_tolerances(
+        &mut self,
+        rtol: F::Scalar,
+        atol: Array<F::Scalar, Ix1>,
+    ) -> Result<(), failure::Error> {
+        if rtol < F::Scalar::zero() {
+            Err(IdaError::IllegalInput {
+                msg: "rtol < 0 illegal.".into(),
+            })?;
+        }
+        if atol.len() != self.ida_ewt.len() {
+            Err(IdaError::IllegalInput {
+                msg: format!(
+                    "atol has length {}, but the problem size is {}.",
+                    atol.len(),
+                    self.ida_ewt.len()
+                ),
+            })?;
+        }
+        if atol.iter().any(|&atol| atol < F::Scalar::zero()) {
+            Err(IdaError::IllegalInput {
+                msg: "Some atol component < 0.0 illegal.".into(),
+            })?;
+        }
+
+        self.ida_itol = Itol::SV { rtol, atol };
+        Ok(())
+    }
+
+    /// IDAWFtolerances
     ///
-    /// On a failure in the nonlinear system solution or error test, the step may be reattempted,
-    /// depending on the nature of the failure.
+    /// Specifies a user-supplied function `efun` that computes the error weight vector from the
+    /// current solution.
+    pub fn wf_tolerances(&mut self, efun: ErrorWeightFn<F>) {
+        self.ida_itol = Itol::WF(efun);
+    }
+
+    /// Specifies the optional inputs of the integrator, replacing all previous values. Returns
+    /// `IdaError::IllegalInput` if any of them is invalid, in which case none are changed.
     ///
-    /// Variables or arrays (all in the IDAMem structure) used in IDAStep are:
+    /// To change some of them, modify the current values from `options()`.
+    pub fn set_options(&mut self, options: IdaOptions<F::Scalar>) -> Result<(), failure::Error> {
+        if options.maxord == 0 {
+            Err(IdaError::IllegalInput {
+                msg: "maxord <= 0 illegal.".into(),
+            })?;
+        }
+        if options.maxord > MAXORD_DEFAULT {
+            Err(IdaError::IllegalInput {
+                msg: format!("maxord > {} illegal.", MAXORD_DEFAULT),
+            })?;
+        }
+        if options.hin.is_nan() {
+            Err(IdaError::IllegalInput {
+                msg: "hin is NaN.".into(),
+            })?;
+        }
+        if options.hmin.is_nan() || options.hmin < F::Scalar::zero() {
+            Err(IdaError::IllegalInput {
+                msg: "hmin < 0 illegal.".into(),
+            })?;
+        }
+        if options.hmax.is_nan() || options.hmax <= F::Scalar::zero() {
+            Err(IdaError::IllegalInput {
+                msg: "hmax <= 0 illegal.".into(),
+            })?;
+        }
+        if options.hmin > options.hmax {
+            Err(IdaError::IllegalInput {
+                msg: "Inconsistent step size limits: hmin > hmax.".into(),
+            })?;
+        }
+        for (name, value) in &[
+            ("maxcor", options.maxcor),
+            ("maxnh", options.maxnh),
+            ("maxnj", options.maxnj),
+            ("maxnit", options.maxnit),
+            ("maxbacks", options.maxbacks),
+        ] {
+            if *value == 0 {
+                Err(IdaError::IllegalInput {
+                    msg: format!("{} <= 0 illegal.", name),
+                })?;
+            }
+        }
+        for (name, value) in &[
+            ("epcon", options.epcon),
+            ("epiccon", options.epiccon),
+            ("steptol", options.steptol),
+        ] {
+            if value.is_nan() || *value <= F::Scalar::zero() {
+                Err(IdaError::IllegalInput {
+                    msg: format!("{} <= 0.0 illegal.", name),
+                })?;
+            }
+        }
+
+        self.ida_maxord = options.maxord;
+        // A lower maximum order takes effect from the next step
+        self.ida_kk = self.ida_kk.min(self.ida_maxord);
+        self.ida_mxstep = options.mxstep;
+        self.ida_hin = options.hin;
+        self.ida_hmin = options.hmin;
+        self.ida_hmax_inv = options.hmax.recip();
+        self.ida_maxnef = options.maxnef;
+        self.ida_maxncf = options.maxncf;
+        self.ida_maxcor = options.maxcor;
+        self.ida_epcon = options.epcon;
+        self.ida_epiccon = options.epiccon;
+        self.ida_maxnh = options.maxnh;
+        self.ida_maxnj = options.maxnj;
+        self.ida_maxnit = options.maxnit;
+        self.ida_maxbacks = options.maxbacks;
+        self.ida_lsoff = options.lsoff;
+        self.ida_steptol = options.steptol;
+        self.ida_suppressalg = options.suppressalg;
+        Ok(())
+    }
+
+    /// The current optional inputs of the integrator
+    pub fn options(&self) -> IdaOptions<F::Scalar> {
+        IdaOptions {
+            maxord: self.ida_maxord,
+            mxstep: self.ida_mxstep,
+            hin: self.ida_hin,
+            hmin: self.ida_hmin,
+            hmax: self.ida_hmax_inv.recip(),
+            maxnef: self.ida_maxnef,
+            maxncf: self.ida_maxncf,
+            maxcor: self.ida_maxcor,
+            epcon: self.ida_epcon,
+            epiccon: self.ida_epiccon,
+            maxnh: self.ida_maxnh,
+            maxnj: self.ida_maxnj,
+            maxnit: self.ida_maxnit,
+            maxbacks: self.ida_maxbacks,
+            lsoff: self.ida_lsoff,
+            steptol: self.ida_steptol,
+            suppressalg: self.ida_suppressalg,
+        }
+    }
+
+    /// IDASetId
     ///
-    /// tt -- Independent variable.
-    /// yy -- Solution vector at tt.
-    /// yp -- Derivative of solution vector after successful stelp.
-    /// res -- User-supplied function to evaluate the residual. See the description given in file ida.h
-    /// lsetup -- Routine to prepare for the linear solver call. It may either save or recalculate
-    ///   quantities used by lsolve. (Optional)
-    /// lsolve -- Routine to solve a linear system. A prior call to lsetup may be required.
-    /// hh  -- Appropriate step size for next step.
-    /// ewt -- Vector of weights used in all convergence tests.
-    /// phi -- Array of divided differences used by IDAStep. This array is composed of (maxord+1)
-    ///   nvectors (each of size Neq). (maxord+1) is the maximum order for the problem, maxord, plus 1.
+    /// Specifies which components are differential (`true`) and which are algebraic (`false`).
+    /// This is required by `calc_ic()` with `IcOption::YaYdpInit`.
+    pub fn set_id(&mut self, id: Array<bool, Ix1>) -> Result<(), failure::Error> {
+        if id.len() != self.ida_ewt.len() {
+            Err(IdaError::IllegalInput {
+                msg: format!(
+                    "id has length {}, but the problem size is {}.",
+                    id.len(),
+                    self.ida_ewt.len()
+                ),
+            })?;
+        }
+
+        self.ida_id = Some(id);
+        Ok(())
+    }
+
+    /// IDASetConstraints
     ///
-    /// Return values are:
-    ///       IDA_SUCCESS   IDA_RES_FAIL      LSETUP_ERROR_NONRECVR
-    ///                     IDA_LSOLVE_FAIL   IDA_ERR_FAIL
-    ///                     IDA_CONSTR_FAIL   IDA_CONV_FAIL
-    ///                     IDA_REP_RES_ERR
-    fn step(&mut self) -> Result<(), failure::Error> {
-        //realtype saved_t, ck;
-        //realtype err_k, err_km1;
-        //int ncf, nef;
-        //int nflag, kflag;
-        let mut ck = F::Scalar::one();
+    /// Specifies an inequality constraint on each component of y. A corrected solution that
+    /// violates a constraint is a recoverable failure, and the step is retried with a step size
+    /// that approximately reaches the constraint boundary. If this fails repeatedly, `solve()`
+    /// returns `IdaError::ConstraintFail`. The initial values must satisfy the constraints.
+    pub fn set_constraints(
+        &mut self,
+        constraints: Array<Constraint, Ix1>,
+    ) -> Result<(), failure::Error> {
+        if constraints.len() != self.ida_ewt.len() {
+            Err(IdaError::IllegalInput {
+                msg: format!(
+                    "constraints has length {}, but the problem size is {}.",
+                    constraints.len(),
+                    self.ida_ewt.len()
+                ),
+            })?;
+        }
 
-        let saved_t = self.ida_tn;
-        //ncf = nef = 0;
+        // If none of the constraints are active, the constraint checks are skipped
+        self.ida_constraintsSet = constraints.iter().any(|&c| c != Constraint::None);
+        self.ida_constraints = constraints;
+        Ok(())
+    }
 
-        if self.ida_nst == 0 {
-            self.ida_kk = 1;
-            self.ida_kused = 0;
-            self.ida_hused = F::Scalar::one();
-            self.ida_psi[0] = self.ida_hh;
-            self.ida_cj = F::Scalar::one() / self.ida_hh;
-            self.ida_phase = 0;
-            self.ida_ns = 0;
+    /// IDASetStopTime
+    ///
+    /// Specifies a time tstop past which the solution is not to proceed. `solve()` returns
+    /// `SolveStatus::TstopReturn` once tstop is reached, after which the stop time is disabled.
+    pub fn set_stop_time(&mut self, tstop: F::Scalar) -> Result<(), failure::Error> {
+        // If solve() was called at least once, test if tstop is legal (i.e. if it was not already
+        // passed). If set_stop_time() is called before the first call to solve(), tstop will be
+        // checked in solve().
+        if self.ida_nst > 0 && (tstop - self.ida_tn) * self.ida_hh < F::Scalar::zero() {
+            self.ida_tstop = tstop;
+            Err(self.bad_tstop())?;
         }
 
-        /* To prevent 'unintialized variable' warnings */
-        //err_k = ZERO;
-        //err_km1 = ZERO;
+        self.ida_tstop = tstop;
+        self.ida_tstopset = true;
+        Ok(())
+    }
 
-        /* Looping point for attempts to take a step */
+    /// IDAClearStopTime
+    ///
+    /// Disables the stop time set by `set_stop_time()`.
+    pub fn clear_stop_time(&mut self) {
+        self.ida_tstopset = false;
+    }
 
-        loop {
-            //-----------------------
-            // Set method coefficients
-            //-----------------------
+    /// Specifies the times of known discontinuities of the model, e.g. steps of its inputs. The
+    /// integration steps exactly onto each of them, and restarts there at order 1 as if from new
+    /// initial values, without returning from `solve()`.
+    ///
+    /// The residual must already use the values from after a discontinuity at a time t > tdisc
+    /// (or t < tdisc when integrating backwards), as the step onto tdisc is evaluated at tdisc.
+    /// If the component types have been set with `set_id()`, the restart computes values of y'
+    /// (and of the algebraic components of y) consistent with the residual after the
+    /// discontinuity.
+    pub fn set_discontinuities(&mut self, tdisc: Vec<F::Scalar>) -> Result<(), failure::Error> {
+        if tdisc.iter().any(|t| t.is_nan()) {
+            Err(IdaError::IllegalInput {
+                msg: "tdisc contains NaN.".into(),
+            })?;
+        }
 
-            ck = self.set_coeffs();
+        self.ida_tdisc = tdisc;
+        self.ida_tdisc.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Ok(())
+    }
 
-            //kflag = IDA_SUCCESS;
+    /// IDASetRootDirection
+    ///
+    /// Specifies the direction of the zero crossings to be located for each root function:
+    /// `rootdir[i] > 0` for crossings where g_i is increasing only, `rootdir[i] < 0` for
+    /// decreasing g_i only, and `rootdir[i] == 0` for both (the default).
+    pub fn set_root_direction(&mut self, rootdir: Array<i8, Ix1>) -> Result<(), failure::Error> {
+        if self.ida_nrtfn == 0 {
+            Err(IdaError::IllegalInput {
+                msg: "Rootfinding was not initialized.".into(),
+            })?;
+        }
+        if rootdir.len() != self.ida_nrtfn {
+            Err(IdaError::IllegalInput {
+                msg: format!(
+                    "rootdir has length {}, but the number of root functions is {}.",
+                    rootdir.len(),
+                    self.ida_nrtfn
+                ),
+            })?;
+        }
 
-            //----------------------------------------------------
-            // If tn is past tstop (by roundoff), reset it to tstop.
-            //-----------------------------------------------------
+        self.ida_rootdir = rootdir;
+        Ok(())
+    }
 
-            self.ida_tn += self.ida_hh;
-            if self.ida_tstopset {
-                if (self.ida_tn - self.ida_tstop) * self.ida_hh > F::Scalar::one() {
-                    self.ida_tn = self.ida_tstop;
-                }
-            }
+    /// Specifies the event action hook `efun`, which is called at each root found by `solve()`
+    /// before it returns `SolveStatus::RootReturn`. If the hook restarts the integration, the
+    /// returned y and y' are the new initial values.
+    pub fn set_event_fn(&mut self, efun: EventFn<F>) {
+        self.ida_event_fn = Some(efun);
+    }
 
-            //-----------------------
-            // Advance state variables
-            //-----------------------
+    /// N_VConstrMask
+    ///
+    /// Returns the mask of the components of y that violate their constraints, or `None` if all
+    /// constraints are satisfied.
+    fn constr_mask<S: Data<Elem = F::Scalar>>(
+        &self,
+        y: &ArrayBase<S, Ix1>,
+    ) -> Option<Array<bool, Ix1>> {
+        let mut violated = false;
+        let mut mm = Array::from_elem(y.raw_dim(), false);
+        Zip::from(&mut mm)
+            .and(&self.ida_constraints)
+            .and(y)
+            .apply(|mm, &c, &y| {
+                *mm = !c.is_satisfied(y);
+                violated |= *mm;
+            });
+        if violated {
+            Some(mm)
+        } else {
+            None
+        }
+    }
 
-            // Compute predicted values for yy and yp
-            self.predict();
+    /// Sets the differential and algebraic components as by `set_id()`, from the structural
+    /// pattern of dF/dy' detected at the initial values by `JacobianPattern::detect()`: the
+    /// components that the residual does not depend on through y' are algebraic.
+    ///
+    /// This must be called before the first call to `solve()`.
+    pub fn detect_id(&mut self) -> Result<(), failure::Error> {
+        if self.ida_nst > 0 {
+            Err(IdaError::IllegalInput {
+                msg: "detect_id() must be called before the first step.".into(),
+            })?;
+        }
 
-            // Nonlinear system solution
-            let nflag = self.nonlinear_solve();
+        let pattern = JacobianPattern::detect(
+            &mut self.f,
+            self.ida_tn,
+            &self.ida_phi.index_axis(Axis(0), 0),
+            &self.ida_phi.index_axis(Axis(0), 1),
+            2,
+        )
+        .map_err(|_| IdaError::ResidualFail {})?;
+        self.set_id(pattern.id())
+    }
 
-            // If NLS was successful, perform error test
-            if nflag.is_ok() {
-                let (err_k, err_km1, nflag) = self.test_error(ck);
+    /// IDAEwtSet
+    ///
+    /// This routine computes the error weight vector ewt from the current solution phi[0],
+    /// according to the tolerances specified by the user.
+    ///
+    /// Returns `IdaError::BadErrorWeightVector` if any component of ewt would be non-positive, and
+    /// `IdaError::IllegalInput` if no tolerances have been specified.
+    fn ewt_set(&mut self) -> Result<(), failure::Error> {
+        let ycur = self.ida_phi.index_axis(Axis(0), 0);
+
+        match &self.ida_itol {
+            Itol::NN => Err(IdaError::IllegalInput {
+                msg: "No integration tolerances have been specified.".into(),
+            })?,
+
+            Itol::SS { rtol, atol } => {
+                let (rtol, atol) = (*rtol, *atol);
+                if ycur
+                    .iter()
+                    .any(|&y| rtol * y.abs() + atol <= F::Scalar::zero())
+                {
+                    Err(IdaError::BadErrorWeightVector {})?;
+                }
+                Zip::from(&mut self.ida_ewt)
+                    .and(&ycur)
+                    .apply(|ewt, &y| *ewt = (rtol * y.abs() + atol).recip());
             }
 
-            // Test for convergence or error test failures
-            //if nflag != IDA_SUCCESS {
-            // restore and decide what to do
-            self.restore(saved_t);
-            //kflag = handle_n_flag(IDA_mem, nflag, err_k, err_km1, &(self.ida_ncfn), &ncf, &(self.ida_netf), &nef);
-
-            // exit on nonrecoverable failure
-            //if kflag != PREDICT_AGAIN {
-            //    return (kflag);
-            //}
+            Itol::SV { rtol, atol } => {
+                let rtol = *rtol;
+                if ycur
+                    .iter()
+                    .zip(atol.iter())
+                    .any(|(&y, &atol)| rtol * y.abs() + atol <= F::Scalar::zero())
+                {
+                    Err(IdaError::BadErrorWeightVector {})?;
+                }
+                Zip::from(&mut self.ida_ewt)
+                    .and(&ycur)
+                    .and(atol)
+                    .apply(|ewt, &y, &atol| *ewt = (rtol * y.abs() + atol).recip());
+            }
 
-            // recoverable error; predict again
-            if self.ida_nst == 0 {
-                self.reset();
+            Itol::WF(efun) => {
+                if efun(&self.f, ycur, self.ida_ewt.view_mut()).is_err()
+                    || self.ida_ewt.iter().any(|&ewt| ewt <= F::Scalar::zero())
+                {
+                    Err(IdaError::BadErrorWeightVector {})?;
+                }
             }
-            continue;
-            //}
+        }
 
-            /* kflag == IDA_SUCCESS */
-            break;
+        Ok(())
+    }
+
+    /// IDAInitialSetup
+    ///
+    /// This routine is called by `solve()` or `calc_ic()` once, before the first step. It checks
+    /// the optional inputs, loads the error weights, and initializes the linear solver.
+    fn initial_setup(&mut self) -> Result<(), failure::Error> {
+        if self.ida_suppressalg && self.ida_id.is_none() {
+            Err(IdaError::IllegalInput {
+                msg: "id = NULL but suppressalg option on.".into(),
+            })?;
         }
 
-        /* Nonlinear system solve and error test were both successful;
-        update data, and consider change of step and/or order */
+        // Check to see if y0 satisfies constraints
+        if self.ida_constraintsSet
+            && self
+                .constr_mask(&self.ida_phi.index_axis(Axis(0), 0))
+                .is_some()
+        {
+            Err(IdaError::IllegalInput {
+                msg: "y0 fails to satisfy constraints.".into(),
+            })?;
+        }
 
-        //self.complete_step(err_k, err_km1);
+        // Load the initial error weights
+        self.ewt_set()?;
 
-        /*
-          Rescale ee vector to be the estimated local error
-          Notes:
-            (1) altering the value of ee is permissible since
-                it will be overwritten by
-                IDASolve()->IDAStep()->IDANls()
-                before it is needed again
-            (2) the value of ee is only valid if IDAHandleNFlag()
-                returns either PREDICT_AGAIN or IDA_SUCCESS
-        */
-        //N_VScale(ck, IDA_mem->ida_ee, IDA_mem->ida_ee);
-        self.ida_ee *= ck;
+        // Call linit function if it exists.
+        if self.ida_ls.init(&self.f).is_err() {
+            Err(IdaError::LinearInitFail {})?;
+        }
 
         Ok(())
     }
 
-    /// This routine computes the coefficients relevant to the current step.
+    /// IDASolve
     ///
-    /// The counter ns counts the number of consecutive steps taken at constant stepsize h and order
-    /// k, up to a maximum of k + 2.
-    /// Then the first ns components of beta will be one, and on a step with ns = k + 2, the
-    /// coefficients alpha, etc. need not be reset here.
-    /// Also, complete_step() prohibits an order increase until ns = k + 2.
+    /// This routine is the main driver of the IDA package.
     ///
-    /// Returns the 'variable stepsize error coefficient ck'
-    pub fn set_coeffs(&mut self) -> F::Scalar {
-        // Set coefficients for the current stepsize h
-        if self.ida_hh != self.ida_hused || self.ida_kk != self.ida_kused {
-            self.ida_ns = 0;
-        }
-        self.ida_ns = std::cmp::min(self.ida_ns + 1, self.ida_kused + 2);
-        if self.ida_kk + 1 >= self.ida_ns {
-            self.ida_beta[0] = F::Scalar::one();
-            self.ida_alpha[0] = F::Scalar::one();
-            let mut temp1 = self.ida_hh;
-            self.ida_gamma[0] = F::Scalar::zero();
-            self.ida_sigma[0] = F::Scalar::one();
-            for i in 1..self.ida_kk {
-                let temp2 = self.ida_psi[i - 1];
-                self.ida_psi[i - 1] = temp1;
-                self.ida_beta[i] = self.ida_beta[i - 1] * (self.ida_psi[i - 1] / temp2);
-                temp1 = temp2 + self.ida_hh;
-                self.ida_alpha[i] = self.ida_hh / temp1;
-                self.ida_sigma[i] =
-                    self.ida_sigma[i - 1] * self.ida_alpha[i] * F::Scalar::from(i).unwrap();
-                self.ida_gamma[i] = self.ida_gamma[i - 1] + self.ida_alpha[i - 1] / self.ida_hh;
+    /// It integrates over an independent variable interval defined by the user, by calling
+    /// `step()` to take internal independent variable steps.
+    ///
+    /// The first time that `solve()` is called for a successfully initialized problem, it computes
+    /// a tentative initial step size from the WRMS norm of y', limited by |tout - t0| and by hmax,
+    /// unless one was given by `IdaOptions::hin`.
+    ///
+    /// `solve()` supports two modes, specified by `task`:
+    /// * In the `Task::Normal` mode, the solver steps until it passes tout and then interpolates to
+    ///   obtain y(tout) and y'(tout).
+    /// * In the `Task::OneStep` mode, it takes one internal step and returns.
+    ///
+    /// If a stop time has been set, the solver never steps past it, and returns
+    /// `SolveStatus::TstopReturn` once it is reached.
+    ///
+    /// On success, returns the time reached, `tret`, together with the return status. The solution
+    /// at `tret` is available from `get_yy()` and `get_yp()`.
+    pub fn solve(
+        &mut self,
+        tout: F::Scalar,
+        task: Task,
+    ) -> Result<(F::Scalar, SolveStatus), failure::Error> {
+        self.ida_taskc = task;
+        self.ida_toutc = tout;
+
+        if self.ida_nst == 0 {
+            if !self.ida_SetupDone {
+                self.initial_setup()?;
+                self.ida_SetupDone = true;
             }
-            self.ida_psi[self.ida_kk] = temp1;
-        }
-        // compute alphas, alpha0
-        let mut alphas = F::Scalar::zero();
-        let mut alpha0 = F::Scalar::zero();
-        for i in 0..self.ida_kk {
-            alphas -= F::Scalar::one() / F::Scalar::from(i + 1).unwrap();
-            alpha0 -= self.ida_alpha[i];
-        }
 
-        // compute leading coefficient cj
-        self.ida_cjlast = self.ida_cj;
-        self.ida_cj = -alphas / self.ida_hh;
+            // On the first call, check for tout - tn too small, set initial hh, check for approach
+            // to tstop, and scale phi[1] by hh.
+            let tdist = (tout - self.ida_tn).abs();
+            if tdist == F::Scalar::zero() {
+                Err(IdaError::IllegalInput {
+                    msg: "tout too close to t0 to start integration.".into(),
+                })?;
+            }
+            let troundoff = F::Scalar::from(2.0).unwrap()
+                * F::Scalar::epsilon()
+                * (self.ida_tn.abs() + tout.abs());
+            if tdist < troundoff {
+                Err(IdaError::IllegalInput {
+                    msg: "tout too close to t0 to start integration.".into(),
+                })?;
+            }
 
-        // compute variable stepsize error coefficient ck
-        let mut ck = (self.ida_alpha[self.ida_kk] + alphas - alpha0).abs();
-        ck = ck.max(self.ida_alpha[self.ida_kk]);
+            self.ida_hh = self.ida_hin;
+            if (self.ida_hh != F::Scalar::zero())
+                && ((tout - self.ida_tn) * self.ida_hh < F::Scalar::zero())
+            {
+                Err(IdaError::IllegalInput {
+                    msg: "Initial step is not towards tout.".into(),
+                })?;
+            }
 
-        // change phi to phi-star
-        // Scale i=self.ida_ns to i<=self.ida_kk
-        if self.ida_ns <= self.ida_kk {
-            let nv = self.ida_kk - self.ida_ns + 1;
-            let c = self.ida_beta.slice(s![self.ida_ns..]);
-
-            let ix1 = s![self.ida_ns..];
-            let ix2 = SliceOrIndex::from(0..self.ida_ns);
-            //self.ida_phi.slice(ix1);
-            //&SliceInfo<<<<F as ModelSpec>::Dim as Dimension>::Larger as Dimension>::SliceArg, _>
-            //let ix: <<<F as ModelSpec>::Dim as Dimension>::Larger as Dimension>::SliceArg = &[SliceOrIndex::from(0..self.ida_ns)];
-
-            //<<<<F as ModelSpec>::Dim as Dimension>::Larger as Dimension>::SliceArg as AsRef<[SliceOrIndex]>>::from(0..1);
-
-            //let z = self.ida_phi.slice_mut(s![self.ida_ns..]);
-            //self.ida_phi.index_axis_mut(Axis(0), 0).assign(&yy0);
-            /*
-            N_VScaleVectorArray(
-              self.ida_kk - self.ida_ns + 1,
-              self.ida_beta + self.ida_ns,
-              self.ida_phi + self.ida_ns,
-              self.ida_phi + self.ida_ns,
-            );
-            */
-        }
+            // Unless given by hin, estimate the initial step size from the WRMS norm of y', so that
+            // the first order prediction changes y by about half the tolerance.
+            if self.ida_hh == F::Scalar::zero() {
+                self.ida_hh = F::Scalar::from(0.001).unwrap() * tdist;
+                let ypnorm = self.wrms_norm(
+                    &self.ida_phi.index_axis(Axis(0), 1),
+                    &self.ida_ewt,
+                    self.ida_suppressalg,
+                );
+                if ypnorm > F::Scalar::from(0.5).unwrap() / self.ida_hh {
+                    self.ida_hh = F::Scalar::from(0.5).unwrap() / ypnorm;
+                }
+                if tout < self.ida_tn {
+                    self.ida_hh = -self.ida_hh;
+                }
+            }
 
-        return ck;
-    }
+            // Limit the initial step size by hmax and hmin
+            let rh = self.ida_hh.abs() * self.ida_hmax_inv;
+            if rh > F::Scalar::one() {
+                self.ida_hh /= rh;
+            }
+            self.limit_hmin();
 
-    /// IDANls
-    /// This routine attempts to solve the nonlinear system using the linear solver specified.
-    /// NOTE: this routine uses N_Vector ee as the scratch vector tempv3 passed to lsetup.
-    pub fn nonlinear_solve(&mut self) -> Result<(), failure::Error> {
-        unimplemented!();
-    }
+            if self.ida_tstopset {
+                if (self.ida_tstop - self.ida_tn) * self.ida_hh <= F::Scalar::zero() {
+                    Err(self.bad_tstop())?;
+                }
+                if (self.ida_tn + self.ida_hh - self.ida_tstop) * self.ida_hh > F::Scalar::zero() {
+                    self.ida_hh = (self.ida_tstop - self.ida_tn)
+                        * (F::Scalar::one() - F::Scalar::from(4.0).unwrap() * F::Scalar::epsilon());
+                }
+            }
+            self.discontinuity_test();
 
-    /// IDAPredict
-    /// This routine predicts the new values for vectors yy and yp.
-    pub fn predict(&mut self) -> () {
-        for j in 0..self.ida_kk {
-            self.ida_cvals[j] = F::Scalar::one();
-        }
+            self.ida_h0u = self.ida_hh;
+            self.ida_kk = 0;
+            self.ida_kused = 0; // set in case of an error return before a step
 
-        let cv = self.ida_cvals.slice(s![self.ida_kk + 1..]);
-        let ph = self.ida_phi.index_axis(Axis(0), self.ida_kk + 1);
-        //let x = self.ida_phi.slice(s![self.ida_kk + 1.., ..]);
+            // Check for exact zeros of the root functions at or near t0.
+            if self.ida_nrtfn > 0 {
+                self.rcheck1()?;
+            }
 
-        // ida_delta = ida_phi[ida_kk] + self.ida_ee;
-        //self.ida_delta .assign(&self.ida_phi.index_axis(Axis(0), self.ida_kk));
-        let v = self.ida_phi.index_axis(Axis(0), self.ida_kk);
-        //Zip::from(&mut self.ida_delta).and(&v).and(&self.ida_ee).apply(|delta, phi, ee| {});
-        self.ida_delta.assign(&v);
-        self.ida_delta += &self.ida_ee;
+            // set phi[1] = hh*y'
+            let mut phi1 = self.ida_phi.index_axis_mut(Axis(0), 1);
+            phi1 *= self.ida_hh;
 
-        //self.ida_delta = &v + &self.ida_ee;
+            // Set the convergence test constants epsNewt and toldel
+            self.ida_epsNewt = self.ida_epcon;
+            self.ida_toldel = F::Scalar::from(0.0001).unwrap() * self.ida_epsNewt;
+        }
 
-        // ida_yypredict = sum 0..kk (cvals[k] * phi[k])
-        /*
-        for i = 0..n
-            for j = 0..nv
+        // Counter for the number of internal steps taken in this call
+        let mut nstloc = 0;
+
+        // If not the first call, perform all stopping tests.
+        if self.ida_nst > 0 {
+            // First, check for a root in the last step taken, other than the last root found, if
+            // any. If task = OneStep and y(tn) was not returned because of an intervening root,
+            // return y(tn) now.
+            if self.ida_nrtfn > 0 {
+                let irfndp = self.ida_irfnd;
+                self.ida_irfnd = false;
+
+                if irfndp && task == Task::OneStep {
+                    self.ida_tretlast = self.ida_tn;
+                    self.get_solution_yy(self.ida_tn)?;
+                    return Ok((self.ida_tn, SolveStatus::Success));
+                }
 
-        */
+                if let Some(ret) = self.rcheck2()? {
+                    return self.handle_event(ret);
+                }
 
-        let c = self.ida_cvals.slice(s![self.ida_kk + 1..]);
-        let x = self
-            .ida_phi
-            .slice_axis(Axis(0), Slice::from(self.ida_kk + 1..));
-        //let mut z = self.ida_yypredict.slice_axis_mut(Axis(0), Slice::from(self.ida_kk+1..));
+                // If tn is distinct from tretlast (within roundoff), check remaining interval for
+                // roots
+                let troundoff = F::Scalar::from(100.0).unwrap()
+                    * F::Scalar::epsilon()
+                    * (self.ida_tn.abs() + self.ida_hh.abs());
+                if (self.ida_tn - self.ida_tretlast).abs() > troundoff {
+                    if let Some(ret) = self.rcheck3()? {
+                        self.ida_irfnd = true;
+                        return self.handle_event(ret);
+                    }
+                }
+            }
 
-        ndarray::Zip::from(&mut self.ida_yypredict)
-            .and(x.lanes(Axis(0)))
-            .apply(|z, row| {
-                *z = (&row * &c).sum();
-            });
+            if let Some(ret) = self.stop_test1(tout, task)? {
+                return Ok(ret);
+            }
+        }
 
-        //N_VLinearCombination(&c, &x, &mut z);
-        //(void) N_VLinearCombination(IDA_mem->ida_kk+1, IDA_mem->ida_cvals, IDA_mem->ida_phi, IDA_mem->ida_yypredict);
-        //(void) N_VLinearCombination(IDA_mem->ida_kk, IDA_mem->ida_gamma+1, IDA_mem->ida_phi+1, IDA_mem->ida_yppredict);
-    }
+        // Looping point for internal steps.
+        loop {
+            // Check for too many steps taken.
+            if (self.ida_mxstep > 0) && (nstloc >= self.ida_mxstep) {
+                // Here yy=yret and yp=ypret already have the current solution.
+                self.ida_tretlast = self.ida_tn;
+                Err(IdaError::TooMuchWork {
+                    t: self.ida_tn.to_f64().unwrap(),
+                })?;
+            }
 
-    /// IDATestError
-    ///
-    /// This routine estimates errors at orders k, k-1, k-2, decides whether or not to suggest an order
-    /// decrease, and performs the local error test.
-    ///
-    /// Returns a tuple of (err_k, err_km1, nflag)
-    pub fn test_error(
-        &mut self,
-        ck: F::Scalar,
-    ) -> (
-        F::Scalar, // err_k
-        F::Scalar, // err_km1
-        bool,      // nflag
-    ) {
-        //realtype enorm_k, enorm_km1, enorm_km2;   /* error norms */
-        //realtype terr_k, terr_km1, terr_km2;      /* local truncation error norms */
-        // Compute error for order k.
-        let enorm_k = self.wrms_norm(&self.ida_ee, &self.ida_ewt, self.ida_suppressalg);
-        let err_k = self.ida_sigma[self.ida_kk] * enorm_k;
-        let terr_k = err_k * F::Scalar::from(self.ida_kk + 1).unwrap();
+            // Reset and check ewt (if not first call).
+            if self.ida_nst > 0 {
+                if let Err(ier) = self.ewt_set() {
+                    self.get_solution_yy(self.ida_tn)?;
+                    self.ida_tretlast = self.ida_tn;
+                    return Err(ier);
+                }
+            }
 
-        let mut err_km1 = F::Scalar::zero(); // estimated error at k-1
-        let mut err_km2 = F::Scalar::zero(); // estimated error at k-2
+            // Check for too much accuracy requested.
+            let nrm = self.wrms_norm(
+                &self.ida_phi.index_axis(Axis(0), 0),
+                &self.ida_ewt,
+                self.ida_suppressalg,
+            );
+            self.ida_tolsf = F::Scalar::epsilon() * nrm;
+            if self.ida_tolsf > F::Scalar::one() {
+                self.ida_tolsf *= F::Scalar::from(10.0).unwrap();
+                self.ida_tretlast = self.ida_tn;
+                if self.ida_nst > 0 {
+                    self.get_solution_yy(self.ida_tn)?;
+                }
+                Err(IdaError::TooMuchAccuracy {
+                    t: self.ida_tn.to_f64().unwrap(),
+                })?;
+            }
 
-        self.ida_knew = self.ida_kk;
+            // Do not step past the next discontinuity, and restart the integration at a
+            // discontinuity that was reached by the last step.
+            let tdisc = self.discontinuity_test();
+            if self.ida_kused > 0 && self.ida_tdisc.contains(&self.ida_tn) {
+                self.restart()?;
+            }
 
-        if self.ida_kk > 1 {
-            // Compute error at order k-1
-            self.ida_delta = &self.ida_phi.index_axis(Axis(0), self.ida_kk) + &self.ida_ee;
-            let enorm_km1 = self.wrms_norm(&self.ida_delta, &self.ida_ewt, self.ida_suppressalg);
-            err_km1 = self.ida_sigma[self.ida_kk - 1] * enorm_km1;
-            let terr_km1 = err_km1 * F::Scalar::from(self.ida_kk).unwrap();
+            // Call step() to take a step.
+            if let Err(sflag) = self.step() {
+                // Process all failed-step cases, and exit loop.
+                self.ida_tretlast = self.ida_tn;
+                self.get_solution_yy(self.ida_tn)?;
+                return Err(sflag);
+            }
 
-            if self.ida_kk > 2 {
-                // Compute error at order k-2
-                // ida_delta = ida_phi[ida_kk - 1] + ida_delta
-                self.ida_delta
-                    .assign(&self.ida_phi.index_axis(Axis(0), self.ida_kk - 1));
-                self.ida_delta.scaled_add(F::Scalar::one(), &self.ida_ee);
+            nstloc += 1;
 
-                let enorm_km2 =
-                    self.wrms_norm(&self.ida_delta, &self.ida_ewt, self.ida_suppressalg);
-                err_km2 = self.ida_sigma[self.ida_kk - 2] * enorm_km2;
-                let terr_km2 = err_km2 * F::Scalar::from(self.ida_kk - 1).unwrap();
+            // If tstop is set and was reached, reset tn = tstop
+            if self.ida_tstopset {
+                let troundoff = F::Scalar::from(100.0).unwrap()
+                    * F::Scalar::epsilon()
+                    * (self.ida_tn.abs() + self.ida_hh.abs());
+                if (self.ida_tn - self.ida_tstop).abs() <= troundoff {
+                    self.ida_tn = self.ida_tstop;
+                }
+            }
 
-                // Decrease order if errors are reduced
-                if terr_km1.max(terr_km2) <= terr_k {
-                    self.ida_knew = self.ida_kk - 1;
+            // Likewise, if the next discontinuity was reached, reset tn to it
+            if let Some(tdisc) = tdisc {
+                let troundoff = F::Scalar::from(100.0).unwrap()
+                    * F::Scalar::epsilon()
+                    * (self.ida_tn.abs() + self.ida_hh.abs());
+                if (self.ida_tn - tdisc).abs() <= troundoff {
+                    self.ida_tn = tdisc;
                 }
-            } else {
-                // Decrease order to 1 if errors are reduced by at least 1/2
-                if terr_km1 <= (terr_k * F::Scalar::from(0.5).unwrap()) {
-                    self.ida_knew = self.ida_kk - 1;
+            }
+
+            // Check for root in last step taken.
+            if self.ida_nrtfn > 0 {
+                if let Some(ret) = self.rcheck3()? {
+                    self.ida_irfnd = true;
+                    return self.handle_event(ret);
                 }
             }
-        };
 
-        (
-            err_k,
-            err_km1,
-            (ck * enorm_k) > F::Scalar::one(), // Perform error test
-        )
+            // After successful step, check for stop conditions; continue or break.
+            if let Some(ret) = self.stop_test2(tout, task)? {
+                return Ok(ret);
+            }
+        }
     }
 
-    /// IDARestore
-    /// This routine restores tn, psi, and phi in the event of a failure.
-    /// It changes back `phi-star` to `phi` (changed in `set_coeffs()`)
+    /// IDAStopTest1
     ///
+    /// This routine tests for stop conditions before taking a step. The tests depend on the value
+    /// of `task`. The variable tretlast is the previously returned value of tret.
     ///
-    pub fn restore(&mut self, saved_t: F::Scalar) -> () {
-        self.ida_tn = saved_t;
-
-        // Restore psi[0 .. kk] = psi[1 .. kk + 1] - hh
-        for j in 1..self.ida_kk + 1 {
-            self.ida_psi[j - 1] = self.ida_psi[j] - self.ida_hh;
+    /// Returns `None` if no stop condition was found, and the integration should continue.
+    fn stop_test1(
+        &mut self,
+        tout: F::Scalar,
+        task: Task,
+    ) -> Result<Option<(F::Scalar, SolveStatus)>, failure::Error> {
+        // Test for tn past tstop.
+        if self.ida_tstopset && (self.ida_tn - self.ida_tstop) * self.ida_hh > F::Scalar::zero() {
+            Err(self.bad_tstop())?;
         }
 
-        //Zip::from(&mut self.ida_psi.slice_mut(s![0..self.ida_kk]))
-        //.and(&self.ida_psi.slice(s![1..self.ida_kk+1]));
-        //ida_psi -= &self.ida_psi.slice(s![1..self.ida_kk+1]);
-
-        if self.ida_ns <= self.ida_kk {
-            // cvals[0 .. kk-ns+1] = 1 / beta[ns .. kk+1]
-            Zip::from(
-                &mut self
-                    .ida_cvals
-                    .slice_mut(s![0..self.ida_kk - self.ida_ns + 1]),
-            )
-            .and(&self.ida_beta.slice(s![self.ida_ns..self.ida_kk + 1]))
-            .apply(|cvals, &beta| {
-                *cvals = beta.recip();
-            });
-
-            // phi[ns .. (kk + 1)] *= cvals[ns .. (kk + 1)]
-            let mut ida_phi = self
-                .ida_phi
-                .slice_axis_mut(Axis(0), Slice::from(self.ida_ns..self.ida_kk + 1));
-
-            // We manually broadcast cvals here so we can turn it into a column vec
-            let cvals = self.ida_cvals.slice(s![0..self.ida_kk - self.ida_ns + 1]);
-            let cvals = cvals
-                .broadcast((1, ida_phi.len_of(Axis(0))))
-                .unwrap()
-                .reversed_axes();
+        match task {
+            Task::Normal => {
+                // Test for tout = tretlast, and for tn past tout.
+                if tout == self.ida_tretlast {
+                    self.ida_tretlast = tout;
+                    return Ok(Some((tout, SolveStatus::Success)));
+                }
+                if (self.ida_tn - tout) * self.ida_hh >= F::Scalar::zero() {
+                    if self.get_solution_yy(tout).is_err() {
+                        Err(IdaError::IllegalInput {
+                            msg: format!(
+                                "Trouble interpolating at tout = {:?}. tout too far back in \
+                                 direction of integration",
+                                tout
+                            ),
+                        })?;
+                    }
+                    self.ida_tretlast = tout;
+                    return Ok(Some((tout, SolveStatus::Success)));
+                }
+            }
 
-            ida_phi *= &cvals;
+            Task::OneStep => {
+                // Test for tn past tretlast.
+                if (self.ida_tn - self.ida_tretlast) * self.ida_hh > F::Scalar::zero() {
+                    self.get_solution_yy(self.ida_tn)?;
+                    self.ida_tretlast = self.ida_tn;
+                    return Ok(Some((self.ida_tn, SolveStatus::Success)));
+                }
+            }
         }
+
+        self.tstop_test()
     }
 
-    /// IDAHandleNFlag
-    /// This routine handles failures indicated by the input variable nflag. Positive values indicate various recoverable failures while negative values indicate nonrecoverable failures. This routine adjusts the step size for recoverable failures.
+    /// IDAStopTest2
     ///
-    ///  Possible nflag values (input):
-    ///
-    ///   --convergence failures--
-    ///   IDA_RES_RECVR              > 0
-    ///   IDA_LSOLVE_RECVR           > 0
-    ///   IDA_CONSTR_RECVR           > 0
-    ///   SUN_NLS_CONV_RECV          > 0
-    ///   IDA_RES_FAIL               < 0
-    ///   IDA_LSOLVE_FAIL            < 0
-    ///   IDA_LSETUP_FAIL            < 0
-    ///
-    ///   --error test failure--
-    ///   ERROR_TEST_FAIL            > 0
-    ///
-    ///  Possible kflag values (output):
-    ///
-    ///   --recoverable--
-    ///   PREDICT_AGAIN
+    /// This routine tests for stop conditions after taking a step. The tests depend on the value
+    /// of `task`.
     ///
-    ///   --nonrecoverable--
-    ///   IDA_CONSTR_FAIL
-    ///   IDA_REP_RES_ERR
-    ///   IDA_ERR_FAIL
-    ///   IDA_CONV_FAIL
-    ///   IDA_RES_FAIL
-    ///   IDA_LSETUP_FAIL
-    ///   IDA_LSOLVE_FAIL
-    pub fn handle_n_flag(
+    /// Returns `None` if no stop condition was found, and the integration should continue.
+    fn stop_test2(
         &mut self,
-        nflag: u32,
-        err_k: F::Scalar,
-        err_km1: F::Scalar, //long int *ncfnPtr,
-                            //int *ncfPtr,
-                            //long int *netfPtr,
-                            //int *nefPtr
-    ) -> () {
-        unimplemented!();
-    }
-
-    /// IDAReset
-    /// This routine is called only if we need to predict again at the very first step. In such a case,
-    /// reset phi[1] and psi[0].
-    pub fn reset(&mut self) -> () {
-        self.ida_psi[0] = self.ida_hh;
-        //N_VScale(IDA_mem->ida_rr, IDA_mem->ida_phi[1], IDA_mem->ida_phi[1]);
-        self.ida_phi *= self.ida_rr;
-    }
-
-    /// IDACompleteStep
-    /// This routine completes a successful step.  It increments nst, saves the stepsize and order
-    /// used, makes the final selection of stepsize and order for the next step, and updates the phi
-    /// array.
-    pub fn complete_step(&mut self, err_k: F::Scalar, err_km1: F::Scalar) -> () {
-        self.ida_nst += 1;
-        let kdiff = self.ida_kk - self.ida_kused;
-        self.ida_kused = self.ida_kk;
-        self.ida_hused = self.ida_hh;
+        tout: F::Scalar,
+        task: Task,
+    ) -> Result<Option<(F::Scalar, SolveStatus)>, failure::Error> {
+        // Test for tn past tout.
+        if task == Task::Normal && (self.ida_tn - tout) * self.ida_hh >= F::Scalar::zero() {
+            self.get_solution_yy(tout)?;
+            self.ida_tretlast = tout;
+            return Ok(Some((tout, SolveStatus::Success)));
+        }
 
-        if (self.ida_knew == self.ida_kk - 1) || (self.ida_kk == self.ida_maxord) {
-            self.ida_phase = 1;
+        let ret = self.tstop_test()?;
+        if ret.is_some() || task == Task::Normal {
+            return Ok(ret);
         }
 
-        // For the first few steps, until either a step fails, or the order is reduced, or the
-        // order reaches its maximum, we raise the order and double the stepsize. During these
-        // steps, phase = 0. Thereafter, phase = 1, and stepsize and order are set by the usual
-        // local error algorithm.
-        //
-        // Note that, after the first step, the order is not increased, as not all of the
-        // neccessary information is available yet.
+        // Return y(tn) and yp(tn)
+        self.ida_tretlast = self.ida_tn;
+        Ok(Some((self.ida_tn, SolveStatus::Success)))
+    }
 
-        if self.ida_phase == 0 {
-            if self.ida_nst > 1 {
-                self.ida_kk += 1;
-                let mut hnew = F::Scalar::from(2.0).unwrap() * self.ida_hh;
-                let tmp = hnew.abs() * self.ida_hmax_inv;
-                if tmp > F::Scalar::one() {
-                    hnew /= tmp;
+    /// Tests for tn at or near tstop. If tstop was reached, the solution is interpolated there,
+    /// and tstop is disabled. Otherwise, hh is reduced if the next step would pass tstop.
+    fn tstop_test(&mut self) -> Result<Option<(F::Scalar, SolveStatus)>, failure::Error> {
+        if self.ida_tstopset {
+            let troundoff = F::Scalar::from(100.0).unwrap()
+                * F::Scalar::epsilon()
+                * (self.ida_tn.abs() + self.ida_hh.abs());
+            if (self.ida_tn - self.ida_tstop).abs() <= troundoff {
+                if self.get_solution_yy(self.ida_tstop).is_err() {
+                    Err(self.bad_tstop())?;
                 }
-                self.ida_hh = hnew;
+                self.ida_tretlast = self.ida_tstop;
+                self.ida_tstopset = false;
+                return Ok(Some((self.ida_tstop, SolveStatus::TstopReturn)));
             }
-        } else {
-            enum Action {
-                None,
-                Lower,
-                Maintain,
-                Raise,
+            if (self.ida_tn + self.ida_hh - self.ida_tstop) * self.ida_hh > F::Scalar::zero() {
+                self.ida_hh = (self.ida_tstop - self.ida_tn)
+                    * (F::Scalar::one() - F::Scalar::from(4.0).unwrap() * F::Scalar::epsilon());
             }
+        }
+        Ok(None)
+    }
 
-            let mut action = Action::None;
+    /// Finds the first discontinuity strictly ahead of tn in the direction of integration, if
+    /// any, and reduces hh if the next step would pass it.
+    fn discontinuity_test(&mut self) -> Option<F::Scalar> {
+        let ahead = |t: &&F::Scalar| (**t - self.ida_tn) * self.ida_hh > F::Scalar::zero();
+        let tdisc = if self.ida_hh > F::Scalar::zero() {
+            self.ida_tdisc.iter().find(ahead).cloned()
+        } else {
+            self.ida_tdisc.iter().rev().find(ahead).cloned()
+        }?;
 
-            // Set action = LOWER/MAINTAIN/RAISE to specify order decision
+        if (self.ida_tn + self.ida_hh - tdisc) * self.ida_hh > F::Scalar::zero() {
+            self.ida_hh = (tdisc - self.ida_tn)
+                * (F::Scalar::one() - F::Scalar::from(4.0).unwrap() * F::Scalar::epsilon());
+        }
+        Some(tdisc)
+    }
 
-            if self.ida_knew == (self.ida_kk - 1) {
-                action = Action::Lower;
-            } else if self.ida_kk == self.ida_maxord {
-                action = Action::Maintain;
-            } else if (self.ida_kk + 1) >= self.ida_ns || (kdiff == 1) {
-                action = Action::Maintain;
-            }
+    /// Restarts the integration at order 1 from the solution at tn, discarding the history in
+    /// phi, with the step size hh chosen for the next step. Used at discontinuities, where the
+    /// interpolating polynomial of the previous steps is no longer valid.
+    ///
+    /// If the component types have been set with `set_id()`, the algebraic components of y and
+    /// the differential components of y' are recomputed as by `calc_ic()` with
+    /// `IcOption::YaYdpInit`, at a time just past tn, so that the first step starts from values
+    /// consistent with the residual after the discontinuity. Otherwise, the restart uses the
+    /// values from before the discontinuity, which may take a number of error test failures if
+    /// y' jumps.
+    fn restart(&mut self) -> Result<(), failure::Error> {
+        let (tdisc, hh) = (self.ida_tn, self.ida_hh);
+        self.get_solution_yy(tdisc)?;
+        self.ida_phi.fill(F::Scalar::zero());
+        self.ida_phi.index_axis_mut(Axis(0), 0).assign(&self.ida_yy);
+        self.ida_phi.index_axis_mut(Axis(0), 1).assign(&self.ida_yp);
+
+        if self.ida_id.is_some() {
+            let smallh =
+                F::Scalar::from(100.0).unwrap() * F::Scalar::epsilon() * (tdisc.abs() + hh.abs());
+            self.ida_tn = if hh > F::Scalar::zero() {
+                tdisc + smallh
+            } else {
+                tdisc - smallh
+            };
+            // The time scale gives an IC step size of about hh
+            let ret = self.ic_solve(
+                IcOption::YaYdpInit,
+                self.ida_tn + F::Scalar::from(1000.0).unwrap() * hh,
+            );
+            self.ida_tn = tdisc;
+            ret?;
 
-            // Estimate the error at order k+1, unless already decided to reduce order, or already using
-            // maximum order, or stepsize has not been constant, or order was just raised.
+            self.ida_hh = hh;
+            self.ida_epsNewt = self.ida_epcon;
+        }
 
-            let mut err_kp1 = F::Scalar::zero();
+        let mut phi1 = self.ida_phi.index_axis_mut(Axis(0), 1);
+        phi1 *= hh;
 
-            if let Action::None = action {
-                //N_VLinearSum(ONE, IDA_mem->ida_ee, -ONE, IDA_mem->ida_phi[IDA_mem->ida_kk + 1], IDA_mem->ida_tempv1);
-                let ida_tempv1 = &self.ida_ee - &self.ida_phi.index_axis(Axis(0), self.ida_kk + 1);
-                let enorm = self.wrms_norm(&ida_tempv1, &self.ida_ewt, self.ida_suppressalg);
-                err_kp1 = enorm / F::Scalar::from(self.ida_kk + 2).unwrap();
+        // step() treats the next step as a first step
+        self.ida_kused = 0;
+        Ok(())
+    }
 
-                // Choose among orders k-1, k, k+1 using local truncation error norms.
+    /// MSG_BAD_TSTOP
+    fn bad_tstop(&self) -> IdaError {
+        IdaError::IllegalInput {
+            msg: format!(
+                "The value tstop = {:?} is behind current t = {:?} in the direction of integration.",
+                self.ida_tstop, self.ida_tn
+            ),
+        }
+    }
 
-                let terr_k = F::Scalar::from(self.ida_kk + 1).unwrap() * err_k;
-                let terr_kp1 = F::Scalar::from(self.ida_kk + 2).unwrap() * err_kp1;
+    /// Interpolates the solution at t into the work vectors yy and yp, see `get_solution()`.
+    fn get_solution_yy(&mut self, t: F::Scalar) -> Result<(), failure::Error> {
+        let mut yy = std::mem::replace(&mut self.ida_yy, Array::zeros(0));
+        let mut yp = std::mem::replace(&mut self.ida_yp, Array::zeros(0));
+        let ret = self.get_solution(t, &mut yy, &mut yp);
+        self.ida_yy = yy;
+        self.ida_yp = yp;
+        ret
+    }
 
-                if self.ida_kk == 1 {
-                    if terr_kp1 >= F::Scalar::from(0.5).unwrap() * terr_k {
-                        action = Action::Maintain;
-                    } else {
-                        action = Action::Raise;
-                    }
+    /// Evaluates the root functions at (t, yy, yp) into gout, and counts the call in nge.
+    fn gfun<S1, S2, S3>(
+        f: &mut F,
+        nge: &mut u64,
+        t: F::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        gout: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), failure::Error>
+    where
+        S1: Data<Elem = F::Scalar>,
+        S2: Data<Elem = F::Scalar>,
+        S3: DataMut<Elem = F::Scalar>,
+    {
+        *nge += 1;
+        f.roots(t, yy, yp, gout)
+            .map_err(|_| IdaError::RootFunctionFail {})?;
+        Ok(())
+    }
+
+    /// IDARcheck1
+    ///
+    /// This routine completes the initialization of rootfinding memory information, and checks
+    /// whether g has a zero both at and very near the initial point of the IVP.
+    ///
+    /// Components of g that are exactly zero at t0 are deactivated, unless they are nonzero at
+    /// a point slightly ahead of t0, in which case that value is used as the initial glo.
+    fn rcheck1(&mut self) -> Result<(), failure::Error> {
+        self.ida_iroots.fill(0);
+        self.ida_tlo = self.ida_tn;
+        self.ida_ttol = (self.ida_tn.abs() + self.ida_hh.abs())
+            * F::Scalar::epsilon()
+            * F::Scalar::from(100.0).unwrap();
+
+        // Evaluate g at initial t and check for zero values.
+        Self::gfun(
+            &mut self.f,
+            &mut self.ida_nge,
+            self.ida_tlo,
+            &self.ida_phi.index_axis(Axis(0), 0),
+            &self.ida_phi.index_axis(Axis(0), 1),
+            &mut self.ida_glo,
+        )?;
+
+        let mut zroot = false;
+        for (gactive, &glo) in self.ida_gactive.iter_mut().zip(&self.ida_glo) {
+            if glo == F::Scalar::zero() {
+                zroot = true;
+                *gactive = false;
+            }
+        }
+        if !zroot {
+            return Ok(());
+        }
+
+        // Some g_i is zero at t0; look at g at t0+(small increment).
+        let hratio = (self.ida_ttol / self.ida_hh.abs()).max(F::Scalar::from(0.1).unwrap());
+        let smallh = hratio * self.ida_hh;
+        let tplus = self.ida_tlo + smallh;
+        Zip::from(&mut self.ida_yy)
+            .and(&self.ida_phi.index_axis(Axis(0), 0))
+            .and(&self.ida_phi.index_axis(Axis(0), 1))
+            .apply(|yy, &phi0, &phi1| *yy = phi0 + smallh * phi1);
+        Self::gfun(
+            &mut self.f,
+            &mut self.ida_nge,
+            tplus,
+            &self.ida_yy,
+            &self.ida_phi.index_axis(Axis(0), 1),
+            &mut self.ida_ghi,
+        )?;
+
+        // We check now only the components of g which were exactly 0.0 at t0 to see if we can
+        // 'activate' them.
+        Zip::from(&mut self.ida_gactive)
+            .and(&mut self.ida_glo)
+            .and(&self.ida_ghi)
+            .apply(|gactive, glo, &ghi| {
+                if !*gactive && ghi != F::Scalar::zero() {
+                    *gactive = true;
+                    *glo = ghi;
+                }
+            });
+        Ok(())
+    }
+
+    /// IDARcheck2
+    ///
+    /// This routine checks for exact zeros of g at the last root found, if the last return was a
+    /// root. It then checks for a close pair of zeros (an error condition), and for a new root
+    /// at a nearby point. The array glo = g(tlo) at the left endpoint of the search interval is
+    /// adjusted if necessary to assure that all g_i are nonzero there, before returning to do a
+    /// root search in the interval.
+    ///
+    /// On entry, tlo = tretlast is the last value of tret returned by `solve()`. This may be the
+    /// previous tn, the previous tout value, or the last root location.
+    ///
+    /// Returns the root return to `solve()` if a new zero of g was found near tlo.
+    fn rcheck2(&mut self) -> Result<Option<(F::Scalar, SolveStatus)>, failure::Error> {
+        if !self.ida_irfnd {
+            return Ok(None);
+        }
+
+        self.get_solution_yy(self.ida_tlo)?;
+        Self::gfun(
+            &mut self.f,
+            &mut self.ida_nge,
+            self.ida_tlo,
+            &self.ida_yy,
+            &self.ida_yp,
+            &mut self.ida_glo,
+        )?;
+
+        let mut zroot = false;
+        self.ida_iroots.fill(0);
+        for i in 0..self.ida_nrtfn {
+            if self.ida_gactive[i] && self.ida_glo[i] == F::Scalar::zero() {
+                zroot = true;
+                self.ida_iroots[i] = 1;
+            }
+        }
+        if !zroot {
+            return Ok(None);
+        }
+
+        // One or more g_i has a zero at tlo. Check g at tlo+smallh.
+        self.ida_ttol = (self.ida_tn.abs() + self.ida_hh.abs())
+            * F::Scalar::epsilon()
+            * F::Scalar::from(100.0).unwrap();
+        let smallh = if self.ida_hh > F::Scalar::zero() {
+            self.ida_ttol
+        } else {
+            -self.ida_ttol
+        };
+        let tplus = self.ida_tlo + smallh;
+        if (tplus - self.ida_tn) * self.ida_hh >= F::Scalar::zero() {
+            let hratio = smallh / self.ida_hh;
+            Zip::from(&mut self.ida_yy)
+                .and(&self.ida_phi.index_axis(Axis(0), 1))
+                .apply(|yy, &phi1| *yy += hratio * phi1);
+        } else {
+            self.get_solution_yy(tplus)?;
+        }
+        Self::gfun(
+            &mut self.f,
+            &mut self.ida_nge,
+            tplus,
+            &self.ida_yy,
+            &self.ida_yp,
+            &mut self.ida_ghi,
+        )?;
+
+        // Check for close roots (error return), for a new zero at tlo+smallh, and for a g_i that
+        // changed from zero to nonzero.
+        let mut zroot = false;
+        for i in 0..self.ida_nrtfn {
+            if !self.ida_gactive[i] {
+                continue;
+            }
+            if self.ida_ghi[i] == F::Scalar::zero() {
+                if self.ida_iroots[i] == 1 {
+                    Err(IdaError::IllegalInput {
+                        msg: format!("Root found at and very near t = {:?}.", self.ida_tlo),
+                    })?;
+                }
+                zroot = true;
+                self.ida_iroots[i] = 1;
+            } else if self.ida_iroots[i] == 1 {
+                self.ida_glo[i] = self.ida_ghi[i];
+            }
+        }
+        if zroot {
+            self.ida_tretlast = self.ida_tlo;
+            return Ok(Some((self.ida_tlo, self.root_return())));
+        }
+        Ok(None)
+    }
+
+    /// IDARcheck3
+    ///
+    /// This routine interfaces to `root_find()` to look for a root of g between tlo and either
+    /// tn or tout, whichever comes first. Only roots beyond tlo in the direction of integration
+    /// are sought.
+    ///
+    /// Returns the root return to `solve()` if a root was found, with the solution at the root
+    /// interpolated into yy and yp.
+    fn rcheck3(&mut self) -> Result<Option<(F::Scalar, SolveStatus)>, failure::Error> {
+        // Set thi = tn or tout, whichever comes first.
+        self.ida_thi = match self.ida_taskc {
+            Task::OneStep => self.ida_tn,
+            Task::Normal => {
+                if (self.ida_toutc - self.ida_tn) * self.ida_hh >= F::Scalar::zero() {
+                    self.ida_tn
                 } else {
-                    let terr_km1 = F::Scalar::from(self.ida_kk).unwrap() * err_km1;
-                    if terr_km1 <= terr_k.min(terr_kp1) {
-                        action = Action::Lower;
-                    } else if terr_kp1 >= terr_k {
-                        action = Action::Maintain;
-                    } else {
-                        action = Action::Raise;
-                    }
+                    self.ida_toutc
                 }
             }
-            //takeaction:
+        };
 
-            // Set the estimated error norm and, on change of order, reset kk.
-            let err_knew = match action {
-                Action::Raise => {
-                    self.ida_kk += 1;
-                    err_kp1
+        // Get y and y' at thi.
+        self.get_solution_yy(self.ida_thi)?;
+
+        // Set ghi = g(thi) and call root_find() to search (tlo,thi) for roots.
+        Self::gfun(
+            &mut self.f,
+            &mut self.ida_nge,
+            self.ida_thi,
+            &self.ida_yy,
+            &self.ida_yp,
+            &mut self.ida_ghi,
+        )?;
+        self.ida_ttol = (self.ida_tn.abs() + self.ida_hh.abs())
+            * F::Scalar::epsilon()
+            * F::Scalar::from(100.0).unwrap();
+        let found = self.root_find()?;
+
+        // Reset root-finding flags
+        Zip::from(&mut self.ida_gactive)
+            .and(&self.ida_grout)
+            .apply(|gactive, &grout| {
+                if !*gactive && grout != F::Scalar::zero() {
+                    *gactive = true;
                 }
-                Action::Lower => {
-                    self.ida_kk -= 1;
-                    err_km1
+            });
+
+        // Set tlo = trout
+        self.ida_tlo = self.ida_trout;
+        self.ida_glo.assign(&self.ida_grout);
+
+        // If no root found, return None.
+        if !found {
+            return Ok(None);
+        }
+
+        // If a root was found, interpolate to get y(trout) and return.
+        self.get_solution_yy(self.ida_trout)?;
+        self.ida_tretlast = self.ida_trout;
+        Ok(Some((self.ida_trout, self.root_return())))
+    }
+
+    /// Calls the event action hook, if any, at the root return `ret` from `solve()`, and
+    /// restarts the integration at the root as requested by the hook. For
+    /// `EventAction::ReinitCalcIc`, the IC calculation looks ahead in the direction of
+    /// integration, with a time scale from the last step size, as in `restart()`.
+    fn handle_event(
+        &mut self,
+        ret: (F::Scalar, SolveStatus),
+    ) -> Result<(F::Scalar, SolveStatus), failure::Error> {
+        let (tret, status) = ret;
+        let event = match (self.ida_event_fn, &status) {
+            (Some(efun), SolveStatus::RootReturn(roots)) => {
+                let mut yy = self.ida_yy.clone();
+                let mut yp = self.ida_yp.clone();
+                let action = efun(&mut self.f, tret, roots, yy.view_mut(), yp.view_mut())?;
+                Some((action, yy, yp))
+            }
+            _ => None,
+        };
+
+        match event {
+            None | Some((EventAction::Continue, _, _)) => {}
+            Some((EventAction::Reinit, yy, yp)) => {
+                self.reinit(tret, yy, yp)?;
+                self.ida_yy.assign(&self.ida_phi.index_axis(Axis(0), 0));
+                self.ida_yp.assign(&self.ida_phi.index_axis(Axis(0), 1));
+            }
+            Some((EventAction::ReinitCalcIc(icopt), yy, yp)) => {
+                // Check the IC inputs before the current state is discarded
+                if icopt == IcOption::YaYdpInit && self.ida_id.is_none() {
+                    Err(IdaError::IllegalInput {
+                        msg: "id = NULL conflicts with icopt.".into(),
+                    })?;
                 }
-                _ => err_k,
-            };
+                let tout1 = tret + F::Scalar::from(1000.0).unwrap() * self.ida_hh;
 
-            // Compute rr = tentative ratio hnew/hh from error norm estimate.
-            // Reduce hh if rr <= 1, double hh if rr >= 2, else leave hh as is.
-            // If hh is reduced, hnew/hh is restricted to be between .5 and .9.
+                self.reinit(tret, yy, yp)?;
+                self.calc_ic(icopt, tout1)?;
+            }
+        }
+        Ok((tret, status))
+    }
 
-            let mut hnew = self.ida_hh;
-            //ida_rr = SUNRpowerR( TWO * err_knew + PT0001, -ONE/(IDA_mem->ida_kk + 1) );
-            self.ida_rr = {
-                let base =
-                    F::Scalar::from(2.0).unwrap() * err_knew + F::Scalar::from(0.0001).unwrap();
-                let arg =
-                    -F::Scalar::one() / (F::Scalar::from(self.ida_kk).unwrap() + F::Scalar::one());
-                base.powf(arg)
+    /// The root return status, with the indices of the functions with a root in iroots
+    fn root_return(&self) -> SolveStatus {
+        SolveStatus::RootReturn(
+            self.ida_iroots
+                .indexed_iter()
+                .filter(|(_, &iroot)| iroot != 0)
+                .map(|(i, _)| i)
+                .collect(),
+        )
+    }
+
+    /// Whether a zero crossing of g_i starting from glo_i is in a direction sought by rootdir
+    fn root_dir_ok(&self, i: usize) -> bool {
+        F::Scalar::from(self.ida_rootdir[i]).unwrap() * self.ida_glo[i] <= F::Scalar::zero()
+    }
+
+    /// Returns whether any active g_i (with an admissible direction) is zero in g, and among
+    /// those that changed sign from glo, the index of the one with the largest
+    /// `|g_i/(g_i - glo_i)|`, if any.
+    fn root_sign_change(&self, g: &Array<F::Scalar, Ix1>) -> (bool, Option<usize>) {
+        let mut maxfrac = F::Scalar::zero();
+        let mut zroot = false;
+        let mut imax = None;
+        for i in 0..self.ida_nrtfn {
+            if !self.ida_gactive[i] {
+                continue;
+            }
+            if g[i] == F::Scalar::zero() {
+                if self.root_dir_ok(i) {
+                    zroot = true;
+                }
+            } else if self.ida_glo[i] * g[i] < F::Scalar::zero() && self.root_dir_ok(i) {
+                let gfrac = (g[i] / (g[i] - self.ida_glo[i])).abs();
+                if gfrac > maxfrac {
+                    maxfrac = gfrac;
+                    imax = Some(i);
+                }
+            }
+        }
+        (zroot, imax)
+    }
+
+    /// IDARootfind
+    ///
+    /// This routine solves for a root of g(t) between tlo and thi, if one exists. Only roots of
+    /// odd multiplicity (i.e. with a change of sign in one of the g_i), or exact zeros, are
+    /// found. Here the sign of tlo - thi is arbitrary, but if multiple roots are found, the one
+    /// closest to tlo is returned.
+    ///
+    /// The method used is the Illinois algorithm, a modified secant method. Reference: Kathie
+    /// L. Hiebert and Lawrence F. Shampine, Implicitly Defined Output Points for Solutions of
+    /// ODEs, Sandia National Laboratory Report SAND80-0180, February 1980.
+    ///
+    /// On entry, glo = g(tlo) and ghi = g(thi) must be set, and ttol is the convergence
+    /// tolerance on the root location. On return, trout and grout = g(trout) are set to the
+    /// root location, or to thi if no root was found, and iroots holds the direction of the
+    /// crossing of each g_i with a root at trout (1 for increasing, -1 for decreasing, 0 if no
+    /// root).
+    ///
+    /// Returns whether a root was found.
+    fn root_find(&mut self) -> Result<bool, failure::Error> {
+        // First check for change in sign in ghi or for a zero in ghi.
+        let (zroot, imax) = self.root_sign_change(&self.ida_ghi);
+
+        // If no sign change was found, reset trout and grout. Then return false if no zero was
+        // found, or set iroots and return true.
+        let mut imax = match imax {
+            Some(imax) => imax,
+            None => {
+                self.ida_trout = self.ida_thi;
+                self.ida_grout.assign(&self.ida_ghi);
+                if !zroot {
+                    return Ok(false);
+                }
+                for i in 0..self.ida_nrtfn {
+                    self.ida_iroots[i] = 0;
+                    if self.ida_gactive[i]
+                        && self.ida_ghi[i] == F::Scalar::zero()
+                        && self.root_dir_ok(i)
+                    {
+                        self.ida_iroots[i] = if self.ida_glo[i] > F::Scalar::zero() {
+                            -1
+                        } else {
+                            1
+                        };
+                    }
+                }
+                return Ok(true);
+            }
+        };
+
+        // A sign change was found. Loop to locate nearest root.
+        let half = F::Scalar::from(0.5).unwrap();
+        let mut alph = F::Scalar::one();
+        let mut side = 0;
+        let mut sideprev = -1;
+        loop {
+            // If interval size is already less than tolerance ttol, break.
+            if (self.ida_thi - self.ida_tlo).abs() <= self.ida_ttol {
+                break;
+            }
+
+            // Set weight alph. On the first two passes, set alph = 1. Thereafter, reset alph
+            // according to the side (low vs high) of the subinterval in which the sign change
+            // was found in the previous two passes. If the sides were opposite, set alph = 1.
+            // If the sides were the same, then double alph (if high side), or halve alph (if
+            // low side). The next guess tmid is the secant method value if alph = 1, but is
+            // closer to tlo if alph < 1, and closer to thi if alph > 1.
+            if sideprev == side {
+                alph = if side == 2 {
+                    alph * F::Scalar::from(2.0).unwrap()
+                } else {
+                    alph * half
+                };
+            } else {
+                alph = F::Scalar::one();
+            }
+
+            // Set next root approximation tmid and get g(tmid). If tmid is too close to tlo or
+            // thi, adjust it inward, by a fractional distance that is between 0.1 and 0.5.
+            let mut tmid = self.ida_thi
+                - (self.ida_thi - self.ida_tlo) * self.ida_ghi[imax]
+                    / (self.ida_ghi[imax] - alph * self.ida_glo[imax]);
+            let fracsub = || {
+                let fracint = (self.ida_thi - self.ida_tlo).abs() / self.ida_ttol;
+                if fracint > F::Scalar::from(5.0).unwrap() {
+                    F::Scalar::from(0.1).unwrap()
+                } else {
+                    half / fracint
+                }
             };
+            if (tmid - self.ida_tlo).abs() < half * self.ida_ttol {
+                tmid = self.ida_tlo + fracsub() * (self.ida_thi - self.ida_tlo);
+            }
+            if (self.ida_thi - tmid).abs() < half * self.ida_ttol {
+                tmid = self.ida_thi - fracsub() * (self.ida_thi - self.ida_tlo);
+            }
 
-            if self.ida_rr >= F::Scalar::from(2.0).unwrap() {
-                hnew = F::Scalar::from(2.0).unwrap() * self.ida_hh;
-                let tmp = hnew.abs() * self.ida_hmax_inv;
-                if tmp > F::Scalar::one() {
-                    hnew /= tmp;
+            self.get_solution_yy(tmid)?;
+            Self::gfun(
+                &mut self.f,
+                &mut self.ida_nge,
+                tmid,
+                &self.ida_yy,
+                &self.ida_yp,
+                &mut self.ida_grout,
+            )?;
+
+            // Check to see in which subinterval g changes sign, and reset imax. Set side = 1 if
+            // sign change is on low side, or 2 if on high side.
+            let (zroot, imid) = self.root_sign_change(&self.ida_grout);
+            sideprev = side;
+
+            if let Some(imid) = imid {
+                // Sign change found in (tlo,tmid); replace thi with tmid.
+                imax = imid;
+                self.ida_thi = tmid;
+                self.ida_ghi.assign(&self.ida_grout);
+                side = 1;
+                // Stop at root thi if converged; otherwise loop.
+                if (self.ida_thi - self.ida_tlo).abs() <= self.ida_ttol {
+                    break;
                 }
-            } else if self.ida_rr <= F::Scalar::one() {
-                //ida_rr = SUNMAX(HALF, SUNMIN(PT9,IDA_mem->ida_rr));
-                self.ida_rr = F::Scalar::from(0.5)
-                    .unwrap()
-                    .max(self.ida_rr.min(F::Scalar::from(0.9).unwrap()));
-                hnew = self.ida_hh * self.ida_rr;
+                continue;
             }
 
-            self.ida_hh = hnew;
-        }
-        // end of phase if block
+            if zroot {
+                // No sign change in (tlo,tmid), but g = 0 at tmid; return root tmid.
+                self.ida_thi = tmid;
+                self.ida_ghi.assign(&self.ida_grout);
+                break;
+            }
 
-        // Save ee for possible order increase on next step
-        if self.ida_kused < self.ida_maxord {
-            //N_VScale(ONE, IDA_mem->ida_ee, IDA_mem->ida_phi[IDA_mem->ida_kused + 1]);
-            self.ida_phi
-                .index_axis_mut(Axis(0), self.ida_kused + 1)
-                .assign(&self.ida_ee);
+            // No sign change in (tlo,tmid), and no zero at tmid. Sign change must be in
+            // (tmid,thi). Replace tlo with tmid.
+            self.ida_tlo = tmid;
+            self.ida_glo.assign(&self.ida_grout);
+            side = 2;
+            // Stop at root thi if converged; otherwise loop back.
+            if (self.ida_thi - self.ida_tlo).abs() <= self.ida_ttol {
+                break;
+            }
         }
 
-        // Update phi arrays
-
-        // To update phi arrays compute X += Z where                  */
-        // X = [ phi[kused], phi[kused-1], phi[kused-2], ... phi[1] ] */
-        // Z = [ ee,         phi[kused],   phi[kused-1], ... phi[0] ] */
-        self.ida_Zvecs
-            .index_axis_mut(Axis(0), 0)
-            .assign(&self.ida_ee);
-        self.ida_Zvecs
-            .slice_mut(s![1..self.ida_kused + 1, ..])
-            .assign(&self.ida_phi.slice(s![1..self.ida_kused + 1;-1, ..]));
-        self.ida_Xvecs
-            .slice_mut(s![1..self.ida_kused + 1, ..])
-            .assign(&self.ida_phi.slice(s![0..self.ida_kused;-1, ..]));
-
-        let mut sliceXvecs = self
-            .ida_Xvecs
-            .slice_axis_mut(Axis(0), Slice::from(0..self.ida_kused + 1));
-        let sliceZvecs = self
-            .ida_Zvecs
-            .slice_axis(Axis(0), Slice::from(0..self.ida_kused + 1));
-        sliceXvecs += &sliceZvecs;
+        // Reset trout and grout, set iroots, and return true.
+        self.ida_trout = self.ida_thi;
+        self.ida_grout.assign(&self.ida_ghi);
+        for i in 0..self.ida_nrtfn {
+            self.ida_iroots[i] = 0;
+            if !self.ida_gactive[i] || !self.root_dir_ok(i) {
+                continue;
+            }
+            if self.ida_ghi[i] == F::Scalar::zero()
+                || self.ida_glo[i] * self.ida_ghi[i] < F::Scalar::zero()
+            {
+                self.ida_iroots[i] = if self.ida_glo[i] > F::Scalar::zero() {
+                    -1
+                } else {
+                    1
+                };
+            }
+        }
+        Ok(true)
     }
 
-    /// This routine evaluates `y(t)` and `y'(t)` as the value and derivative of the interpolating
-    /// polynomial at the independent variable t, and stores the results in the vectors yret and ypret.
-    /// It uses the current independent variable value, tn, and the method order last used, kused.
-    /// This function is called by `solve` with `t = tout`, `t = tn`, or `t = tstop`.
+    /// IDACalcIC
     ///
-    /// If `kused = 0` (no step has been taken), or if `t = tn`, then the order used here is taken
-    /// to be 1, giving `yret = phi[0]`, `ypret = phi[1]/psi[0]`.
+    /// This computes consistent initial values for the problem, given the initial values supplied
+    /// to `new()`, using a damped Newton iteration with a linesearch. `tout1` is the first value
+    /// of t at which a solution will be requested, and is used to set the time scale of the
+    /// iteration.
     ///
-    /// The return values are:
-    ///   IDA_SUCCESS  if t is legal, or
-    ///   IDA_BAD_T    if t is not within the interval of the last step taken.
-    pub fn get_solution(
-        &mut self,
-        t: F::Scalar,
-        yret: &mut Array<F::Scalar, Ix1>,
-        ypret: &mut Array<F::Scalar, Ix1>,
-    ) -> Result<(), failure::Error> {
-        // Check t for legality.  Here tn - hused is t_{n-1}.
+    /// Depending on `icopt`:
+    /// * `IcOption::YaYdpInit` computes the algebraic components of y and the differential
+    ///   components of y', given the differential components of y. The component types must have
+    ///   been set with `set_id()`.
+    /// * `IcOption::YInit` computes all components of y, given y'.
+    ///
+    /// On success, the consistent values are available from `get_yy()` and `get_yp()`, and are
+    /// used as initial values by the next call to `solve()`.
+    pub fn calc_ic(&mut self, icopt: IcOption, tout1: F::Scalar) -> Result<(), failure::Error> {
+        // Check for legal input arguments
+        if self.ida_nst != 0 {
+            Err(IdaError::IllegalInput {
+                msg: "IDACalcIC -- illegal call; nst > 0".into(),
+            })?;
+        }
 
-        //tfuzz = HUNDRED * IDA_mem->ida_uround * (SUNRabs(IDA_mem->ida_tn) + SUNRabs(IDA_mem->ida_hh));
+        if icopt == IcOption::YaYdpInit && self.ida_id.is_none() {
+            Err(IdaError::IllegalInput {
+                msg: "id = NULL conflicts with icopt.".into(),
+            })?;
+        }
 
-        let mut tfuzz = F::Scalar::from(100.0).unwrap()
+        let tdist = (tout1 - self.ida_tn).abs();
+        let troundoff = F::Scalar::from(2.0).unwrap()
             * F::Scalar::epsilon()
-            * (self.ida_tn.abs() + self.ida_hh.abs());
-        if self.ida_hh < F::Scalar::zero() {
-            tfuzz = -tfuzz;
-        }
-        let tp = self.ida_tn - self.ida_hused - tfuzz;
-        if (t - tp) * self.ida_hh < F::Scalar::zero() {
-            Err(IdaError::BadTimeValue {
-                t: t.to_f64().unwrap(),
-                tdiff: (self.ida_tn - self.ida_hused).to_f64().unwrap(),
-                tcurr: self.ida_tn.to_f64().unwrap(),
+            * (self.ida_tn.abs() + tout1.abs());
+        if tdist == F::Scalar::zero() || tdist < troundoff {
+            Err(IdaError::IllegalInput {
+                msg: "tout1 too close to t0 to attempt initial condition calculation.".into(),
             })?;
         }
 
-        // Initialize kord = (kused or 1).
-        let kord = if self.ida_kused == 0 {
-            1
-        } else {
-            self.ida_kused
-        };
+        if !self.ida_SetupDone {
+            self.initial_setup()?;
+            self.ida_SetupDone = true;
+        }
 
-        // Accumulate multiples of columns phi[j] into yret and ypret.
-        let delt = t - self.ida_tn;
-        let mut c = F::Scalar::one();
-        let mut d = F::Scalar::zero();
-        let mut gam = delt / self.ida_psi[0];
+        self.ic_solve(icopt, tout1)
+    }
 
-        self.ida_cvals[0] = c;
-        for j in 1..kord {
-            d = d * gam + c / self.ida_psi[j - 1];
-            c = c * gam;
-            gam = (delt + self.ida_psi[j - 1]) / self.ida_psi[j];
+    /// Computes consistent initial values at tn for `calc_ic()`, starting from phi[0] and
+    /// phi[1], with the time scale |tout1 - tn|. On success, the consistent values are in phi[0]
+    /// and phi[1], as well as in yy and yp.
+    fn ic_solve(&mut self, icopt: IcOption, tout1: F::Scalar) -> Result<(), failure::Error> {
+        let tdist = (tout1 - self.ida_tn).abs();
+        self.ida_icopt = icopt;
+
+        // Initialize the work vectors yy0 = yy and yp0 = yp.
+        self.ida_yy.assign(&self.ida_phi.index_axis(Axis(0), 0));
+        self.ida_yp.assign(&self.ida_phi.index_axis(Axis(0), 1));
+
+        // For use in the YaYdpInit case, set sysindex and tscale.
+        self.ida_sysindex = 1;
+        self.ida_tscale = tdist;
+        if icopt == IcOption::YaYdpInit && self.ida_id.iter().flatten().all(|&id| id) {
+            self.ida_sysindex = 0;
+        }
 
-            self.ida_cvals[j] = c;
-            self.ida_dvals[j - 1] = d;
+        // Set the test constant in the Newton convergence test
+        self.ida_epsNewt = self.ida_epiccon;
+
+        // Set hic, hh, cj, and mxnh.
+        let mut hic = F::Scalar::from(0.001).unwrap() * tdist;
+        let ypnorm = self.wrms_norm(&self.ida_yp, &self.ida_ewt, self.ida_suppressalg);
+        if ypnorm > F::Scalar::from(0.5).unwrap() / hic {
+            hic = F::Scalar::from(0.5).unwrap() / ypnorm;
         }
+        if tout1 < self.ida_tn {
+            hic = -hic;
+        }
+        self.ida_hh = hic;
+        let mxnh = match icopt {
+            IcOption::YaYdpInit => {
+                self.ida_cj = hic.recip();
+                self.ida_maxnh
+            }
+            IcOption::YInit => {
+                self.ida_cj = F::Scalar::zero();
+                1
+            }
+        };
 
-        //retval = N_VLinearCombination(kord+1, IDA_mem->ida_cvals, IDA_mem->ida_phi,  yret);
-        ndarray::Zip::from(yret)
-            .and(
-                self.ida_phi
-                    .slice_axis(Axis(0), Slice::from(0..kord + 1))
-                    .lanes(Axis(0)),
-            )
-            .apply(|z, row| {
-                *z = (&row * &self.ida_cvals.slice(s![0..kord + 1])).sum();
-            });
+        let mut retval = Ok(());
+
+        // Loop over nwt = number of evaluations of ewt vector.
+        for _nwt in 0..2 {
+            // Loop over nh = number of h values.
+            for nh in 1..=mxnh {
+                // Call the IC nonlinear solver function.
+                retval = self.nls_ic();
+
+                // Cut h and loop on recoverable YaYdpInit failure; else break.
+                let err = match &retval {
+                    Ok(()) => break,
+                    Err(err) => err,
+                };
+                self.ida_ncfn += 1;
+                let slow = match err.downcast_ref::<IdaIcFailure>() {
+                    Some(IdaIcFailure::SlowConvrg {}) => true,
+                    Some(_) => false,
+                    None => break,
+                };
+                if nh == mxnh {
+                    break;
+                }
+                // If looping to try again, reset yy0 and yp0 if not converging.
+                if !slow {
+                    self.ida_yy.assign(&self.ida_phi.index_axis(Axis(0), 0));
+                    self.ida_yp.assign(&self.ida_phi.index_axis(Axis(0), 1));
+                }
+                hic *= F::Scalar::from(0.1).unwrap();
+                self.ida_cj = hic.recip();
+                self.ida_hh = hic;
+            }
 
-        //retval = N_VLinearCombination(kord, IDA_mem->ida_dvals, IDA_mem->ida_phi+1, ypret);
-        ndarray::Zip::from(ypret)
-            .and(
-                self.ida_phi
-                    .slice_axis(Axis(0), Slice::from(1..kord + 1))
-                    .lanes(Axis(0)),
-            )
-            .apply(|z, row| {
-                *z = (&row * &self.ida_dvals.slice(s![0..kord])).sum();
-            });
+            // Break on failure; else save yy0, yp0 in phi, reset ewt, and loop.
+            if retval.is_err() {
+                break;
+            }
+            self.ida_phi.index_axis_mut(Axis(0), 0).assign(&self.ida_yy);
+            self.ida_phi.index_axis_mut(Axis(0), 1).assign(&self.ida_yp);
+            if self.ewt_set().is_err() {
+                retval = Err(IdaError::BadErrorWeightVector {}.into());
+                break;
+            }
+        }
 
-        Ok(())
+        // Load the optional outputs.
+        if icopt == IcOption::YaYdpInit {
+            self.ida_hused = hic;
+        }
+
+        // On any failure, return the proper error.
+        retval.map_err(Self::ic_fail_flag)
     }
 
-    /// Returns the WRMS norm of vector x with weights w.
-    /// If mask = SUNTRUE, the weight vector w is masked by id, i.e.,
-    ///      nrm = N_VWrmsNormMask(x,w,id);
-    ///  Otherwise,
-    ///      nrm = N_VWrmsNorm(x,w);
+    /// IDAnlsIC
     ///
-    /// mask = SUNFALSE       when the call is made from the nonlinear solver.
-    /// mask = suppressalg otherwise.
-    pub fn wrms_norm(
-        &self,
-        x: &Array<F::Scalar, Ix1>,
-        w: &Array<F::Scalar, Ix1>,
-        mask: bool,
-    ) -> F::Scalar {
-        if mask {
-            //x.norm_wrms_masked(w, self.ida_id)
-            x.norm_wrms_masked(w, &self.ida_id)
-        } else {
-            x.norm_wrms(w)
+    /// This routine solves the nonlinear system F(t0, y0, y0') = 0 for the unknown components of
+    /// (yy0, yp0), which are held in the work vectors yy and yp. It calls `lsetup()` up to maxnj
+    /// times, and `newton_ic()` to perform the Newton iteration.
+    fn nls_ic(&mut self) -> Result<(), failure::Error> {
+        // Evaluate the residual, and save it.
+        self.res()
+            .map_err(|err| map_recoverable(err, IdaError::FirstResidualFail {}))?;
+        self.ida_savres.assign(&self.ida_delta);
+
+        let mut retval = Ok(());
+
+        // Loop over nj = number of linear solve Jacobian setups.
+        for _nj in 0..self.ida_maxnj {
+            self.ida_nsetups += 1;
+            self.lsetup()
+                .map_err(|err| map_recoverable(err, IdaIcFailure::FailRecov {}))?;
+
+            // Call the Newton iteration routine, and return if successful.
+            retval = self.newton_ic();
+            match &retval {
+                Ok(()) => return retval,
+                // If converging slowly, restore the residual and retry with a new Jacobian.
+                Err(err) => match err.downcast_ref::<IdaIcFailure>() {
+                    Some(IdaIcFailure::SlowConvrg {}) => {
+                        self.ida_delta.assign(&self.ida_savres);
+                    }
+                    _ => return retval,
+                },
+            }
         }
+
+        // No convergence after maxnj tries; return with the slow convergence failure.
+        retval
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::ida::Ida;
-    use crate::lorenz63::Lorenz63;
-    use ndarray::*;
-    use nearly_eq::*;
+    /// IDANewtonIC
+    ///
+    /// This routine performs the Newton iteration with linesearch for the IC calculation. It
+    /// assumes that delta contains the residual at (yy0, yp0) on entry.
+    fn newton_ic(&mut self) -> Result<(), failure::Error> {
+        // Call the linear solve function to get the Newton step, delta.
+        self.ida_mnewt = 0;
+        self.lsolve()
+            .map_err(|err| map_recoverable(err, IdaIcFailure::FailRecov {}))?;
+
+        // Compute the norm of the step, and return now if it is small.
+        let mut fnorm = self.wrms_norm(&self.ida_delta, &self.ida_ewt, false);
+        if self.ida_sysindex == 0 {
+            fnorm *= self.ida_tscale * self.ida_cj.abs();
+        }
+        if fnorm <= self.ida_epsNewt {
+            return Ok(());
+        }
+        let fnorm0 = fnorm;
+        let mut rate = F::Scalar::zero();
+
+        // Newton iteration loop
+        for mnewt in 0..self.ida_maxnit {
+            self.ida_nni += 1;
+            self.ida_mnewt = mnewt + 1;
+            let oldfnrm = fnorm;
+
+            // Call the linesearch function and return if it failed.
+            fnorm = self.line_srch(fnorm)?;
+
+            // Set the observed convergence rate and test for convergence.
+            rate = fnorm / oldfnrm;
+            if fnorm <= self.ida_epsNewt {
+                return Ok(());
+            }
 
-    #[test]
-    fn test_test_error1() {
-        let ck = 1.091414141414142;
-        let suppressalg = 0;
-        let kk = 5;
-        let ida_phi = array![
-            [
-                3.634565317158998e-05,
-                1.453878335134203e-10,
-                0.9999636542014404,
-            ],
-            [
-                -6.530333550677049e-06,
-                -2.612329458968465e-11,
-                6.530359673556191e-06,
-            ],
-            [
-                1.946442728026142e-06,
-                7.786687275994346e-12,
-                -1.946450515496441e-06,
-            ],
-            [
-                -8.097632208221231e-07,
-                -3.239585549038764e-12,
-                8.097664556005615e-07,
-            ],
-            [
-                3.718130977075839e-07,
-                1.487573462300438e-12,
-                -3.71814615793545e-07,
-            ],
-            [
-                -3.24421895454213e-07,
-                -1.297915245220823e-12,
-                3.244230624265827e-07,
-            ],
-        ];
-        let ida_ee = array![
-            2.65787533317467e-07,
-            1.063275845801634e-12,
-            -2.657884288386138e-07,
-        ];
-        let ida_ewt = array![73343005.56993243, 999999.985461217, 9901.346408259429];
-        let ida_sigma = array![
-            1.0,
-            0.6666666666666666,
-            0.6666666666666666,
-            0.888888888888889,
-            1.422222222222222,
-            2.585858585858586,
-        ];
-        let knew = 4;
-        let err_k = 29.10297975314245;
-        let err_km1 = 3.531162835377502;
-        let nflag = true;
+            // If not converged, copy the new step vector, and loop.
+            self.ida_delta.assign(&self.ida_delnew);
+        }
 
-        let f = Lorenz63::default();
-        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+        // Return either the slow convergence or the convergence failure.
+        if rate <= F::Scalar::from(1.5).unwrap() || fnorm < F::Scalar::from(0.1).unwrap() * fnorm0 {
+            Err(IdaIcFailure::SlowConvrg {})?
+        } else {
+            Err(IdaIcFailure::ConvFail {})?
+        }
+    }
 
-        // Set preconditions:
-        ida.ida_kk = kk;
-        ida.ida_suppressalg = suppressalg > 0;
-        ida.ida_phi.assign(&ida_phi);
-        ida.ida_ee.assign(&ida_ee);
-        ida.ida_ewt.assign(&ida_ewt);
-        ida.ida_sigma.assign(&ida_sigma);
+    /// IDALineSrch
+    ///
+    /// This routine does a linesearch on the Newton step delta, starting at the norm `fnorm` of
+    /// the current step. On success, yy0 and yp0 are updated, delnew contains the next Newton
+    /// step, and its norm is returned.
+    fn line_srch(&mut self, fnorm: F::Scalar) -> Result<F::Scalar, failure::Error> {
+        let mut delnorm = fnorm;
+        let f1norm = fnorm * fnorm * F::Scalar::from(0.5).unwrap();
+        let mut ratio = F::Scalar::one();
+
+        // If there are constraints, check and reduce step if necessary.
+        if self.ida_constraintsSet {
+            // Update y and check constraints.
+            self.newyyp(F::Scalar::one());
+            if let Some(mm) = self.constr_mask(&self.ida_ynew) {
+                // Not satisfied. Compute scaled step to satisfy constraints:
+                // ratio = 0.99 * min(yy0_i / delta_i) over the violated components.
+                ratio = F::Scalar::max_value();
+                Zip::from(&mm)
+                    .and(&self.ida_yy)
+                    .and(&self.ida_delta)
+                    .apply(|&mm, &yy0, &delta| {
+                        if mm && delta != F::Scalar::zero() {
+                            ratio = ratio.min(yy0 / delta);
+                        }
+                    });
+                ratio *= F::Scalar::from(0.99).unwrap();
+                delnorm *= ratio;
+                if delnorm <= self.ida_steptol {
+                    Err(IdaIcFailure::ConstrFailed {})?;
+                }
+                self.ida_delta *= ratio;
+            }
+        }
 
-        // Call the function under test
-        let (err_k_new, err_km1_new, nflag_new) = ida.test_error(ck);
+        let slpi = -F::Scalar::from(2.0).unwrap() * f1norm * ratio;
+        let minlam = self.ida_steptol / delnorm;
+        let mut lambda = F::Scalar::one();
+        let mut nbacks = 0;
 
-        assert_eq!(ida.ida_knew, knew);
-        assert_nearly_eq!(err_k_new, err_k);
-        assert_nearly_eq!(err_km1_new, err_km1);
-        assert_eq!(nflag_new, nflag);
-    }
+        // In the YInit case, set ypnew = yp0 (fixed) for the linesearch.
+        if self.ida_icopt == IcOption::YInit {
+            self.ida_ypnew.assign(&self.ida_yp);
+        }
 
-    #[test]
-    fn test_test_error2() {
-        //--- IDATestError Before:
-        let ck = 0.2025812352167927;
-        let suppressalg = 0;
-        let kk = 4;
-        let ida_phi = array![
-            [
-                3.051237735052657e-05,
-                1.220531905117091e-10,
-                0.9999694875005963,
-            ],
-            [
-                -2.513114849098281e-06,
-                -1.005308974226734e-11,
-                2.513124902721765e-06,
-            ],
-            [
-                4.500284453718991e-07,
-                1.800291970640913e-12,
-                -4.500302448499092e-07,
-            ],
-            [
-                -1.366709389821433e-07,
-                -5.467603693902342e-13,
-                1.366714866794709e-07,
-            ],
-            [
-                7.278821769100639e-08,
-                2.911981566628798e-13,
-                -7.278850816613011e-08,
-            ],
-            [
-                -8.304741244343501e-09,
-                -3.324587131187576e-14,
-                8.304772990651073e-09,
-            ],
-        ];
-        let ida_ee = array![
-            -2.981302228744271e-08,
-            -1.192712676406388e-13,
-            2.981313872620108e-08,
-        ];
-        let ida_ewt = array![76621085.31777237, 999999.9877946811, 9901.289220872719,];
-        let ida_sigma = array![
-            1.0,
-            0.5,
-            0.3214285714285715,
-            0.2396514200444849,
-            0.1941955227762807,
-            2.585858585858586,
-        ];
-        //--- IDATestError After:
-        let knew = 4;
-        let err_k = 0.2561137489433976;
-        let err_km1 = 0.455601916633899;
-        let nflag = false;
+        // Loop on linesearch variable lambda.
+        let fnormp = loop {
+            if nbacks == self.ida_maxbacks {
+                Err(IdaIcFailure::LinesrchFailed {})?;
+            }
+            // Get new (y,y') = (ynew,ypnew) and norm of new function value.
+            self.newyyp(lambda);
+            let fnormp = self.fnorm_ic()?;
 
-        let f = Lorenz63::default();
-        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+            // If lsoff option is on, break out.
+            if self.ida_lsoff {
+                break fnormp;
+            }
 
-        // Set preconditions:
-        ida.ida_kk = kk;
-        ida.ida_suppressalg = suppressalg > 0;
-        ida.ida_phi.assign(&ida_phi);
-        ida.ida_ee.assign(&ida_ee);
-        ida.ida_ewt.assign(&ida_ewt);
-        ida.ida_sigma.assign(&ida_sigma);
+            // Do alpha-condition test.
+            let f1normp = fnormp * fnormp * F::Scalar::from(0.5).unwrap();
+            if f1normp <= f1norm + F::Scalar::from(ALPHALS).unwrap() * slpi * lambda {
+                break fnormp;
+            }
+            if lambda < minlam {
+                Err(IdaIcFailure::LinesrchFailed {})?;
+            }
+            lambda /= F::Scalar::from(2.0).unwrap();
+            self.ida_nbacktr += 1;
+            nbacks += 1;
+        };
 
-        // Call the function under test
-        let (err_k_new, err_km1_new, nflag_new) = ida.test_error(ck);
+        // Update yy0 and yp0, then return.
+        self.ida_yy.assign(&self.ida_ynew);
+        if self.ida_icopt == IcOption::YaYdpInit {
+            self.ida_yp.assign(&self.ida_ypnew);
+        }
 
-        assert_eq!(ida.ida_knew, knew);
-        assert_nearly_eq!(err_k_new, err_k);
-        assert_nearly_eq!(err_km1_new, err_km1);
-        assert_eq!(nflag_new, nflag);
+        Ok(fnormp)
     }
 
-    #[test]
-    fn test_restore1() {
-        let saved_t = 717553.4942644858;
-        #[rustfmt::skip]
-        let phi_before = array![[0.00280975951420059, 1.125972706132338e-08, 0.9971902292261264], [-0.0001926545663078034, -7.857235149861102e-10,0.0001926553520857565], [2.945636347837807e-05, 1.066748079583829e-10,-2.945647009050819e-05], [-5.518529121250618e-06, -4.529997656241677e-11,5.518574540464112e-06], [2.822681468681011e-06, -4.507342025411469e-11,-2.822636100488049e-06], [-8.124641701620927e-08,-8.669560754165103e-11,8.133355922669991e-08], ];
-        #[rustfmt::skip]
-        let psi_before = array![ 47467.05706123715, 94934.1141224743, 142401.1711837114, 166134.69971433, 189868.2282449486, 107947.0192373629 ];
-        let cvals_before = array![1., 1., 1., 1., 1., 0.];
-        let beta_before = array![1., 1., 1., 1.2, 1.4, 1.];
-
-        #[rustfmt::skip]
-        let phi_after = array![[0.00280975951420059,1.125972706132338e-08, 0.9971902292261264,], [-0.0001926545663078034,-7.857235149861102e-10,0.0001926553520857565,], [2.945636347837807e-05,1.066748079583829e-10,-2.945647009050819e-05,], [-4.598774267708849e-06,-3.774998046868064e-11,4.598812117053426e-06,], [2.016201049057865e-06,-3.219530018151049e-11,-2.016168643205749e-06,], [-8.124641701620927e-08,-8.669560754165103e-11,8.133355922669991e-08,], ];
-        #[rustfmt::skip]
-        let psi_after = array![ 47467.05706123715, 94934.11412247429, 118667.6426530929, 142401.1711837114, 189868.2282449486, 107947.0192373629 ];
-        let cvals_after = array![0.8333333333333334, 0.7142857142857142, 1., 1., 1., 0.];
-        let beta_after = array![1., 1., 1., 1.2, 1.4, 1.];
-
-        let f = Lorenz63::default();
-        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+    /// IDAfnorm
+    ///
+    /// This routine evaluates the residual at (ynew, ypnew), saves it in savres, and computes the
+    /// next Newton step into delnew. It returns the norm of the new step.
+    ///
+    /// A recoverable residual or linear solver error is returned as `IdaIcFailure::FailRecov`, so that
+    /// `calc_ic()` retries with a smaller h.
+    fn fnorm_ic(&mut self) -> Result<F::Scalar, failure::Error> {
+        // Evaluate on the trial values by swapping them in for (yy, yp, delta).
+        std::mem::swap(&mut self.ida_yy, &mut self.ida_ynew);
+        std::mem::swap(&mut self.ida_yp, &mut self.ida_ypnew);
+        std::mem::swap(&mut self.ida_delta, &mut self.ida_delnew);
+
+        let mut retval = self.res();
+        if retval.is_ok() {
+            self.ida_savres.assign(&self.ida_delta);
+            retval = self.lsolve();
+        }
 
-        // Set preconditions:
-        ida.ida_tn = 765020.5513257229;
-        ida.ida_ns = 3;
-        ida.ida_kk = 4;
-        ida.ida_hh = 47467.05706123715;
-        ida.ida_phi.assign(&phi_before);
-        ida.ida_psi.assign(&psi_before);
-        ida.ida_cvals.assign(&cvals_before);
-        ida.ida_beta.assign(&beta_before);
+        std::mem::swap(&mut self.ida_yy, &mut self.ida_ynew);
+        std::mem::swap(&mut self.ida_yp, &mut self.ida_ypnew);
+        std::mem::swap(&mut self.ida_delta, &mut self.ida_delnew);
+        retval.map_err(|err| map_recoverable(err, IdaIcFailure::FailRecov {}))?;
 
-        // Call the function under test
-        ida.restore(saved_t);
+        // Compute the WRMS-norm, rescaled if index = 0.
+        let mut fnorm = self.wrms_norm(&self.ida_delnew, &self.ida_ewt, false);
+        if self.ida_sysindex == 0 {
+            fnorm *= self.ida_tscale * self.ida_cj.abs();
+        }
 
-        assert_nearly_eq!(ida.ida_tn, saved_t);
-        assert_eq!(ida.ida_ns, 3);
-        assert_eq!(ida.ida_kk, 4);
-        assert_nearly_eq!(ida.ida_cvals, cvals_after, 1e-6);
-        assert_nearly_eq!(ida.ida_beta, beta_after, 1e-6);
-        assert_nearly_eq!(ida.ida_psi, psi_after, 1e-6);
-        assert_nearly_eq!(ida.ida_phi, phi_after, 1e-6);
+        Ok(fnorm)
     }
 
-    #[test]
-    fn test_restore2() {
-        let saved_t = 3623118336.24244;
-        #[rustfmt::skip]
-        let phi_before = array![ [5.716499633245077e-07,2.286601144610028e-12, 0.9999994283477499,], [-1.555846772013456e-07,-6.223394599091205e-13,1.555852991517385e-07,], [7.018252655941472e-08,2.807306512268244e-13,-7.01828076998538e-08,], [-4.56160628763917e-08,-1.824647796129851e-13,4.561624269904529e-08,], [5.593228676143622e-08,2.237297583983664e-13,-5.593253344183256e-08,], [-2.242367216194777e-10,-8.970915966733762e-16,2.242247401239887e-10,], ];
-        #[rustfmt::skip]
-        let psi_before = array![  857870592.1885694,   1286805888.282854,   1715741184.377139,   1930208832.424281,   2144676480.471424,    26020582.4876316];
-        #[rustfmt::skip]
-        let cvals_before = array![1., 1., 1., 1., 1., 1.];
-        #[rustfmt::skip]
-        let beta_before = array![1., 2., 3., 4.8, 7.199999999999999, 10.28571428571428];
-        //--- IDARestore After: saved_t=   3623118336.24244 tn=   3623118336.24244 ns=1 kk=4
-        #[rustfmt::skip]
-        let phi_after = array![ [5.716499633245077e-07,2.286601144610028e-12, 0.9999994283477499,], [-7.779233860067279e-08,-3.111697299545603e-13,7.779264957586927e-08,], [2.339417551980491e-08,9.35768837422748e-14,-2.33942692332846e-08,], [-9.503346432581604e-09,-3.801349575270522e-14,9.503383895634436e-09,], [7.768373161310588e-09,3.107357755532867e-14,-7.768407422476745e-09,], [-2.242367216194777e-10,-8.970915966733762e-16,2.242247401239887e-10,], ];
-        #[rustfmt::skip]
-        let psi_after= array![  428935296.0942847,   857870592.1885694,   1072338240.235712,   1286805888.282854,   2144676480.471424,    26020582.4876316];
-        #[rustfmt::skip]
-        let cvals_after = array![ 0.5, 0.3333333333333333, 0.2083333333333333, 0.1388888888888889, 1., 1. ];
-        #[rustfmt::skip]
-        let beta_after = array![1., 2., 3., 4.8, 7.199999999999999, 10.28571428571428];
+    /// IDANewyyp
+    ///
+    /// This routine computes the trial values (ynew, ypnew) for the step length lambda:
+    /// * YaYdpInit: ynew = yy0 - lambda*delta where id_i = 0, and ypnew = yp0 - cj*lambda*delta
+    ///   where id_i = 1.
+    /// * YInit: ynew = yy0 - lambda*delta (ypnew = yp0 preset).
+    fn newyyp(&mut self, lambda: F::Scalar) {
+        match (self.ida_icopt, &self.ida_id) {
+            (IcOption::YaYdpInit, Some(id)) => {
+                let cj = self.ida_cj;
+                Zip::from(&mut self.ida_ynew)
+                    .and(&mut self.ida_ypnew)
+                    .and(&self.ida_yy)
+                    .and(&self.ida_yp)
+                    .and(&self.ida_delta)
+                    .and(id)
+                    .apply(|ynew, ypnew, &yy0, &yp0, &delta, &id| {
+                        if id {
+                            *ynew = yy0;
+                            *ypnew = yp0 - cj * lambda * delta;
+                        } else {
+                            *ynew = yy0 - lambda * delta;
+                            *ypnew = yp0;
+                        }
+                    });
+            }
+            _ => {
+                Zip::from(&mut self.ida_ynew)
+                    .and(&self.ida_yy)
+                    .and(&self.ida_delta)
+                    .apply(|ynew, &yy0, &delta| *ynew = yy0 - lambda * delta);
+            }
+        }
+    }
 
-        let f = Lorenz63::default();
-        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+    /// IDAICFailFlag
+    ///
+    /// This routine maps a failure of the IC calculation to the error returned by `calc_ic()`.
+    fn ic_fail_flag(err: failure::Error) -> failure::Error {
+        match err.downcast::<IdaIcFailure>() {
+            Ok(IdaIcFailure::FailRecov {}) => IdaError::NoRecovery {}.into(),
+            Ok(IdaIcFailure::ConstrFailed {}) => IdaError::ConstraintFail {}.into(),
+            Ok(IdaIcFailure::LinesrchFailed {}) => IdaError::LinesearchFail {}.into(),
+            Ok(IdaIcFailure::ConvFail {}) | Ok(IdaIcFailure::SlowConvrg {}) => {
+                IdaError::ConvergenceFail {}.into()
+            }
+            Err(err) => err,
+        }
+    }
 
-        // Set preconditions:
-        ida.ida_tn = 4480988928.431009;
-        ida.ida_ns = 1;
-        ida.ida_kk = 4;
-        ida.ida_hh = 857870592.1885694;
-        ida.ida_phi.assign(&phi_before);
-        ida.ida_psi.assign(&psi_before);
-        ida.ida_cvals.assign(&cvals_before);
+    /// This routine performs one internal IDA step, from tn to tn + hh. It calls other routines to do all the work.
+    ///
+    /// It solves a system of differential/algebraic equations of the form F(t,y,y') = 0, for one step.
+    /// In IDA, tt is used for t, yy is used for y, and yp is used for y'. The function F is supplied
+    /// as 'res' by the user.
+    ///
+    /// The methods used are modified divided difference, fixed leading coefficient forms of backward
+    /// differentiation formulas. The code adjusts the stepsize and order to control the local error
+    /// per step.
+    ///
+    /// The main operations done here are as follows:
+    /// * initialize various quantities;
+    /// * setting of multistep method coefficients;
+    /// * solution of the nonlinear system for yy at t = tn + hh;
+    /// * deciding on order reduction and testing the local error;
+    /// * attempting to recover from failure in nonlinear solver or error test;
+    /// * resetting stepsize and order for the next step.
+    /// * updating phi and other state data if successful;
+    ///
+    /// On a failure in the nonlinear system solution or error test, the step may be reattempted,
+    /// depending on the nature of the failure.
+    ///
+    /// Variables or arrays (all in the IDAMem structure) used in IDAStep are:
+    ///
+    /// tt -- Independent variable.
+    /// yy -- Solution vector at tt.
+    /// yp -- Derivative of solution vector after successful stelp.
+    /// res -- User-supplied function to evaluate the residual. See the description given in file ida.h
+    /// lsetup -- Routine to prepare for the linear solver call. It may either save or recalculate
+    ///   quantities used by lsolve. (Optional)
+    /// lsolve -- Routine to solve a linear system. A prior call to lsetup may be required.
+    /// hh  -- Appropriate step size for next step.
+    /// ewt -- Vector of weights used in all convergence tests.
+    /// phi -- Array of divided differences used by IDAStep. This array is composed of (maxord+1)
+    ///   nvectors (each of size Neq). (maxord+1) is the maximum order for the problem, maxord, plus 1.
+    ///
+    /// Return values are:
+    ///       IDA_SUCCESS   IDA_RES_FAIL      LSETUP_ERROR_NONRECVR
+    ///                     IDA_LSOLVE_FAIL   IDA_ERR_FAIL
+    ///                     IDA_CONSTR_FAIL   IDA_CONV_FAIL
+    ///                     IDA_REP_RES_ERR
+    fn step(&mut self) -> Result<(), failure::Error> {
+        let saved_t = self.ida_tn;
+        let mut ck;
+
+        // local counters for convergence and error test failures
+        let mut ncf = 0;
+        let mut nef = 0;
+
+        // First step since the start of the integration (or a restart): kused is only set once a
+        // step has been completed
+        if self.ida_kused == 0 {
+            self.ida_kk = 1;
+            self.ida_kused = 0;
+            self.ida_hused = F::Scalar::zero();
+            self.ida_psi[0] = self.ida_hh;
+            self.ida_cj = F::Scalar::one() / self.ida_hh;
+            self.ida_phase = 0;
+            self.ida_ns = 0;
+        }
+
+        let mut err_k = F::Scalar::zero();
+        let mut err_km1 = F::Scalar::zero();
+
+        // Looping point for attempts to take a step
+        loop {
+            //-----------------------
+            // Set method coefficients
+            //-----------------------
+
+            ck = self.set_coeffs();
+
+            //----------------------------------------------------
+            // If tn is past tstop (by roundoff), reset it to tstop.
+            //-----------------------------------------------------
+
+            self.ida_tn += self.ida_hh;
+            if self.ida_tstopset && (self.ida_tn - self.ida_tstop) * self.ida_hh > F::Scalar::zero()
+            {
+                self.ida_tn = self.ida_tstop;
+            }
+
+            //-----------------------
+            // Advance state variables
+            //-----------------------
+
+            // Compute predicted values for yy and yp
+            self.predict();
+
+            // Nonlinear system solution
+            let mut nflag = self.nonlinear_solve();
+
+            // If NLS was successful, perform error test
+            if nflag.is_ok() {
+                let (k, km1, etest_failed) = self.test_error(ck);
+                err_k = k;
+                err_km1 = km1;
+                if etest_failed {
+                    nflag = Err(IdaRecoverable::ErrorTest {}.into());
+                }
+            }
+
+            // Test for convergence or error test failures
+            if let Err(nflag) = nflag {
+                // restore and decide what to do
+                self.restore(saved_t);
+
+                // exit on nonrecoverable failure
+                self.handle_n_flag(nflag, err_k, err_km1, &mut ncf, &mut nef)?;
+
+                // recoverable error; predict again
+                if self.ida_kused == 0 {
+                    self.reset();
+                }
+                continue;
+            }
+
+            break;
+        }
+
+        // Nonlinear system solve and error test were both successful; update data, and consider
+        // change of step and/or order
+        self.complete_step(err_k, err_km1);
+
+        // Rescale ee vector to be the estimated local error
+        // Notes:
+        //   (1) altering the value of ee is permissible since it will be overwritten by
+        //       solve()->step()->nonlinear_solve() before it is needed again
+        //   (2) the value of ee is only valid if handle_n_flag() returns either PREDICT_AGAIN or
+        //       success
+        self.ida_ee *= ck;
+
+        Ok(())
+    }
+
+    /// This routine computes the coefficients relevant to the current step.
+    ///
+    /// The counter ns counts the number of consecutive steps taken at constant stepsize h and order
+    /// k, up to a maximum of k + 2.
+    /// Then the first ns components of beta will be one, and on a step with ns = k + 2, the
+    /// coefficients alpha, etc. need not be reset here.
+    /// Also, complete_step() prohibits an order increase until ns = k + 2.
+    ///
+    /// Returns the 'variable stepsize error coefficient ck'
+    pub fn set_coeffs(&mut self) -> F::Scalar {
+        // Set coefficients for the current stepsize h
+        if self.ida_hh != self.ida_hused || self.ida_kk != self.ida_kused {
+            self.ida_ns = 0;
+        }
+        self.ida_ns = std::cmp::min(self.ida_ns + 1, self.ida_kused + 2);
+        if self.ida_kk + 1 >= self.ida_ns {
+            self.ida_beta[0] = F::Scalar::one();
+            self.ida_alpha[0] = F::Scalar::one();
+            let mut temp1 = self.ida_hh;
+            self.ida_gamma[0] = F::Scalar::zero();
+            self.ida_sigma[0] = F::Scalar::one();
+            for i in 1..=self.ida_kk {
+                let temp2 = self.ida_psi[i - 1];
+                self.ida_psi[i - 1] = temp1;
+                self.ida_beta[i] = self.ida_beta[i - 1] * (self.ida_psi[i - 1] / temp2);
+                temp1 = temp2 + self.ida_hh;
+                self.ida_alpha[i] = self.ida_hh / temp1;
+                self.ida_sigma[i] =
+                    self.ida_sigma[i - 1] * self.ida_alpha[i] * F::Scalar::from(i).unwrap();
+                self.ida_gamma[i] = self.ida_gamma[i - 1] + self.ida_alpha[i - 1] / self.ida_hh;
+            }
+            self.ida_psi[self.ida_kk] = temp1;
+        }
+        // compute alphas, alpha0
+        let mut alphas = F::Scalar::zero();
+        let mut alpha0 = F::Scalar::zero();
+        for i in 0..self.ida_kk {
+            alphas -= F::Scalar::one() / F::Scalar::from(i + 1).unwrap();
+            alpha0 -= self.ida_alpha[i];
+        }
+
+        // compute leading coefficient cj
+        self.ida_cjlast = self.ida_cj;
+        self.ida_cj = -alphas / self.ida_hh;
+
+        // compute variable stepsize error coefficient ck
+        let mut ck = (self.ida_alpha[self.ida_kk] + alphas - alpha0).abs();
+        ck = ck.max(self.ida_alpha[self.ida_kk]);
+
+        // change phi to phi-star
+        // Scale i=self.ida_ns to i<=self.ida_kk
+        if self.ida_ns <= self.ida_kk {
+            // phi[ns .. (kk + 1)] *= beta[ns .. (kk + 1)]
+            let beta = self.ida_beta.slice(s![self.ida_ns..=self.ida_kk]);
+            let beta = beta.broadcast((1, beta.len())).unwrap().reversed_axes();
+
+            let mut ida_phi = self
+                .ida_phi
+                .slice_axis_mut(Axis(0), Slice::from(self.ida_ns..=self.ida_kk));
+            ida_phi *= &beta;
+        }
+
+        ck
+    }
+
+    /// IDANls
+    /// This routine attempts to solve the nonlinear system using the linear solver specified.
+    ///
+    /// Starting from the predicted values `yypredict` and `yppredict`, a modified Newton iteration
+    /// is performed on the residual F(t, y, y') with the iteration matrix `dF/dy + cj*dF/dy'`. The
+    /// accumulated corrections to y are stored in `ee`, and on success `yy` and `yp` hold the
+    /// corrected values at tn.
+    ///
+    /// The iteration matrix from the last call to lsetup is reused as long as cj has not changed
+    /// by more than a factor of about `1 + 2*XRATE` since then. If the iteration fails with an
+    /// old iteration matrix, it is retried once with a new one.
+    ///
+    /// If constraints are set, the corrected y is checked against them by
+    /// `check_constraints()`.
+    ///
+    /// Returns `IdaRecoverable` errors for failures that may be handled by reducing the step size,
+    /// and `IdaError` for unrecoverable ones.
+    pub fn nonlinear_solve(&mut self) -> Result<(), failure::Error> {
+        let mut call_setup = false;
+
+        // Initialize if the first time called since the start of the integration
+        if self.ida_kused == 0 {
+            self.ida_cjold = self.ida_cj;
+            self.ida_ss = F::Scalar::from(20.0).unwrap();
+            call_setup = true;
+        }
+
+        // Decide if lsetup is to be called
+        self.ida_cjratio = self.ida_cj / self.ida_cjold;
+        let temp1 = F::Scalar::from((1.0 - XRATE) / (1.0 + XRATE)).unwrap();
+        let temp2 = temp1.recip();
+        if self.ida_cjratio < temp1 || self.ida_cjratio > temp2 {
+            call_setup = true;
+        }
+        if self.ida_cj != self.ida_cjlast {
+            self.ida_ss = F::Scalar::from(100.0).unwrap();
+        }
+
+        // Begin the main loop. This loop is traversed at most twice. The second pass only occurs
+        // when the first pass had a recoverable failure with old Jacobian data.
+        loop {
+            // Initial guess for the correction to the predictor
+            self.ida_ee.fill(F::Scalar::zero());
+            self.ida_yy.assign(&self.ida_yypredict);
+            self.ida_yp.assign(&self.ida_yppredict);
+
+            // Compute the residual at the predicted values
+            self.res()?;
+
+            // If indicated, evaluate and factor the iteration matrix, and reset the convergence
+            // rate estimate.
+            if call_setup {
+                self.ida_nsetups += 1;
+                self.lsetup()?;
+                self.ida_ss = F::Scalar::from(20.0).unwrap();
+            }
+
+            // Retry the current step on recoverable failure with old Jacobian data
+            match self.newton_iter() {
+                Err(err) if !call_setup && err.downcast_ref::<IdaRecoverable>().is_some() => {
+                    call_setup = true;
+                }
+                retval => {
+                    retval?;
+                    break;
+                }
+            }
+        }
+
+        // If otherwise successful, check and enforce inequality constraints
+        if self.ida_constraintsSet {
+            self.check_constraints()?;
+        }
+        Ok(())
+    }
+
+    /// Checks the corrected y against the constraints, as in IDANls.
+    ///
+    /// If any constraint is violated, the correction `v = mm * (y - 0.1 * a * c / ewt)` is
+    /// computed, where mm is the mask of the violated components, c the constraint (±1 or ±2 as
+    /// in IDA) and `a = |c| > 1.5`. This moves y onto the bound of a ≥ 0 or ≤ 0 constraint, and
+    /// just inside that of a > 0 or < 0 constraint. If `||v|| <= epsNewt`, v is subtracted from
+    /// ee (and from y) and the step is accepted.
+    ///
+    /// Otherwise, returns `IdaRecoverable::Constraint` with the step size ratio rr set so that the
+    /// retried step approximately reaches the constraint boundary, assuming that y varies
+    /// linearly from phi[0]: `rr = 0.9 * min(phi0_i / (phi0_i - y_i))` over the violated
+    /// components, but at least 0.1.
+    fn check_constraints(&mut self) -> Result<(), failure::Error> {
+        let mm = match self.constr_mask(&self.ida_yy) {
+            Some(mm) => mm,
+            None => return Ok(()),
+        };
+
+        // Compute the correction to satisfy the constraints
+        let pt1 = F::Scalar::from(0.1).unwrap();
+        let mut vv = Array::zeros(self.ida_yy.raw_dim());
+        Zip::from(&mut vv)
+            .and(&mm)
+            .and(&self.ida_constraints)
+            .and(&self.ida_yy)
+            .and(&self.ida_ewt)
+            .apply(|v, &mm, &c, &yy, &ewt| {
+                if mm {
+                    let ac = match c {
+                        Constraint::Positive => F::Scalar::from(2.0).unwrap(),
+                        Constraint::Negative => F::Scalar::from(-2.0).unwrap(),
+                        _ => F::Scalar::zero(),
+                    };
+                    *v = yy - pt1 * ac / ewt;
+                }
+            });
+
+        // If the correction is small in norm, correct and accept this step. yy and yp are
+        // corrected along with ee, as by a Newton update, since they are returned by `solve()`.
+        if self.wrms_norm(&vv, &self.ida_ewt, false) <= self.ida_epsNewt {
+            self.ida_ee -= &vv;
+            self.ida_yy -= &vv;
+            self.ida_yp.scaled_add(-self.ida_cj, &vv);
+            return Ok(());
+        }
+
+        // The correction is too large, reduce h by computing rr = h'/h
+        let mut ratio = F::Scalar::max_value();
+        Zip::from(&mm)
+            .and(&self.ida_phi.index_axis(Axis(0), 0))
+            .and(&self.ida_yy)
+            .apply(|&mm, &phi0, &yy| {
+                if mm && phi0 != yy {
+                    ratio = ratio.min(phi0 / (phi0 - yy));
+                }
+            });
+        self.ida_rr = (F::Scalar::from(0.9).unwrap() * ratio).max(pt1);
+
+        Err(IdaRecoverable::Constraint {})?
+    }
+
+    /// IDANewtonIter
+    /// This routine performs the Newton iteration. It assumes that delta contains the initial
+    /// residual vector on entry.
+    ///
+    /// The iteration is stopped when the estimated error `ss * ||delta||` drops below `epsNewt`,
+    /// and declared divergent when the estimated convergence rate exceeds `RATEMAX`, or when
+    /// `maxcor` iterations have been performed.
+    fn newton_iter(&mut self) -> Result<(), failure::Error> {
+        let mut mnewt = 0;
+
+        loop {
+            self.ida_nni += 1;
+
+            // Save a copy of the residual vector in savres.
+            self.ida_savres.assign(&self.ida_delta);
+
+            // Call the lsolve function to get correction vector delta.
+            self.ida_mnewt = mnewt;
+            self.lsolve()?;
+
+            // Apply delta to yy, yp, and ee, and get norm(delta).
+            self.ida_yy -= &self.ida_delta;
+            self.ida_ee -= &self.ida_delta;
+            self.ida_yp.scaled_add(-self.ida_cj, &self.ida_delta);
+            let delnrm = self.wrms_norm(&self.ida_delta, &self.ida_ewt, false);
+
+            // Test for convergence, first directly, then with rate estimate.
+            if mnewt == 0 {
+                self.ida_oldnrm = delnrm;
+                if delnrm <= self.ida_toldel {
+                    return Ok(());
+                }
+            } else {
+                let rate = (delnrm / self.ida_oldnrm)
+                    .powf(F::Scalar::one() / F::Scalar::from(mnewt).unwrap());
+                if rate > F::Scalar::from(RATEMAX).unwrap() {
+                    Err(IdaRecoverable::NonlinearConvergence {})?;
+                }
+                self.ida_ss = rate / (F::Scalar::one() - rate);
+            }
+
+            if self.ida_ss * delnrm <= self.ida_epsNewt {
+                return Ok(());
+            }
+
+            // Not yet converged.  Increment mnewt and test for max allowed.
+            mnewt += 1;
+            if mnewt >= self.ida_maxcor {
+                Err(IdaRecoverable::NonlinearConvergence {})?;
+            }
+
+            // Call res for new residual.
+            self.res()?;
+        }
+    }
+
+    /// Evaluates the residual F(tn, yy, yp) into delta.
+    ///
+    /// A recoverable error from the model is returned as `IdaRecoverable::Residual`, and a fatal
+    /// one as `IdaError::ResidualFail`.
+    fn res(&mut self) -> Result<(), failure::Error> {
+        let retval = self
+            .f
+            .residual(self.ida_tn, &self.ida_yy, &self.ida_yp, &mut self.ida_delta);
+        self.ida_nre += 1;
+        match retval {
+            Ok(()) => Ok(()),
+            Err(ModelError::Recoverable {}) => Err(IdaRecoverable::Residual {})?,
+            Err(ModelError::Fatal {}) => Err(IdaError::ResidualFail {})?,
+        }
+    }
+
+    /// Calls the linear solver setup routine with the iteration matrix at (tn, yy, yp), where
+    /// delta holds the current residual. Residual calls made by the setup, e.g. for a difference
+    /// quotient Jacobian, are counted in nre.
+    ///
+    /// The current cj is saved in cjold, for the decision to reuse the iteration matrix in later
+    /// steps.
+    fn lsetup(&mut self) -> Result<(), failure::Error> {
+        let ctx = LinearContext {
+            tt: self.ida_tn,
+            cj: self.ida_cj,
+            hh: self.ida_hh,
+            yy: self.ida_yy.view(),
+            yp: self.ida_yp.view(),
+            rr: self.ida_delta.view(),
+            ewt: self.ida_ewt.view(),
+            eps_newt: self.ida_epsNewt,
+            constraints: if self.ida_constraintsSet {
+                Some(self.ida_constraints.view())
+            } else {
+                None
+            },
+        };
+        let retval = self.ida_ls.setup(&mut self.f, &ctx);
+        self.ida_nre += self.ida_ls.num_setup_res_evals() as u64;
+        self.ida_npe += self.ida_ls.num_prec_setups() as u64;
+        self.ida_cjold = self.ida_cj;
+        self.ida_cjratio = F::Scalar::one();
+
+        match retval {
+            Ok(()) => Ok(()),
+            Err(LinearSolverError::Recoverable {}) | Err(LinearSolverError::ResidualReduced {}) => {
+                Err(IdaRecoverable::LinearSetup {})?
+            }
+            Err(LinearSolverError::Fatal {}) => Err(IdaError::LinearSetupFail {})?,
+        }
+    }
+
+    /// Calls the linear solver solve routine to solve `J x = delta` at (tn, yy, yp), where savres
+    /// holds the current residual, overwriting delta with the solution x.
+    ///
+    /// If the solver uses an iteration matrix from a previous value cjold of cj, the solution is
+    /// scaled by `2/(1 + cj/cjold)` to account for the change in cj.
+    fn lsolve(&mut self) -> Result<(), failure::Error> {
+        let ctx = LinearContext {
+            tt: self.ida_tn,
+            cj: self.ida_cj,
+            hh: self.ida_hh,
+            yy: self.ida_yy.view(),
+            yp: self.ida_yp.view(),
+            rr: self.ida_savres.view(),
+            ewt: self.ida_ewt.view(),
+            eps_newt: self.ida_epsNewt,
+            constraints: if self.ida_constraintsSet {
+                Some(self.ida_constraints.view())
+            } else {
+                None
+            },
+        };
+        let retval = self
+            .ida_ls
+            .solve(&mut self.f, self.ida_delta.view_mut(), &ctx);
+
+        self.ida_nli += self.ida_ls.num_iters() as u64;
+        self.ida_nps += self.ida_ls.num_prec_solves() as u64;
+        if retval.is_err() {
+            self.ida_ncfl += 1;
+        }
+
+        if self.ida_ls.scale_correction() && self.ida_cjratio != F::Scalar::one() {
+            let scale = F::Scalar::from(2.0).unwrap() / (F::Scalar::one() + self.ida_cjratio);
+            self.ida_delta *= scale;
+        }
+
+        match retval {
+            Ok(()) => Ok(()),
+            // Allow a reduced residual, but not a solution, on the first Newton iteration
+            Err(LinearSolverError::ResidualReduced {}) if self.ida_mnewt == 0 => Ok(()),
+            Err(LinearSolverError::Recoverable {}) | Err(LinearSolverError::ResidualReduced {}) => {
+                Err(IdaRecoverable::LinearSolve {})?
+            }
+            Err(LinearSolverError::Fatal {}) => Err(IdaError::LinearSolveFail {})?,
+        }
+    }
+
+    /// IDAPredict
+    /// This routine predicts the new values for vectors yy and yp.
+    pub fn predict(&mut self) {
+        // yypredict = sum_{j=0}^{kk} phi[j]
+        // yppredict = sum_{j=1}^{kk} gamma[j] * phi[j]
+        let phi = self
+            .ida_phi
+            .slice_axis(Axis(0), Slice::from(0..=self.ida_kk));
+        let gamma = self.ida_gamma.slice(s![1..=self.ida_kk]);
+
+        ndarray::Zip::from(&mut self.ida_yypredict)
+            .and(&mut self.ida_yppredict)
+            .and(phi.lanes(Axis(0)))
+            .apply(|yy, yp, row| {
+                *yy = row.sum();
+                *yp = (&row.slice(s![1..]) * &gamma).sum();
+            });
+    }
+
+    /// IDATestError
+    ///
+    /// This routine estimates errors at orders k, k-1, k-2, decides whether or not to suggest an order
+    /// decrease, and performs the local error test.
+    ///
+    /// Returns a tuple of (err_k, err_km1, nflag)
+    pub fn test_error(
+        &mut self,
+        ck: F::Scalar,
+    ) -> (
+        F::Scalar, // err_k
+        F::Scalar, // err_km1
+        bool,      // nflag
+    ) {
+        //realtype enorm_k, enorm_km1, enorm_km2;   /* error norms */
+        //realtype terr_k, terr_km1, terr_km2;      /* local truncation error norms */
+        // Compute error for order k.
+        let enorm_k = self.wrms_norm(&self.ida_ee, &self.ida_ewt, self.ida_suppressalg);
+        let err_k = self.ida_sigma[self.ida_kk] * enorm_k;
+        let terr_k = err_k * F::Scalar::from(self.ida_kk + 1).unwrap();
+
+        let mut err_km1 = F::Scalar::zero(); // estimated error at k-1
+
+        self.ida_knew = self.ida_kk;
+
+        if self.ida_kk > 1 {
+            // Compute error at order k-1
+            self.ida_delta = &self.ida_phi.index_axis(Axis(0), self.ida_kk) + &self.ida_ee;
+            let enorm_km1 = self.wrms_norm(&self.ida_delta, &self.ida_ewt, self.ida_suppressalg);
+            err_km1 = self.ida_sigma[self.ida_kk - 1] * enorm_km1;
+            let terr_km1 = err_km1 * F::Scalar::from(self.ida_kk).unwrap();
+
+            if self.ida_kk > 2 {
+                // Compute error at order k-2
+                // ida_delta = ida_phi[ida_kk - 1] + ida_delta
+                self.ida_delta += &self.ida_phi.index_axis(Axis(0), self.ida_kk - 1);
+
+                let enorm_km2 =
+                    self.wrms_norm(&self.ida_delta, &self.ida_ewt, self.ida_suppressalg);
+                let err_km2 = self.ida_sigma[self.ida_kk - 2] * enorm_km2;
+                let terr_km2 = err_km2 * F::Scalar::from(self.ida_kk - 1).unwrap();
+
+                // Decrease order if errors are reduced
+                if terr_km1.max(terr_km2) <= terr_k {
+                    self.ida_knew = self.ida_kk - 1;
+                }
+            } else {
+                // Decrease order to 1 if errors are reduced by at least 1/2
+                if terr_km1 <= (terr_k * F::Scalar::from(0.5).unwrap()) {
+                    self.ida_knew = self.ida_kk - 1;
+                }
+            }
+        };
+
+        (
+            err_k,
+            err_km1,
+            (ck * enorm_k) > F::Scalar::one(), // Perform error test
+        )
+    }
+
+    /// IDARestore
+    /// This routine restores tn, psi, and phi in the event of a failure.
+    /// It changes back `phi-star` to `phi` (changed in `set_coeffs()`)
+    ///
+    ///
+    pub fn restore(&mut self, saved_t: F::Scalar) {
+        self.ida_tn = saved_t;
+
+        // Restore psi[0 .. kk] = psi[1 .. kk + 1] - hh
+        for j in 1..self.ida_kk + 1 {
+            self.ida_psi[j - 1] = self.ida_psi[j] - self.ida_hh;
+        }
+
+        //Zip::from(&mut self.ida_psi.slice_mut(s![0..self.ida_kk]))
+        //.and(&self.ida_psi.slice(s![1..self.ida_kk+1]));
+        //ida_psi -= &self.ida_psi.slice(s![1..self.ida_kk+1]);
+
+        if self.ida_ns <= self.ida_kk {
+            // cvals[0 .. kk-ns+1] = 1 / beta[ns .. kk+1]
+            Zip::from(
+                &mut self
+                    .ida_cvals
+                    .slice_mut(s![0..self.ida_kk - self.ida_ns + 1]),
+            )
+            .and(&self.ida_beta.slice(s![self.ida_ns..self.ida_kk + 1]))
+            .apply(|cvals, &beta| {
+                *cvals = beta.recip();
+            });
+
+            // phi[ns .. (kk + 1)] *= cvals[ns .. (kk + 1)]
+            let mut ida_phi = self
+                .ida_phi
+                .slice_axis_mut(Axis(0), Slice::from(self.ida_ns..self.ida_kk + 1));
+
+            // We manually broadcast cvals here so we can turn it into a column vec
+            let cvals = self.ida_cvals.slice(s![0..self.ida_kk - self.ida_ns + 1]);
+            let cvals = cvals
+                .broadcast((1, ida_phi.len_of(Axis(0))))
+                .unwrap()
+                .reversed_axes();
+
+            ida_phi *= &cvals;
+        }
+    }
+
+    /// IDAHandleNFlag
+    /// This routine handles failures indicated by the input variable nflag. `IdaRecoverable`
+    /// errors indicate various recoverable failures while all others are nonrecoverable. This
+    /// routine adjusts the step size for recoverable failures.
+    ///
+    ///  Possible nflag values (input):
+    ///
+    ///   --convergence failures--
+    ///   IdaRecoverable::NonlinearConvergence
+    ///   IdaRecoverable::LinearSetup
+    ///   IdaRecoverable::LinearSolve
+    ///   IdaRecoverable::Residual
+    ///   any nonrecoverable error from `nonlinear_solve()`
+    ///
+    ///   --error test failure--
+    ///   IdaRecoverable::ErrorTest
+    ///
+    ///  Possible return values:
+    ///
+    ///   --recoverable--
+    ///   Ok(()), predict again
+    ///
+    ///   --nonrecoverable--
+    ///   IdaError::ErrorTestFail
+    ///   IdaError::ConvergenceFail
+    ///   nflag, if it was nonrecoverable
+    ///
+    /// `ncf` and `nef` are the local counters of convergence and error test failures for the
+    /// current step.
+    pub fn handle_n_flag(
+        &mut self,
+        nflag: failure::Error,
+        err_k: F::Scalar,
+        err_km1: F::Scalar,
+        ncf: &mut u64,
+        nef: &mut u64,
+    ) -> Result<(), failure::Error> {
+        self.ida_phase = 1;
+
+        match nflag.downcast::<IdaRecoverable>() {
+            Ok(IdaRecoverable::ErrorTest {}) => {
+                //-----------------
+                // Error Test failed
+                //-----------------
+
+                *nef += 1;
+                self.ida_netf += 1;
+                let at_hmin = self.at_hmin();
+
+                if *nef == 1 {
+                    // On first error test failure, keep current order or lower order by one.
+                    // Compute new stepsize based on differences of the solution.
+                    let err_knew = if self.ida_kk == self.ida_knew {
+                        err_k
+                    } else {
+                        err_km1
+                    };
+
+                    self.ida_kk = self.ida_knew;
+                    self.ida_rr = {
+                        let base = F::Scalar::from(2.0).unwrap() * err_knew
+                            + F::Scalar::from(0.0001).unwrap();
+                        let arg = -F::Scalar::one() / F::Scalar::from(self.ida_kk + 1).unwrap();
+                        F::Scalar::from(0.9).unwrap() * base.powf(arg)
+                    };
+                    self.ida_rr = F::Scalar::from(0.25)
+                        .unwrap()
+                        .max(self.ida_rr.min(F::Scalar::from(0.9).unwrap()));
+                } else if *nef == 2 {
+                    // On second error test failure, use current order or decrease order by one.
+                    // Reduce stepsize by factor of 1/4.
+                    self.ida_kk = self.ida_knew;
+                    self.ida_rr = F::Scalar::from(0.25).unwrap();
+                } else {
+                    // On third and subsequent error test failures, set order to 1. Reduce
+                    // stepsize by factor of 1/4.
+                    self.ida_kk = 1;
+                    self.ida_rr = F::Scalar::from(0.25).unwrap();
+                }
+                self.ida_hh *= self.ida_rr;
+                self.limit_hmin();
+
+                // Check if we had too many failures, or the step size was already at its minimum
+                if *nef >= self.ida_maxnef || at_hmin {
+                    Err(IdaError::ErrorTestFail {})?;
+                }
+                Ok(())
+            }
+
+            Ok(nflag) => {
+                //-----------------------
+                // Nonlinear solver failed
+                //-----------------------
+
+                *ncf += 1;
+                self.ida_ncfn += 1;
+                let at_hmin = self.at_hmin();
+
+                // Reduce step size for a new prediction. For a constraint failure, rr was already
+                // set by check_constraints().
+                match nflag {
+                    IdaRecoverable::Constraint {} => {}
+                    _ => self.ida_rr = F::Scalar::from(0.25).unwrap(),
+                }
+                self.ida_hh *= self.ida_rr;
+                self.limit_hmin();
+
+                // Test if there were too many convergence failures, or the step size was already
+                // at its minimum
+                if *ncf >= self.ida_maxncf || at_hmin {
+                    match nflag {
+                        IdaRecoverable::Residual {} => Err(IdaError::RepeatedResidualError {})?,
+                        IdaRecoverable::Constraint {} => Err(IdaError::ConstraintFail {})?,
+                        _ => Err(IdaError::ConvergenceFail {})?,
+                    }
+                }
+                Ok(())
+            }
+
+            Err(nflag) => {
+                // nonrecoverable failure
+                *ncf += 1;
+                self.ida_ncfn += 1;
+                Err(nflag)
+            }
+        }
+    }
+
+    /// Whether the magnitude of the step size hh is at (or below) its minimum hmin
+    fn at_hmin(&self) -> bool {
+        self.ida_hmin > F::Scalar::zero()
+            && self.ida_hh.abs() <= self.ida_hmin * F::Scalar::from(ONEPSM).unwrap()
+    }
+
+    /// Raises the magnitude of the step size hh to at least hmin
+    fn limit_hmin(&mut self) {
+        if self.ida_hh.abs() < self.ida_hmin {
+            self.ida_hh = self.ida_hmin * self.ida_hh.signum();
+        }
+    }
+
+    /// IDAReset
+    /// This routine is called only if we need to predict again at the very first step. In such a case,
+    /// reset phi[1] and psi[0].
+    pub fn reset(&mut self) {
+        self.ida_psi[0] = self.ida_hh;
+        //N_VScale(IDA_mem->ida_rr, IDA_mem->ida_phi[1], IDA_mem->ida_phi[1]);
+        let mut phi1 = self.ida_phi.index_axis_mut(Axis(0), 1);
+        phi1 *= self.ida_rr;
+    }
+
+    /// IDACompleteStep
+    /// This routine completes a successful step.  It increments nst, saves the stepsize and order
+    /// used, makes the final selection of stepsize and order for the next step, and updates the phi
+    /// array.
+    pub fn complete_step(&mut self, err_k: F::Scalar, err_km1: F::Scalar) {
+        self.ida_nst += 1;
+        let first_step = self.ida_kused == 0;
+        let kdiff = self.ida_kk as isize - self.ida_kused as isize;
+        self.ida_kused = self.ida_kk;
+        self.ida_hused = self.ida_hh;
+
+        if (self.ida_knew == self.ida_kk - 1) || (self.ida_kk == self.ida_maxord) {
+            self.ida_phase = 1;
+        }
+
+        // For the first few steps, until either a step fails, or the order is reduced, or the
+        // order reaches its maximum, we raise the order and double the stepsize. During these
+        // steps, phase = 0. Thereafter, phase = 1, and stepsize and order are set by the usual
+        // local error algorithm.
+        //
+        // Note that, after the first step, the order is not increased, as not all of the
+        // neccessary information is available yet.
+
+        if self.ida_phase == 0 {
+            if !first_step {
+                self.ida_kk += 1;
+                let mut hnew = F::Scalar::from(2.0).unwrap() * self.ida_hh;
+                let tmp = hnew.abs() * self.ida_hmax_inv;
+                if tmp > F::Scalar::one() {
+                    hnew /= tmp;
+                }
+                self.ida_hh = hnew;
+            }
+        } else {
+            enum Action {
+                None,
+                Lower,
+                Maintain,
+                Raise,
+            }
+
+            let mut action = Action::None;
+
+            // Set action = LOWER/MAINTAIN/RAISE to specify order decision
+
+            if self.ida_knew == (self.ida_kk - 1) {
+                action = Action::Lower;
+            } else if self.ida_kk == self.ida_maxord
+                || (self.ida_kk + 1) >= self.ida_ns
+                || (kdiff == 1)
+            {
+                action = Action::Maintain;
+            }
+
+            // Estimate the error at order k+1, unless already decided to reduce order, or already using
+            // maximum order, or stepsize has not been constant, or order was just raised.
+
+            let mut err_kp1 = F::Scalar::zero();
+
+            if let Action::None = action {
+                //N_VLinearSum(ONE, IDA_mem->ida_ee, -ONE, IDA_mem->ida_phi[IDA_mem->ida_kk + 1], IDA_mem->ida_tempv1);
+                let ida_tempv1 = &self.ida_ee - &self.ida_phi.index_axis(Axis(0), self.ida_kk + 1);
+                let enorm = self.wrms_norm(&ida_tempv1, &self.ida_ewt, self.ida_suppressalg);
+                err_kp1 = enorm / F::Scalar::from(self.ida_kk + 2).unwrap();
+
+                // Choose among orders k-1, k, k+1 using local truncation error norms.
+
+                let terr_k = F::Scalar::from(self.ida_kk + 1).unwrap() * err_k;
+                let terr_kp1 = F::Scalar::from(self.ida_kk + 2).unwrap() * err_kp1;
+
+                if self.ida_kk == 1 {
+                    if terr_kp1 >= F::Scalar::from(0.5).unwrap() * terr_k {
+                        action = Action::Maintain;
+                    } else {
+                        action = Action::Raise;
+                    }
+                } else {
+                    let terr_km1 = F::Scalar::from(self.ida_kk).unwrap() * err_km1;
+                    if terr_km1 <= terr_k.min(terr_kp1) {
+                        action = Action::Lower;
+                    } else if terr_kp1 >= terr_k {
+                        action = Action::Maintain;
+                    } else {
+                        action = Action::Raise;
+                    }
+                }
+            }
+            //takeaction:
+
+            // Set the estimated error norm and, on change of order, reset kk.
+            let err_knew = match action {
+                Action::Raise => {
+                    self.ida_kk += 1;
+                    err_kp1
+                }
+                Action::Lower => {
+                    self.ida_kk -= 1;
+                    err_km1
+                }
+                _ => err_k,
+            };
+
+            // Compute rr = tentative ratio hnew/hh from error norm estimate.
+            // Reduce hh if rr <= 1, double hh if rr >= 2, else leave hh as is.
+            // If hh is reduced, hnew/hh is restricted to be between .5 and .9.
+
+            let mut hnew = self.ida_hh;
+            //ida_rr = SUNRpowerR( TWO * err_knew + PT0001, -ONE/(IDA_mem->ida_kk + 1) );
+            self.ida_rr = {
+                let base =
+                    F::Scalar::from(2.0).unwrap() * err_knew + F::Scalar::from(0.0001).unwrap();
+                let arg =
+                    -F::Scalar::one() / (F::Scalar::from(self.ida_kk).unwrap() + F::Scalar::one());
+                base.powf(arg)
+            };
+
+            if self.ida_rr >= F::Scalar::from(2.0).unwrap() {
+                hnew = F::Scalar::from(2.0).unwrap() * self.ida_hh;
+                let tmp = hnew.abs() * self.ida_hmax_inv;
+                if tmp > F::Scalar::one() {
+                    hnew /= tmp;
+                }
+            } else if self.ida_rr <= F::Scalar::one() {
+                //ida_rr = SUNMAX(HALF, SUNMIN(PT9,IDA_mem->ida_rr));
+                self.ida_rr = F::Scalar::from(0.5)
+                    .unwrap()
+                    .max(self.ida_rr.min(F::Scalar::from(0.9).unwrap()));
+                hnew = self.ida_hh * self.ida_rr;
+            }
+
+            self.ida_hh = hnew;
+            self.limit_hmin();
+        }
+        // end of phase if block
+
+        // Save ee for possible order increase on next step
+        if self.ida_kused < self.ida_maxord {
+            //N_VScale(ONE, IDA_mem->ida_ee, IDA_mem->ida_phi[IDA_mem->ida_kused + 1]);
+            self.ida_phi
+                .index_axis_mut(Axis(0), self.ida_kused + 1)
+                .assign(&self.ida_ee);
+        }
+
+        // Update phi arrays
+
+        // Update phi arrays:
+        // phi[kused] += ee, then phi[j] += phi[j + 1] for j = kused - 1, ..., 0
+        let mut next = self.ida_phi.index_axis_mut(Axis(0), self.ida_kused);
+        next += &self.ida_ee;
+        for j in (0..self.ida_kused).rev() {
+            let (mut phi_j, phi_next) = self.ida_phi.view_mut().split_at(Axis(0), j + 1);
+            let mut phi_j = phi_j.index_axis_mut(Axis(0), j);
+            phi_j += &phi_next.index_axis(Axis(0), 0);
+        }
+    }
+
+    /// This routine evaluates `y(t)` and `y'(t)` as the value and derivative of the interpolating
+    /// polynomial at the independent variable t, and stores the results in the vectors yret and ypret.
+    /// It uses the current independent variable value, tn, and the method order last used, kused.
+    /// This function is called by `solve` with `t = tout`, `t = tn`, or `t = tstop`.
+    ///
+    /// If `kused = 0` (no step has been taken), or if `t = tn`, then the order used here is taken
+    /// to be 1, giving `yret = phi[0]`, `ypret = phi[1]/psi[0]`.
+    ///
+    /// The return values are:
+    ///   IDA_SUCCESS  if t is legal, or
+    ///   IDA_BAD_T    if t is not within the interval of the last step taken.
+    pub fn get_solution(
+        &mut self,
+        t: F::Scalar,
+        yret: &mut Array<F::Scalar, Ix1>,
+        ypret: &mut Array<F::Scalar, Ix1>,
+    ) -> Result<(), failure::Error> {
+        // Check t for legality.  Here tn - hused is t_{n-1}.
+
+        //tfuzz = HUNDRED * IDA_mem->ida_uround * (SUNRabs(IDA_mem->ida_tn) + SUNRabs(IDA_mem->ida_hh));
+
+        let mut tfuzz = F::Scalar::from(100.0).unwrap()
+            * F::Scalar::epsilon()
+            * (self.ida_tn.abs() + self.ida_hh.abs());
+        if self.ida_hh < F::Scalar::zero() {
+            tfuzz = -tfuzz;
+        }
+        let tp = self.ida_tn - self.ida_hused - tfuzz;
+        if (t - tp) * self.ida_hh < F::Scalar::zero() {
+            Err(IdaError::BadTimeValue {
+                t: t.to_f64().unwrap(),
+                tdiff: (self.ida_tn - self.ida_hused).to_f64().unwrap(),
+                tcurr: self.ida_tn.to_f64().unwrap(),
+            })?;
+        }
+
+        // Initialize kord = (kused or 1).
+        let kord = if self.ida_kused == 0 {
+            1
+        } else {
+            self.ida_kused
+        };
+
+        // Accumulate multiples of columns phi[j] into yret and ypret.
+        let delt = t - self.ida_tn;
+        let mut c = F::Scalar::one();
+        let mut d = F::Scalar::zero();
+        let mut gam = delt / self.ida_psi[0];
+
+        self.ida_cvals[0] = c;
+        for j in 1..=kord {
+            d = d * gam + c / self.ida_psi[j - 1];
+            c *= gam;
+            gam = (delt + self.ida_psi[j - 1]) / self.ida_psi[j];
+
+            self.ida_cvals[j] = c;
+            self.ida_dvals[j - 1] = d;
+        }
+
+        //retval = N_VLinearCombination(kord+1, IDA_mem->ida_cvals, IDA_mem->ida_phi,  yret);
+        ndarray::Zip::from(yret)
+            .and(
+                self.ida_phi
+                    .slice_axis(Axis(0), Slice::from(0..kord + 1))
+                    .lanes(Axis(0)),
+            )
+            .apply(|z, row| {
+                *z = (&row * &self.ida_cvals.slice(s![0..kord + 1])).sum();
+            });
+
+        //retval = N_VLinearCombination(kord, IDA_mem->ida_dvals, IDA_mem->ida_phi+1, ypret);
+        ndarray::Zip::from(ypret)
+            .and(
+                self.ida_phi
+                    .slice_axis(Axis(0), Slice::from(1..kord + 1))
+                    .lanes(Axis(0)),
+            )
+            .apply(|z, row| {
+                *z = (&row * &self.ida_dvals.slice(s![0..kord])).sum();
+            });
+
+        Ok(())
+    }
+
+    /// Returns the WRMS norm of vector x with weights w.
+    /// If mask = SUNTRUE, the weight vector w is masked by id, i.e.,
+    ///      nrm = N_VWrmsNormMask(x,w,id);
+    ///  Otherwise,
+    ///      nrm = N_VWrmsNorm(x,w);
+    ///
+    /// mask = SUNFALSE       when the call is made from the nonlinear solver.
+    /// mask = suppressalg otherwise.
+    pub fn wrms_norm<S1, S2>(
+        &self,
+        x: &ArrayBase<S1, Ix1>,
+        w: &ArrayBase<S2, Ix1>,
+        mask: bool,
+    ) -> F::Scalar
+    where
+        S1: Data<Elem = F::Scalar>,
+        S2: Data<Elem = F::Scalar>,
+    {
+        match (mask, &self.ida_id) {
+            (true, Some(id)) => x.view().norm_wrms_masked(&w.view(), id),
+            _ => x.view().norm_wrms(&w.view()),
+        }
+    }
+
+    /// Returns the solution vector y at the time last returned by `solve()`
+    pub fn get_yy(&self) -> ArrayView1<'_, F::Scalar> {
+        self.ida_yy.view()
+    }
+
+    /// Returns the derivative vector y' at the time last returned by `solve()`
+    pub fn get_yp(&self) -> ArrayView1<'_, F::Scalar> {
+        self.ida_yp.view()
+    }
+
+    /// IDAGetRootInfo
+    ///
+    /// Returns the direction of the zero crossing of each root function at the root last
+    /// returned by `solve()`: 1 if g_i was increasing, -1 if it was decreasing, and 0 if g_i
+    /// has no root there.
+    pub fn get_root_info(&self) -> ArrayView1<'_, i8> {
+        self.ida_iroots.view()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::excessive_precision)]
+mod tests {
+    use crate::dual::AutoJacobian;
+    use crate::heat2d::Heat2D;
+    use crate::ida::{
+        Constraint, EventAction, IcOption, Ida, IdaError, IdaOptions, IdaRecoverable, SolveStatus,
+        Task,
+    };
+    use crate::linear::{Band, Bbd, BlockJacobi, Dense, Ilu0, LinearSolver, SparseLu, Spgmr};
+    use crate::lorenz63::Lorenz63;
+    use crate::roberts::Roberts;
+    use crate::testing::Wrapped;
+    use crate::traits::*;
+
+    use ndarray::*;
+    use nearly_eq::*;
+
+    #[test]
+    fn test_test_error1() {
+        let ck = 1.091414141414142;
+        let suppressalg = 0;
+        let kk = 5;
+        let ida_phi = array![
+            [
+                3.634565317158998e-05,
+                1.453878335134203e-10,
+                0.9999636542014404,
+            ],
+            [
+                -6.530333550677049e-06,
+                -2.612329458968465e-11,
+                6.530359673556191e-06,
+            ],
+            [
+                1.946442728026142e-06,
+                7.786687275994346e-12,
+                -1.946450515496441e-06,
+            ],
+            [
+                -8.097632208221231e-07,
+                -3.239585549038764e-12,
+                8.097664556005615e-07,
+            ],
+            [
+                3.718130977075839e-07,
+                1.487573462300438e-12,
+                -3.71814615793545e-07,
+            ],
+            [
+                -3.24421895454213e-07,
+                -1.297915245220823e-12,
+                3.244230624265827e-07,
+            ],
+        ];
+        let ida_ee = array![
+            2.65787533317467e-07,
+            1.063275845801634e-12,
+            -2.657884288386138e-07,
+        ];
+        let ida_ewt = array![73343005.56993243, 999999.985461217, 9901.346408259429];
+        let ida_sigma = array![
+            1.0,
+            0.6666666666666666,
+            0.6666666666666666,
+            0.888888888888889,
+            1.422222222222222,
+            2.585858585858586,
+        ];
+        let knew = 4;
+        let err_k = 29.10297975314245;
+        let err_km1 = 3.531162835377502;
+        let nflag = true;
+
+        let f = Lorenz63::default();
+        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+
+        // Set preconditions:
+        ida.ida_kk = kk;
+        ida.ida_suppressalg = suppressalg > 0;
+        ida.ida_phi.assign(&ida_phi);
+        ida.ida_ee.assign(&ida_ee);
+        ida.ida_ewt.assign(&ida_ewt);
+        ida.ida_sigma.assign(&ida_sigma);
+
+        // Call the function under test
+        let (err_k_new, err_km1_new, nflag_new) = ida.test_error(ck);
+
+        assert_eq!(ida.ida_knew, knew);
+        assert_nearly_eq!(err_k_new, err_k);
+        assert_nearly_eq!(err_km1_new, err_km1);
+        assert_eq!(nflag_new, nflag);
+    }
+
+    #[test]
+    fn test_test_error2() {
+        //--- IDATestError Before:
+        let ck = 0.2025812352167927;
+        let suppressalg = 0;
+        let kk = 4;
+        let ida_phi = array![
+            [
+                3.051237735052657e-05,
+                1.220531905117091e-10,
+                0.9999694875005963,
+            ],
+            [
+                -2.513114849098281e-06,
+                -1.005308974226734e-11,
+                2.513124902721765e-06,
+            ],
+            [
+                4.500284453718991e-07,
+                1.800291970640913e-12,
+                -4.500302448499092e-07,
+            ],
+            [
+                -1.366709389821433e-07,
+                -5.467603693902342e-13,
+                1.366714866794709e-07,
+            ],
+            [
+                7.278821769100639e-08,
+                2.911981566628798e-13,
+                -7.278850816613011e-08,
+            ],
+            [
+                -8.304741244343501e-09,
+                -3.324587131187576e-14,
+                8.304772990651073e-09,
+            ],
+        ];
+        let ida_ee = array![
+            -2.981302228744271e-08,
+            -1.192712676406388e-13,
+            2.981313872620108e-08,
+        ];
+        let ida_ewt = array![76621085.31777237, 999999.9877946811, 9901.289220872719,];
+        let ida_sigma = array![
+            1.0,
+            0.5,
+            0.3214285714285715,
+            0.2396514200444849,
+            0.1941955227762807,
+            2.585858585858586,
+        ];
+        //--- IDATestError After:
+        let knew = 4;
+        let err_k = 0.2561137489433976;
+        let err_km1 = 0.455601916633899;
+        let nflag = false;
+
+        let f = Lorenz63::default();
+        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+
+        // Set preconditions:
+        ida.ida_kk = kk;
+        ida.ida_suppressalg = suppressalg > 0;
+        ida.ida_phi.assign(&ida_phi);
+        ida.ida_ee.assign(&ida_ee);
+        ida.ida_ewt.assign(&ida_ewt);
+        ida.ida_sigma.assign(&ida_sigma);
+
+        // Call the function under test
+        let (err_k_new, err_km1_new, nflag_new) = ida.test_error(ck);
+
+        assert_eq!(ida.ida_knew, knew);
+        assert_nearly_eq!(err_k_new, err_k);
+        assert_nearly_eq!(err_km1_new, err_km1);
+        assert_eq!(nflag_new, nflag);
+    }
+
+    #[test]
+    fn test_restore1() {
+        let saved_t = 717553.4942644858;
+        #[rustfmt::skip]
+        let phi_before = array![[0.00280975951420059, 1.125972706132338e-08, 0.9971902292261264], [-0.0001926545663078034, -7.857235149861102e-10,0.0001926553520857565], [2.945636347837807e-05, 1.066748079583829e-10,-2.945647009050819e-05], [-5.518529121250618e-06, -4.529997656241677e-11,5.518574540464112e-06], [2.822681468681011e-06, -4.507342025411469e-11,-2.822636100488049e-06], [-8.124641701620927e-08,-8.669560754165103e-11,8.133355922669991e-08], ];
+        #[rustfmt::skip]
+        let psi_before = array![ 47467.05706123715, 94934.1141224743, 142401.1711837114, 166134.69971433, 189868.2282449486, 107947.0192373629 ];
+        let cvals_before = array![1., 1., 1., 1., 1., 0.];
+        let beta_before = array![1., 1., 1., 1.2, 1.4, 1.];
+
+        #[rustfmt::skip]
+        let phi_after = array![[0.00280975951420059,1.125972706132338e-08, 0.9971902292261264,], [-0.0001926545663078034,-7.857235149861102e-10,0.0001926553520857565,], [2.945636347837807e-05,1.066748079583829e-10,-2.945647009050819e-05,], [-4.598774267708849e-06,-3.774998046868064e-11,4.598812117053426e-06,], [2.016201049057865e-06,-3.219530018151049e-11,-2.016168643205749e-06,], [-8.124641701620927e-08,-8.669560754165103e-11,8.133355922669991e-08,], ];
+        #[rustfmt::skip]
+        let psi_after = array![ 47467.05706123715, 94934.11412247429, 118667.6426530929, 142401.1711837114, 189868.2282449486, 107947.0192373629 ];
+        let cvals_after = array![0.8333333333333334, 0.7142857142857142, 1., 1., 1., 0.];
+        let beta_after = array![1., 1., 1., 1.2, 1.4, 1.];
+
+        let f = Lorenz63::default();
+        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+
+        // Set preconditions:
+        ida.ida_tn = 765020.5513257229;
+        ida.ida_ns = 3;
+        ida.ida_kk = 4;
+        ida.ida_hh = 47467.05706123715;
+        ida.ida_phi.assign(&phi_before);
+        ida.ida_psi.assign(&psi_before);
+        ida.ida_cvals.assign(&cvals_before);
+        ida.ida_beta.assign(&beta_before);
+
+        // Call the function under test
+        ida.restore(saved_t);
+
+        assert_nearly_eq!(ida.ida_tn, saved_t);
+        assert_eq!(ida.ida_ns, 3);
+        assert_eq!(ida.ida_kk, 4);
+        assert_nearly_eq!(ida.ida_cvals, cvals_after, 1e-6);
+        assert_nearly_eq!(ida.ida_beta, beta_after, 1e-6);
+        assert_nearly_eq!(ida.ida_psi, psi_after, 1e-6);
+        assert_nearly_eq!(ida.ida_phi, phi_after, 1e-6);
+    }
+
+    #[test]
+    fn test_restore2() {
+        let saved_t = 3623118336.24244;
+        #[rustfmt::skip]
+        let phi_before = array![ [5.716499633245077e-07,2.286601144610028e-12, 0.9999994283477499,], [-1.555846772013456e-07,-6.223394599091205e-13,1.555852991517385e-07,], [7.018252655941472e-08,2.807306512268244e-13,-7.01828076998538e-08,], [-4.56160628763917e-08,-1.824647796129851e-13,4.561624269904529e-08,], [5.593228676143622e-08,2.237297583983664e-13,-5.593253344183256e-08,], [-2.242367216194777e-10,-8.970915966733762e-16,2.242247401239887e-10,], ];
+        #[rustfmt::skip]
+        let psi_before = array![  857870592.1885694,   1286805888.282854,   1715741184.377139,   1930208832.424281,   2144676480.471424,    26020582.4876316];
+        #[rustfmt::skip]
+        let cvals_before = array![1., 1., 1., 1., 1., 1.];
+        #[rustfmt::skip]
+        let beta_before = array![1., 2., 3., 4.8, 7.199999999999999, 10.28571428571428];
+        //--- IDARestore After: saved_t=   3623118336.24244 tn=   3623118336.24244 ns=1 kk=4
+        #[rustfmt::skip]
+        let phi_after = array![ [5.716499633245077e-07,2.286601144610028e-12, 0.9999994283477499,], [-7.779233860067279e-08,-3.111697299545603e-13,7.779264957586927e-08,], [2.339417551980491e-08,9.35768837422748e-14,-2.33942692332846e-08,], [-9.503346432581604e-09,-3.801349575270522e-14,9.503383895634436e-09,], [7.768373161310588e-09,3.107357755532867e-14,-7.768407422476745e-09,], [-2.242367216194777e-10,-8.970915966733762e-16,2.242247401239887e-10,], ];
+        #[rustfmt::skip]
+        let psi_after= array![  428935296.0942847,   857870592.1885694,   1072338240.235712,   1286805888.282854,   2144676480.471424,    26020582.4876316];
+        #[rustfmt::skip]
+        let cvals_after = array![ 0.5, 0.3333333333333333, 0.2083333333333333, 0.1388888888888889, 1., 1. ];
+        #[rustfmt::skip]
+        let beta_after = array![1., 2., 3., 4.8, 7.199999999999999, 10.28571428571428];
+
+        let f = Lorenz63::default();
+        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+
+        // Set preconditions:
+        ida.ida_tn = 4480988928.431009;
+        ida.ida_ns = 1;
+        ida.ida_kk = 4;
+        ida.ida_hh = 857870592.1885694;
+        ida.ida_phi.assign(&phi_before);
+        ida.ida_psi.assign(&psi_before);
+        ida.ida_cvals.assign(&cvals_before);
+        ida.ida_beta.assign(&beta_before);
+
+        // Call the function under test
+        ida.restore(saved_t);
+
+        assert_nearly_eq!(ida.ida_tn, saved_t);
+        assert_eq!(ida.ida_ns, 1);
+        assert_eq!(ida.ida_kk, 4);
+        assert_nearly_eq!(ida.ida_cvals, cvals_after, 1e-6);
+        assert_nearly_eq!(ida.ida_beta, beta_after, 1e-6);
+        assert_nearly_eq!(ida.ida_psi, psi_after, 1e-6);
+        assert_nearly_eq!(ida.ida_phi, phi_after, 1e-6);
+    }
+
+    #[test]
+    fn test_restore3() {
+        let saved_t = 13638904.64873992;
+        let phi_before = array![
+            [
+                0.0001523741818966069,
+                6.095884948264652e-10,
+                0.9998476252085154,
+            ],
+            [
+                -1.964117218731689e-05,
+                -7.858910051867137e-11,
+                1.964125077907938e-05,
+            ],
+            [
+                4.048658569496216e-06,
+                1.620249912028008e-11,
+                -4.048674765925692e-06,
+            ],
+            [
+                -1.215165175266232e-06,
+                -4.863765573523665e-12,
+                1.21517004866448e-06,
+            ],
+            [
+                4.909710408845208e-07,
+                1.965778579990634e-12,
+                -4.909729965008022e-07,
+            ],
+            [
+                -2.529640523993838e-07,
+                -1.012593011825966e-12,
+                2.529650614751456e-07,
+            ],
+        ];
+        let psi_before = array![
+            1656116.685489699,
+            2484175.028234549,
+            3312233.370979399,
+            4140291.713724249,
+            5060356.538996303,
+            5520388.951632331
+        ];
+        let cvals_before = array![1., 1., 1., 1., 1., 1.];
+        let beta_before = array![1., 2., 3., 4., 4.864864864864866, 6.370656370656372];
+        //--- IDARestore After: saved_t=  13638904.64873992 tn=  13638904.64873992 ns=1 kk=5
+        let phi_after = array![
+            [
+                0.0001523741818966069,
+                6.095884948264652e-10,
+                0.9998476252085154,
+            ],
+            [
+                -9.820586093658443e-06,
+                -3.929455025933569e-11,
+                9.820625389539692e-06,
+            ],
+            [
+                1.349552856498739e-06,
+                5.400833040093358e-12,
+                -1.349558255308564e-06,
+            ],
+            [
+                -3.037912938165579e-07,
+                -1.215941393380916e-12,
+                3.0379251216612e-07,
+            ],
+            [
+                1.009218250707071e-07,
+                4.040767081091857e-13,
+                -1.009222270584982e-07,
+            ],
+            [
+                -3.970769064935782e-08,
+                -1.589464182199546e-13,
+                3.970784904367437e-08,
+            ],
+        ];
+        let psi_after = array![
+            828058.3427448499,
+            1656116.685489699,
+            2484175.02823455,
+            3404239.853506604,
+            3864272.266142632,
+            5520388.951632331,
+        ];
+        let cvals_after = array![
+            0.5,
+            0.3333333333333333,
+            0.25,
+            0.2055555555555555,
+            0.156969696969697,
+            1.,
+        ];
+        let beta_after = array![1., 2., 3., 4., 4.864864864864866, 6.370656370656372];
+
+        let f = Lorenz63::default();
+        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+
+        // Set preconditions:
+        ida.ida_tn = 15295021.33422961;
+        ida.ida_ns = 1;
+        ida.ida_kk = 5;
+        ida.ida_hh = 1656116.685489699;
+        ida.ida_phi.assign(&phi_before);
+        ida.ida_psi.assign(&psi_before);
+        ida.ida_cvals.assign(&cvals_before);
         ida.ida_beta.assign(&beta_before);
 
-        // Call the function under test
-        ida.restore(saved_t);
+        // Call the function under test
+        ida.restore(saved_t);
+
+        assert_nearly_eq!(ida.ida_tn, saved_t);
+        assert_eq!(ida.ida_ns, 1);
+        assert_eq!(ida.ida_kk, 5);
+        assert_nearly_eq!(ida.ida_cvals, cvals_after, 1e-6);
+        assert_nearly_eq!(ida.ida_beta, beta_after, 1e-6);
+        assert_nearly_eq!(ida.ida_psi, psi_after, 1e-6);
+        assert_nearly_eq!(ida.ida_phi, phi_after, 1e-6);
+    }
+
+    #[test]
+    fn test_complete_step() {
+        let err_k = 0.1022533962984153;
+        let err_km1 = 0.3638660854770704;
+        let ida_phi = array![
+            [0.0000001057015204, 0.0000000000004228, 0.9999998942980568,],
+            [-0.0000000330821964, -0.0000000000001323, 0.0000000330823287,],
+            [0.0000000186752739, 0.0000000000000747, -0.0000000186753488,],
+            [-0.0000000199565018, -0.0000000000000798, 0.0000000199565809,],
+            [0.0000000012851942, 0.0000000000000051, -0.0000000012851948,],
+            [-0.0000000002242367, -0.0000000000000009, 0.0000000002242247,],
+        ];
+        let ida_ee = array![-0.0000000051560075, -0.0000000000000206, 0.0000000051560285,];
+        let ida_ewt = array![
+            99894410.0897681862115860,
+            999999.9999577193520963,
+            9900.9911352019826154,
+        ];
+        let kk = 2;
+        let kused = 2;
+        let knew = 2;
+        let phase = 1;
+        let hh = 3774022770.1406540870666504;
+        let hused = 4313148194.5176315307617188;
+        let rr = 0.8750041964562566;
+        let hmax_inv = 0.0000000000000000;
+        let nst = 357;
+        let maxord = 5;
+
+        // Set preconditions:
+        let f = Lorenz63::default();
+        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+        ida.ida_nst = nst;
+        ida.ida_kk = kk;
+        ida.ida_hh = hh;
+        ida.ida_rr = rr;
+        ida.ida_kused = kused;
+        ida.ida_hused = hused;
+        ida.ida_knew = knew;
+        ida.ida_maxord = maxord;
+        ida.ida_phase = phase;
+        ida.ida_hmax_inv = hmax_inv;
+        ida.ida_ee.assign(&ida_ee);
+        ida.ida_phi.assign(&ida_phi);
+        ida.ida_ewt.assign(&ida_ewt);
+
+        ida.complete_step(err_k, err_km1);
+
+        let ida_phi = array![
+            [0.0000000861385903, 0.0000000000003446, 0.9999999138610652,],
+            [-0.0000000195629300, -0.0000000000000783, 0.0000000195630084,],
+            [0.0000000135192664, 0.0000000000000541, -0.0000000135193203,],
+            [-0.0000000051560075, -0.0000000000000206, 0.0000000051560285,],
+            [0.0000000012851942, 0.0000000000000051, -0.0000000012851948,],
+            [-0.0000000002242367, -0.0000000000000009, 0.0000000002242247,],
+        ];
+        let ida_ee = array![-0.0000000051560075, -0.0000000000000206, 0.0000000051560285,];
+        let ida_ewt = array![
+            99894410.0897681862115860,
+            999999.9999577193520963,
+            9900.9911352019826154,
+        ];
+        let kk = 2;
+        let kused = 2;
+        let knew = 2;
+        let phase = 1;
+        let hh = 3774022770.1406540870666504;
+        let hused = 3774022770.1406540870666504;
+        let rr = 1.6970448397793398;
+        let hmax_inv = 0.0000000000000000;
+        let nst = 358;
+        let maxord = 5;
+
+        assert_eq!(ida.ida_nst, nst);
+        assert_eq!(ida.ida_kk, kk);
+        assert_eq!(ida.ida_hh, hh);
+        assert_nearly_eq!(ida.ida_rr, rr, 1e-6);
+        assert_eq!(ida.ida_kused, kused);
+        assert_eq!(ida.ida_hused, hused);
+        assert_eq!(ida.ida_knew, knew);
+        assert_eq!(ida.ida_maxord, maxord);
+        assert_eq!(ida.ida_phase, phase);
+        assert_eq!(ida.ida_hmax_inv, hmax_inv);
+        assert_nearly_eq!(ida.ida_ee, ida_ee, 1e-6);
+        assert_nearly_eq!(ida.ida_phi, ida_phi, 1e-6);
+        assert_nearly_eq!(ida.ida_ewt, ida_ewt, 1e-6);
+    }
+
+    #[test]
+    fn test_nonlinear_solve() {
+        let hh = 1.0e-3;
+        let y0 = array![1., 1., 1.];
+
+        let f = Lorenz63::default();
+        let mut yp0 = Array::zeros(3);
+        f.rhs(&y0, &mut yp0);
+
+        let mut ida = Ida::new(f, y0.clone(), yp0.clone());
+
+        // Set preconditions: an explicit Euler predictor with a backward Euler corrector
+        ida.ida_cj = 1. / hh;
+        ida.ida_yypredict.assign(&(&y0 + &(&yp0 * hh)));
+        ida.ida_yppredict.assign(&yp0);
+        ida.ida_ewt.fill(1.0e6);
+        ida.ida_ls.init(&ida.f).unwrap();
+
+        // Call the function under test
+        ida.nonlinear_solve().unwrap();
+
+        // The corrected values satisfy F(y, y') = y' - f(y) = 0
+        let mut rhs = Array::zeros(3);
+        f.rhs(&ida.ida_yy, &mut rhs);
+        assert_nearly_eq!(ida.ida_yp, rhs, 1e-9);
+
+        // and are consistent with the accumulated corrections
+        assert_nearly_eq!(ida.ida_yy, &ida.ida_yypredict + &ida.ida_ee, 1e-12);
+        assert_nearly_eq!(ida.ida_yp, &ida.ida_yppredict + &(&ida.ida_ee / hh), 1e-9);
+
+        assert!(ida.ida_nni > 0);
+        assert_eq!(ida.ida_nre, ida.ida_nni);
+        assert_eq!(ida.ida_nsetups, 1);
+    }
+
+    #[test]
+    fn test_handle_n_flag() {
+        let f = Lorenz63::default();
+        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+        let (mut ncf, mut nef) = (0, 0);
+
+        // First error test failure: new step size from the error estimate at order knew
+        ida.ida_kk = 3;
+        ida.ida_knew = 2;
+        ida.ida_hh = 1.0;
+        ida.handle_n_flag(
+            IdaRecoverable::ErrorTest {}.into(),
+            1.0,
+            4.0,
+            &mut ncf,
+            &mut nef,
+        )
+        .unwrap();
+        assert_eq!(ida.ida_kk, 2);
+        assert_nearly_eq!(ida.ida_rr, 0.9 * 8.0001f64.powf(-1.0 / 3.0), 1e-12);
+        assert_nearly_eq!(ida.ida_hh, ida.ida_rr, 1e-12);
+        assert_eq!((nef, ida.ida_netf, ida.ida_phase), (1, 1, 1));
+
+        // Subsequent failures reduce the step by 1/4 and the order down to 1
+        ida.ida_hh = 1.0;
+        ida.ida_knew = 1;
+        ida.handle_n_flag(
+            IdaRecoverable::ErrorTest {}.into(),
+            1.0,
+            1.0,
+            &mut ncf,
+            &mut nef,
+        )
+        .unwrap();
+        assert_eq!(ida.ida_kk, 1);
+        assert_eq!(ida.ida_hh, 0.25);
+        ida.ida_kk = 3;
+        ida.handle_n_flag(
+            IdaRecoverable::ErrorTest {}.into(),
+            1.0,
+            1.0,
+            &mut ncf,
+            &mut nef,
+        )
+        .unwrap();
+        assert_eq!(ida.ida_kk, 1);
+        assert_eq!(ida.ida_hh, 0.0625);
+
+        // Convergence failures reduce the step by 1/4 until maxncf is reached
+        for i in 1..ida.ida_maxncf {
+            ida.ida_hh = 1.0;
+            let nflag = IdaRecoverable::NonlinearConvergence {}.into();
+            ida.handle_n_flag(nflag, 1.0, 1.0, &mut ncf, &mut nef)
+                .unwrap();
+            assert_eq!(ida.ida_hh, 0.25);
+            assert_eq!((ncf, ida.ida_ncfn), (i, i));
+        }
+        let nflag = IdaRecoverable::NonlinearConvergence {}.into();
+        let err = ida
+            .handle_n_flag(nflag, 1.0, 1.0, &mut ncf, &mut nef)
+            .unwrap_err();
+        match err.downcast::<IdaError>() {
+            Ok(IdaError::ConvergenceFail {}) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    /// Reference solution of the Lorenz system from classic RK4 with a small fixed step
+    fn lorenz63_rk4(f: &Lorenz63, y0: &Array1<f64>, tout: f64, nsteps: usize) -> Array1<f64> {
+        let h = tout / nsteps as f64;
+        let rhs = |y: &Array1<f64>| {
+            let mut v = Array::zeros(3);
+            f.rhs(y, &mut v);
+            v
+        };
+        let mut y = y0.clone();
+        for _ in 0..nsteps {
+            let k1 = rhs(&y);
+            let k2 = rhs(&(&y + &(&k1 * (h / 2.))));
+            let k3 = rhs(&(&y + &(&k2 * (h / 2.))));
+            let k4 = rhs(&(&y + &(&k3 * h)));
+            y = &y + &((&k1 + &(&k2 * 2.) + &(&k3 * 2.) + &k4) * (h / 6.));
+        }
+        y
+    }
+
+    #[test]
+    fn test_solve_normal() {
+        let y0 = array![1., 1., 1.];
+        let f = Lorenz63::default();
+        let mut yp0 = Array::zeros(3);
+        f.rhs(&y0, &mut yp0);
+
+        let mut ida = Ida::new(f, y0.clone(), yp0);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+
+        for &tout in &[0.1, 0.2, 0.5] {
+            let (tret, status) = ida.solve(tout, Task::Normal).unwrap();
+            assert_eq!(tret, tout);
+            assert_eq!(status, SolveStatus::Success);
+
+            let y = lorenz63_rk4(&f, &y0, tout, 10000);
+            assert_nearly_eq!(ida.get_yy().to_owned(), y, 1e-4);
+        }
+        assert!(ida.ida_nst > 0);
+        assert!(ida.ida_tn >= 0.5);
+    }
+
+    #[test]
+    fn test_solve_one_step() {
+        let y0 = array![1., 1., 1.];
+        let f = Lorenz63::default();
+        let mut yp0 = Array::zeros(3);
+        f.rhs(&y0, &mut yp0);
+
+        let mut ida = Ida::new(f, y0.clone(), yp0);
+        ida.ss_tolerances(1e-6, 1e-6).unwrap();
+
+        // Each call returns after a single internal step
+        let mut tlast = 0.;
+        for nst in 1..=10 {
+            let (tret, status) = ida.solve(1.0, Task::OneStep).unwrap();
+            assert_eq!(status, SolveStatus::Success);
+            assert_eq!(ida.ida_nst, nst);
+            assert_eq!(tret, ida.ida_tn);
+            assert!(tret > tlast);
+            tlast = tret;
+        }
+
+        // The maximum number of steps per call is enforced
+        ida.ida_mxstep = 2;
+        let err = ida.solve(10.0, Task::Normal).unwrap_err();
+        match err.downcast::<IdaError>() {
+            Ok(IdaError::TooMuchWork { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_solve_tstop() {
+        let y0 = array![1., 1., 1.];
+        let f = Lorenz63::default();
+        let mut yp0 = Array::zeros(3);
+        f.rhs(&y0, &mut yp0);
+
+        let mut ida = Ida::new(f, y0.clone(), yp0);
+        ida.ss_tolerances(1e-6, 1e-6).unwrap();
+        ida.set_stop_time(0.05).unwrap();
+
+        // The integration stops exactly at tstop, and does not step past it
+        let (tret, status) = ida.solve(1.0, Task::Normal).unwrap();
+        assert_eq!(status, SolveStatus::TstopReturn);
+        assert_eq!(tret, 0.05);
+        assert_eq!(ida.ida_tn, 0.05);
+        assert!(!ida.ida_tstopset);
+
+        let y = lorenz63_rk4(&f, &y0, 0.05, 1000);
+        assert_nearly_eq!(ida.get_yy().to_owned(), y, 1e-3);
+
+        // A stop time behind tn is illegal, and a cleared one is ignored
+        assert!(ida.set_stop_time(0.04).is_err());
+        ida.set_stop_time(0.06).unwrap();
+        ida.clear_stop_time();
+        let (tret, status) = ida.solve(0.1, Task::Normal).unwrap();
+        assert_eq!((tret, status), (0.1, SolveStatus::Success));
+    }
+
+    /// `y' = u(t)`, with the input u stepping from 0 to 1 after t = 1
+    #[derive(Clone, Copy, Debug)]
+    struct InputStep;
+
+    impl ModelSpec for InputStep {
+        type Scalar = f64;
+        type Dim = Ix1;
+
+        fn model_size(&self) -> usize {
+            1
+        }
+    }
+
+    impl IdaModel for InputStep {
+        fn residual<S1, S2, S3>(
+            &mut self,
+            tt: f64,
+            _yy: &ArrayBase<S1, Ix1>,
+            yp: &ArrayBase<S2, Ix1>,
+            rr: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            rr[0] = yp[0] - if tt > 1.0 { 1.0 } else { 0.0 };
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_solve_discontinuities() {
+        let mut stepping = Ida::new(InputStep, array![1.0], array![0.0]);
+        stepping.ss_tolerances(1e-6, 1e-8).unwrap();
+        stepping.set_id(array![true]).unwrap();
+        let mut ida = stepping.clone();
+        assert!(ida.set_discontinuities(vec![f64::NAN]).is_err());
+        ida.set_discontinuities(vec![3.0, 1.0]).unwrap();
+
+        // The integration lands on t = 1 and restarts there at order 1
+        let mut landed = false;
+        while ida.ida_tn < 2.0 {
+            let (tret, _) = ida.solve(2.0, Task::OneStep).unwrap();
+            if tret == 1.0 {
+                landed = true;
+                assert_eq!(ida.get_yy()[0], 1.0);
+            } else if tret > 1.0 && !landed {
+                panic!("stepped past the discontinuity");
+            }
+        }
+        assert!(landed);
+        let (tret, _) = ida.solve(2.0, Task::Normal).unwrap();
+        assert_eq!(tret, 2.0);
+        assert_nearly_eq!(ida.get_yy()[0], 2.0, 1e-8);
+
+        // Stepping across the discontinuity takes error test failures and more steps
+        stepping.solve(2.0, Task::Normal).unwrap();
+        assert!(stepping.ida_netf > ida.ida_netf);
+        assert!(stepping.ida_nst > ida.ida_nst);
+    }
+
+    #[test]
+    fn test_solve_roberts() {
+        let mut ida = Ida::new(
+            Roberts::default(),
+            array![1., 0., 0.],
+            array![-0.04, 0.04, 0.],
+        );
+        ida.sv_tolerances(1e-4, array![1e-8, 1e-6, 1e-6]).unwrap();
+
+        // Reference values from the output of the Sundials idaRoberts_dns example
+        for &(tout, ref y) in &[
+            (0.4, array![9.8517e-01, 3.3864e-05, 1.4794e-02]),
+            (4.0, array![9.0553e-01, 2.2406e-05, 9.4452e-02]),
+            (40.0, array![7.1579e-01, 9.1838e-06, 2.8420e-01]),
+        ] {
+            ida.solve(tout, Task::Normal).unwrap();
+            let yy = ida.get_yy();
+            assert_nearly_eq!((&yy - y) / y, Array::zeros(3), 1e-2);
+            // The algebraic constraint is satisfied
+            assert_nearly_eq!(yy.sum(), 1.0, 1e-10);
+        }
+
+        // The iteration matrix is reused over several steps while cj changes little
+        assert!(ida.ida_nsetups * 2 < ida.ida_nst);
+    }
+
+    #[test]
+    fn test_detect_id() {
+        let mut ida = Ida::new(
+            Roberts::default(),
+            array![1., 0., 0.],
+            array![-0.04, 0.04, 0.],
+        );
+        ida.detect_id().unwrap();
+        assert_eq!(ida.ida_id, Some(array![true, true, false]));
+
+        // With the detected id, the algebraic component can be computed by calc_ic()
+        let mut ida = Ida::new(
+            Roberts::default(),
+            array![1., 0., 0.5],
+            array![-0.04, 0.04, 0.],
+        );
+        ida.ss_tolerances(1e-6, 1e-8).unwrap();
+        ida.detect_id().unwrap();
+        ida.calc_ic(IcOption::YaYdpInit, 0.4).unwrap();
+        assert_nearly_eq!(ida.get_yy()[2], 0.0, 1e-8);
+    }
+
+    #[test]
+    fn test_solve_dq_jacobian() {
+        let (yy0, yp0) = (array![1., 0., 0.], array![-0.04, 0.04, 0.]);
+        let mut dq = Ida::new(Wrapped::dq(Roberts::default()), yy0.clone(), yp0.clone());
+        let mut analytic = Ida::new(Roberts::default(), yy0, yp0);
+        dq.sv_tolerances(1e-4, array![1e-8, 1e-6, 1e-6]).unwrap();
+        analytic
+            .sv_tolerances(1e-4, array![1e-8, 1e-6, 1e-6])
+            .unwrap();
+
+        for &tout in &[0.4, 4.0, 40.0] {
+            dq.solve(tout, Task::Normal).unwrap();
+            analytic.solve(tout, Task::Normal).unwrap();
+            let y = analytic.get_yy();
+            assert_nearly_eq!((&dq.get_yy() - &y) / y, Array::zeros(3), 1e-3);
+        }
+
+        // One residual call per column in each setup, all counted in nre
+        assert_eq!(dq.ida_nre, dq.f.nres);
+        assert!(dq.ida_nre >= dq.ida_nni + 3 * dq.ida_nsetups);
+    }
+
+    #[test]
+    fn test_solve_auto_jacobian() {
+        let y0 = array![1., 1., 1.];
+        let f = Lorenz63::default();
+        let mut yp0 = Array::zeros(3);
+        f.rhs(&y0, &mut yp0);
+
+        // The Jacobian computed with dual numbers is exact, so the integration is the same as
+        // with the hand-coded one
+        let mut auto = Ida::new(AutoJacobian::new(f), y0.clone(), yp0.clone());
+        let mut hand = Ida::new(f, y0, yp0);
+        auto.ss_tolerances(1e-8, 1e-10).unwrap();
+        hand.ss_tolerances(1e-8, 1e-10).unwrap();
+        for &tout in &[0.1, 0.2, 0.5] {
+            auto.solve(tout, Task::Normal).unwrap();
+            hand.solve(tout, Task::Normal).unwrap();
+            assert_nearly_eq!(auto.get_yy().to_owned(), hand.get_yy().to_owned(), 1e-12);
+        }
+        assert_eq!(auto.ida_nst, hand.ida_nst);
+        assert_eq!(auto.ida_nre, hand.ida_nre);
+    }
+
+    /// Lorenz system whose residual reports a recoverable error for `fails` evaluations past
+    /// `t_fail`, as if evaluated at an invalid state.
+    #[derive(Clone, Debug)]
+    struct FlakyLorenz63 {
+        inner: Lorenz63,
+        t_fail: f64,
+        fails: usize,
+    }
+
+    impl ModelSpec for FlakyLorenz63 {
+        type Scalar = f64;
+        type Dim = Ix1;
+
+        fn model_size(&self) -> usize {
+            3
+        }
+    }
+
+    impl IdaModel for FlakyLorenz63 {
+        fn residual<S1, S2, S3>(
+            &mut self,
+            tt: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            yp: &ArrayBase<S2, Ix1>,
+            rr: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            if tt >= self.t_fail && self.fails > 0 {
+                self.fails -= 1;
+                return Err(ModelError::Recoverable {});
+            }
+            self.inner.residual(tt, yy, yp, rr)
+        }
+
+        fn jacobian<S1, S2, S3, S4>(
+            &mut self,
+            tt: f64,
+            cj: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            yp: &ArrayBase<S2, Ix1>,
+            rr: &ArrayBase<S3, Ix1>,
+            jac: &mut ArrayBase<S4, Ix2>,
+        ) -> Option<Result<(), ModelError>>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: Data<Elem = f64>,
+            S4: DataMut<Elem = f64>,
+        {
+            self.inner.jacobian(tt, cj, yy, yp, rr, jac)
+        }
+    }
+
+    #[test]
+    fn test_recoverable_residual() {
+        let y0 = array![1., 1., 1.];
+        let f = Lorenz63::default();
+        let mut yp0 = Array::zeros(3);
+        f.rhs(&y0, &mut yp0);
+
+        // A few failures are recovered from by reducing the step size
+        let flaky = FlakyLorenz63 {
+            inner: f,
+            t_fail: 0.05,
+            fails: 3,
+        };
+        let mut ida = Ida::new(flaky, y0.clone(), yp0.clone());
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        ida.solve(0.1, Task::Normal).unwrap();
+        assert_eq!(ida.ida_ncfn, 3);
+        let y = lorenz63_rk4(&f, &y0, 0.1, 10000);
+        assert_nearly_eq!(ida.get_yy().to_owned(), y, 1e-4);
+
+        // Repeated failures are fatal
+        let flaky = FlakyLorenz63 {
+            inner: f,
+            t_fail: 0.0,
+            fails: usize::MAX,
+        };
+        let mut ida = Ida::new(flaky, y0.clone(), yp0.clone());
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        let err = ida.solve(0.1, Task::Normal).unwrap_err();
+        match err.downcast::<IdaError>().unwrap() {
+            IdaError::RepeatedResidualError {} => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(ida.ida_ncfn, 10);
+
+        // A failure on the first residual evaluation of calc_ic() is fatal
+        let flaky = FlakyLorenz63 {
+            inner: f,
+            t_fail: 0.0,
+            fails: 1,
+        };
+        let mut ida = Ida::new(flaky, y0, yp0);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        let err = ida.calc_ic(IcOption::YInit, 0.1).unwrap_err();
+        match err.downcast::<IdaError>().unwrap() {
+            IdaError::FirstResidualFail {} => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    /// Scalar model `a*y' + k*y + c = 0`, without a Jacobian
+    #[derive(Clone, Copy, Debug)]
+    struct Decay {
+        a: f64,
+        k: f64,
+        c: f64,
+    }
+
+    impl ModelSpec for Decay {
+        type Scalar = f64;
+        type Dim = Ix1;
+
+        fn model_size(&self) -> usize {
+            1
+        }
+    }
+
+    impl IdaModel for Decay {
+        fn residual<S1, S2, S3>(
+            &mut self,
+            _tt: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            yp: &ArrayBase<S2, Ix1>,
+            rr: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            rr[0] = self.a * yp[0] + self.k * yy[0] + self.c;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_set_options() {
+        let decay = Decay {
+            a: 1.0,
+            k: 1.0,
+            c: 0.0,
+        };
+        let mut ida = Ida::new(decay, array![1.0], array![-1.0]);
+        ida.ss_tolerances(1e-6, 1e-8).unwrap();
+        assert_eq!(ida.options(), IdaOptions::default());
+        assert_eq!(ida.options().hmax, f64::INFINITY);
+
+        // Invalid options are rejected, and leave all options unchanged
+        let invalid = vec![
+            (
+                IdaOptions {
+                    maxord: 6,
+                    ..Default::default()
+                },
+                "maxord",
+            ),
+            (
+                IdaOptions {
+                    maxord: 0,
+                    ..Default::default()
+                },
+                "maxord",
+            ),
+            (
+                IdaOptions {
+                    hmax: -1.0,
+                    ..Default::default()
+                },
+                "hmax",
+            ),
+            (
+                IdaOptions {
+                    hmin: -1.0,
+                    ..Default::default()
+                },
+                "hmin",
+            ),
+            (
+                IdaOptions {
+                    hmin: 2.0,
+                    hmax: 1.0,
+                    mxstep: 10,
+                    ..Default::default()
+                },
+                "hmin > hmax",
+            ),
+            (
+                IdaOptions {
+                    maxcor: 0,
+                    ..Default::default()
+                },
+                "maxcor",
+            ),
+            (
+                IdaOptions {
+                    epcon: 0.0,
+                    ..Default::default()
+                },
+                "epcon",
+            ),
+            (
+                IdaOptions {
+                    steptol: f64::NAN,
+                    ..Default::default()
+                },
+                "steptol",
+            ),
+        ];
+        for (options, name) in invalid {
+            match ida.set_options(options).unwrap_err().downcast::<IdaError>() {
+                Ok(IdaError::IllegalInput { ref msg }) if msg.contains(name) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+            assert_eq!(ida.options(), IdaOptions::default());
+        }
+
+        // The order and step size stay within their limits
+        let mut options = ida.options();
+        options.maxord = 2;
+        options.hmax = 0.01;
+        options.hin = 0.001;
+        ida.set_options(options.clone()).unwrap();
+        assert_eq!(ida.options(), options);
+        let mut limited = ida.clone();
+        while limited.ida_tn < 1.0 {
+            limited.solve(1.0, Task::OneStep).unwrap();
+            assert!(limited.ida_kused <= 2);
+            assert!(limited.ida_hused <= 0.01 * (1.0 + 1e-12));
+        }
+        assert_eq!(limited.ida_h0u, 0.001);
+        assert!(limited.ida_nst >= 100);
+
+        // A step size that cannot be reduced below hmin fails the error test
+        options.hin = 1.0;
+        options.hmax = f64::INFINITY;
+        options.hmin = 0.5;
+        ida.set_options(options).unwrap();
+        match ida
+            .solve(10.0, Task::Normal)
+            .unwrap_err()
+            .downcast::<IdaError>()
+        {
+            Ok(IdaError::ErrorTestFail {}) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(ida.ida_netf, 2);
+        assert_eq!(ida.ida_hh, 0.5);
+    }
+
+    #[test]
+    fn test_initial_step() {
+        // A fast decay, for which 0.001 * |tout - t0| would be far too large a first step
+        let decay = Decay {
+            a: 1.0,
+            k: 1000.0,
+            c: 0.0,
+        };
+        let mut ida = Ida::new(decay, array![1.0], array![-1000.0]);
+        ida.ss_tolerances(1e-6, 1e-8).unwrap();
+        let mut fixed = ida.clone();
+
+        ida.solve(10.0, Task::OneStep).unwrap();
+        let ypnorm = 1000.0 / (1e-6 + 1e-8);
+        assert_nearly_eq!(ida.ida_h0u, 0.5 / ypnorm, 1e-15);
+        assert_eq!(ida.ida_netf, 0);
+
+        // A user-supplied initial step overrides the estimate
+        let mut options = fixed.options();
+        options.hin = 0.01;
+        fixed.set_options(options).unwrap();
+        fixed.solve(10.0, Task::OneStep).unwrap();
+        assert!(fixed.ida_netf > 0);
+
+        // Without a derivative, the step is limited by tout - t0 and hmax, in the direction of tout
+        let decay = Decay {
+            a: 1.0,
+            k: 0.0,
+            c: 0.0,
+        };
+        let mut ida = Ida::new(decay, array![1.0], array![0.0]);
+        ida.ss_tolerances(1e-6, 1e-8).unwrap();
+        let mut limited = ida.clone();
+        ida.solve(-10.0, Task::OneStep).unwrap();
+        assert_eq!(ida.ida_h0u, -0.01);
+
+        let mut options = limited.options();
+        options.hmax = 0.001;
+        limited.set_options(options).unwrap();
+        limited.solve(10.0, Task::OneStep).unwrap();
+        assert_eq!(limited.ida_h0u, 0.001);
+    }
+
+    #[test]
+    fn test_constraints() {
+        let decay = Decay {
+            a: 1.0,
+            k: 10.0,
+            c: 0.0,
+        };
+        let mut ida = Ida::new(decay, array![1.0], array![-10.0]);
+        ida.ss_tolerances(1e-1, 1e-1).unwrap();
+        assert!(ida.set_constraints(array![]).is_err());
+        assert!(!ida.ida_constraintsSet);
+
+        // Without constraints, the loose tolerances let the solution undershoot zero
+        let mut unconstrained = ida.clone();
+        let mut positive = ida.clone();
+        let mut ymin = f64::MAX;
+        while unconstrained.ida_tn < 10.0 {
+            unconstrained.solve(10.0, Task::OneStep).unwrap();
+            ymin = ymin.min(unconstrained.get_yy()[0]);
+        }
+        assert!(ymin < 0.0);
+
+        ida.set_constraints(array![Constraint::NonNegative])
+            .unwrap();
+        assert!(ida.ida_constraintsSet);
+        let mut zeros = 0;
+        while ida.ida_tn < 10.0 {
+            ida.solve(10.0, Task::OneStep).unwrap();
+            assert!(ida.get_yy()[0] >= 0.0);
+            zeros += (ida.get_yy()[0] == 0.0) as usize;
+        }
+        // The undershoots are small, so they are corrected onto the bound rather than rejecting
+        // the step
+        assert!(zeros > 0);
+        assert_eq!(ida.ida_ncfn, 0);
+
+        // For y > 0, the correction moves y just inside the bound
+        positive
+            .set_constraints(array![Constraint::Positive])
+            .unwrap();
+        while positive.ida_tn < 10.0 {
+            positive.solve(10.0, Task::OneStep).unwrap();
+            assert!(positive.get_yy()[0] > 0.0);
+        }
+        assert_eq!(positive.ida_ncfn, 0);
+
+        // y0 must satisfy the constraints
+        let mut ida = Ida::new(decay, array![0.0], array![0.0]);
+        ida.ss_tolerances(1e-4, 1e-6).unwrap();
+        ida.set_constraints(array![Constraint::Positive]).unwrap();
+        let err = ida.solve(1.0, Task::Normal).unwrap_err();
+        match err.downcast::<IdaError>().unwrap() {
+            IdaError::IllegalInput { ref msg } if msg.contains("constraints") => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // The algebraic equation y = -1 cannot satisfy the constraint y > 0 for any step size
+        let negative = Decay {
+            a: 0.0,
+            k: 1.0,
+            c: 1.0,
+        };
+        let mut ida = Ida::new(negative, array![1.0], array![0.0]);
+        ida.ss_tolerances(1e-4, 1e-6).unwrap();
+        ida.set_constraints(array![Constraint::Positive]).unwrap();
+        let err = ida.solve(1.0, Task::Normal).unwrap_err();
+        match err.downcast::<IdaError>().unwrap() {
+            IdaError::ConstraintFail {} => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(ida.ida_ncfn, 10);
+        assert_eq!(ida.ida_nst, 0);
+    }
+
+    /// `y' = -y`, with the root functions `y - 0.5`, `t - 1` and `y - 0.1`
+    #[derive(Clone, Copy, Debug)]
+    struct ExpRoots;
+
+    impl ModelSpec for ExpRoots {
+        type Scalar = f64;
+        type Dim = Ix1;
+
+        fn model_size(&self) -> usize {
+            1
+        }
+    }
+
+    impl IdaModel for ExpRoots {
+        fn residual<S1, S2, S3>(
+            &mut self,
+            _tt: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            yp: &ArrayBase<S2, Ix1>,
+            rr: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            rr[0] = yp[0] + yy[0];
+            Ok(())
+        }
+
+        fn num_roots(&self) -> usize {
+            3
+        }
+
+        fn roots<S1, S2, S3>(
+            &mut self,
+            tt: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            _yp: &ArrayBase<S2, Ix1>,
+            gout: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            gout[0] = yy[0] - 0.5;
+            gout[1] = tt - 1.0;
+            gout[2] = yy[0] - 0.1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_solve_roots() {
+        let mut ida = Ida::new(ExpRoots, array![1.0], array![-1.0]);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+
+        let expected = [(2f64.ln(), 0, -1), (1.0, 1, 1), (10f64.ln(), 2, -1)];
+        for &(troot, i, dir) in &expected {
+            let (tret, status) = ida.solve(3.0, Task::Normal).unwrap();
+            assert_eq!(status, SolveStatus::RootReturn(vec![i]));
+            assert_nearly_eq!(tret, troot, 1e-6);
+            assert_nearly_eq!(ida.get_yy()[0], (-tret).exp(), 1e-6);
+            assert_eq!(ida.get_root_info()[i], dir);
+        }
+        let (tret, status) = ida.solve(3.0, Task::Normal).unwrap();
+        assert_eq!((tret, status), (3.0, SolveStatus::Success));
+        assert!(ida.ida_nge > 0);
+
+        // Only increasing crossings of g_0 and g_2 are sought, so only t = 1 is found. In the
+        // OneStep mode, the step past the root is returned by the next call.
+        let mut ida = Ida::new(ExpRoots, array![1.0], array![-1.0]);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        assert!(ida.set_root_direction(array![1, 1]).is_err());
+        ida.set_root_direction(array![1, 0, 1]).unwrap();
+        let mut roots = Vec::new();
+        let mut tlast = 0.0;
+        while ida.ida_tn < 3.0 {
+            let (tret, status) = ida.solve(3.0, Task::OneStep).unwrap();
+            assert!(tret > tlast);
+            tlast = tret;
+            if let SolveStatus::RootReturn(i) = status {
+                roots.push((tret, i));
+                assert!(tret < ida.ida_tn);
+            }
+        }
+        assert_eq!(roots.len(), 1);
+        assert_nearly_eq!(roots[0].0, 1.0, 1e-8);
+        assert_eq!(roots[0].1, vec![1]);
+
+        // A zero of g_0 at t0 is not reported as a root
+        let mut ida = Ida::new(ExpRoots, array![0.5], array![-0.5]);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        let (tret, status) = ida.solve(3.0, Task::Normal).unwrap();
+        assert_eq!(status, SolveStatus::RootReturn(vec![1]));
+        assert_nearly_eq!(tret, 1.0, 1e-8);
+
+        // Models without root functions
+        let mut ida = Ida::new(Lorenz63::default(), array![1.0, 1.0, 1.0], Array::zeros(3));
+        assert!(ida.set_root_direction(array![]).is_err());
+    }
+
+    /// Ball bouncing on the ground, with y = (height, velocity) and the root function height
+    #[derive(Clone, Copy, Debug)]
+    struct BouncingBall;
+
+    impl ModelSpec for BouncingBall {
+        type Scalar = f64;
+        type Dim = Ix1;
+
+        fn model_size(&self) -> usize {
+            2
+        }
+    }
+
+    impl IdaModel for BouncingBall {
+        fn residual<S1, S2, S3>(
+            &mut self,
+            _tt: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            yp: &ArrayBase<S2, Ix1>,
+            rr: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            rr[0] = yp[0] - yy[1];
+            rr[1] = yp[1] + 9.81;
+            Ok(())
+        }
+
+        fn num_roots(&self) -> usize {
+            1
+        }
+
+        fn roots<S1, S2, S3>(
+            &mut self,
+            _tt: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            _yp: &ArrayBase<S2, Ix1>,
+            gout: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            gout[0] = yy[0];
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reinit_bouncing_ball() {
+        let mut ida = Ida::new(BouncingBall, array![1.0, 0.0], array![0.0, -9.81]);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        ida.set_root_direction(array![-1]).unwrap();
+        ida.set_event_fn(|_, _, roots, mut yy, mut yp| {
+            assert_eq!(roots, &[0]);
+            // Reverse the velocity with a coefficient of restitution of 0.5
+            yy[0] = 0.0;
+            yy[1] *= -0.5;
+            yp[0] = yy[1];
+            Ok(EventAction::Reinit)
+        });
+
+        // The ball hits the ground at t1 = sqrt(2/g), and then after flights of t1 and t1/2
+        let t1 = (2.0 / 9.81f64).sqrt();
+        for &(timpact, vbounce) in &[
+            (t1, 4.905 * t1),
+            (2.0 * t1, 2.4525 * t1),
+            (2.5 * t1, 1.22625 * t1),
+        ] {
+            let (tret, status) = ida.solve(2.0, Task::Normal).unwrap();
+            assert_eq!(status, SolveStatus::RootReturn(vec![0]));
+            assert_nearly_eq!(tret, timpact, 1e-6);
+            assert_nearly_eq!(ida.get_yy().to_owned(), array![0.0, vbounce], 1e-6);
+            assert_eq!(ida.get_yp()[0], ida.get_yy()[1]);
+            // The integration restarts at the impact
+            assert_eq!(ida.ida_nst, 0);
+            assert_eq!(ida.ida_tn, tret);
+        }
+
+        // Between impacts, the ball reaches the height v^2/2g
+        let tout = ida.ida_tn + 0.125 * t1;
+        let (tret, status) = ida.solve(tout, Task::Normal).unwrap();
+        assert_eq!((tret, status), (tout, SolveStatus::Success));
+        assert_nearly_eq!(ida.get_yy()[0], 0.015625, 1e-6);
+        assert!(ida.reinit(0.0, array![1.0], array![0.0, -9.81]).is_err());
+    }
+
+    /// `x' = -q`, with the algebraic `q = k*x` for the discrete mode k, switched from 0 to 1 at
+    /// the root of `t - 1`
+    #[derive(Clone, Copy, Debug)]
+    struct Switched {
+        k: f64,
+    }
+
+    impl ModelSpec for Switched {
+        type Scalar = f64;
+        type Dim = Ix1;
+
+        fn model_size(&self) -> usize {
+            2
+        }
+    }
+
+    impl IdaModel for Switched {
+        fn residual<S1, S2, S3>(
+            &mut self,
+            _tt: f64,
+            yy: &ArrayBase<S1, Ix1>,
+            yp: &ArrayBase<S2, Ix1>,
+            rr: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            rr[0] = yp[0] + yy[1];
+            rr[1] = yy[1] - self.k * yy[0];
+            Ok(())
+        }
+
+        fn num_roots(&self) -> usize {
+            1
+        }
+
+        fn roots<S1, S2, S3>(
+            &mut self,
+            tt: f64,
+            _yy: &ArrayBase<S1, Ix1>,
+            _yp: &ArrayBase<S2, Ix1>,
+            gout: &mut ArrayBase<S3, Ix1>,
+        ) -> Result<(), ModelError>
+        where
+            S1: Data<Elem = f64>,
+            S2: Data<Elem = f64>,
+            S3: DataMut<Elem = f64>,
+        {
+            gout[0] = tt - 1.0;
+            Ok(())
+        }
+    }
 
-        assert_nearly_eq!(ida.ida_tn, saved_t);
-        assert_eq!(ida.ida_ns, 1);
-        assert_eq!(ida.ida_kk, 4);
-        assert_nearly_eq!(ida.ida_cvals, cvals_after, 1e-6);
-        assert_nearly_eq!(ida.ida_beta, beta_after, 1e-6);
-        assert_nearly_eq!(ida.ida_psi, psi_after, 1e-6);
-        assert_nearly_eq!(ida.ida_phi, phi_after, 1e-6);
+    #[test]
+    fn test_event_mode_switch() {
+        let mut ida = Ida::new(Switched { k: 0.0 }, array![1.0, 0.0], array![0.0, 0.0]);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        ida.set_id(array![true, false]).unwrap();
+        ida.set_event_fn(|f, _, _, _, _| {
+            f.k = 1.0;
+            Ok(EventAction::ReinitCalcIc(IcOption::YaYdpInit))
+        });
+
+        // At the switch, the algebraic q and x' are made consistent with the new mode
+        let (tret, status) = ida.solve(2.0, Task::Normal).unwrap();
+        assert_eq!(status, SolveStatus::RootReturn(vec![0]));
+        assert_nearly_eq!(tret, 1.0, 1e-8);
+        assert_nearly_eq!(ida.get_yy().to_owned(), array![1.0, 1.0], 1e-8);
+        assert_nearly_eq!(ida.get_yp()[0], -1.0, 1e-8);
+
+        let (tret, status) = ida.solve(2.0, Task::Normal).unwrap();
+        assert_eq!((tret, status), (2.0, SolveStatus::Success));
+        let x = (-1f64).exp();
+        assert_nearly_eq!(ida.get_yy().to_owned(), array![x, x], 1e-6);
     }
 
     #[test]
-    fn test_restore3() {
-        let saved_t = 13638904.64873992;
-        let phi_before = array![
-            [
-                0.0001523741818966069,
-                6.095884948264652e-10,
-                0.9998476252085154,
-            ],
-            [
-                -1.964117218731689e-05,
-                -7.858910051867137e-11,
-                1.964125077907938e-05,
-            ],
-            [
-                4.048658569496216e-06,
-                1.620249912028008e-11,
-                -4.048674765925692e-06,
-            ],
-            [
-                -1.215165175266232e-06,
-                -4.863765573523665e-12,
-                1.21517004866448e-06,
-            ],
-            [
-                4.909710408845208e-07,
-                1.965778579990634e-12,
-                -4.909729965008022e-07,
-            ],
-            [
-                -2.529640523993838e-07,
-                -1.012593011825966e-12,
-                2.529650614751456e-07,
-            ],
-        ];
-        let psi_before = array![
-            1656116.685489699,
-            2484175.028234549,
-            3312233.370979399,
-            4140291.713724249,
-            5060356.538996303,
-            5520388.951632331
-        ];
-        let cvals_before = array![1., 1., 1., 1., 1., 1.];
-        let beta_before = array![1., 2., 3., 4., 4.864864864864866, 6.370656370656372];
-        //--- IDARestore After: saved_t=  13638904.64873992 tn=  13638904.64873992 ns=1 kk=5
-        let phi_after = array![
-            [
-                0.0001523741818966069,
-                6.095884948264652e-10,
-                0.9998476252085154,
-            ],
-            [
-                -9.820586093658443e-06,
-                -3.929455025933569e-11,
-                9.820625389539692e-06,
-            ],
-            [
-                1.349552856498739e-06,
-                5.400833040093358e-12,
-                -1.349558255308564e-06,
-            ],
-            [
-                -3.037912938165579e-07,
-                -1.215941393380916e-12,
-                3.0379251216612e-07,
-            ],
-            [
-                1.009218250707071e-07,
-                4.040767081091857e-13,
-                -1.009222270584982e-07,
-            ],
-            [
-                -3.970769064935782e-08,
-                -1.589464182199546e-13,
-                3.970784904367437e-08,
-            ],
-        ];
-        let psi_after = array![
-            828058.3427448499,
-            1656116.685489699,
-            2484175.02823455,
-            3404239.853506604,
-            3864272.266142632,
-            5520388.951632331,
-        ];
-        let cvals_after = array![
-            0.5,
-            0.3333333333333333,
-            0.25,
-            0.2055555555555555,
-            0.156969696969697,
-            1.,
-        ];
-        let beta_after = array![1., 2., 3., 4., 4.864864864864866, 6.370656370656372];
+    fn test_event_mode_switch_at_tout() {
+        let mut ida = Ida::new(Switched { k: 0.0 }, array![1.0, 0.0], array![0.0, 0.0]);
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        ida.set_event_fn(|f, _, _, _, _| {
+            f.k = 1.0;
+            Ok(EventAction::ReinitCalcIc(IcOption::YaYdpInit))
+        });
+
+        // Without the component types, the IC calculation is rejected before the state is reset
+        let mut no_id = ida.clone();
+        let err = no_id.solve(1.0, Task::Normal).unwrap_err();
+        match err.downcast::<IdaError>().unwrap() {
+            IdaError::IllegalInput { ref msg } if msg.contains("id = NULL") => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(no_id.ida_nst > 0);
+
+        // A time event exactly at tout switches the mode there
+        ida.set_id(array![true, false]).unwrap();
+        let (tret, status) = ida.solve(1.0, Task::Normal).unwrap();
+        assert_eq!(status, SolveStatus::RootReturn(vec![0]));
+        assert_nearly_eq!(tret, 1.0, 1e-8);
+        assert_nearly_eq!(ida.get_yy().to_owned(), array![1.0, 1.0], 1e-8);
+        assert_nearly_eq!(ida.get_yp()[0], -1.0, 1e-8);
+
+        // The integration continues from the switch, also in OneStep mode
+        while ida.ida_tn < 2.0 {
+            ida.solve(2.0, Task::OneStep).unwrap();
+        }
+        let (tret, status) = ida.solve(2.0, Task::Normal).unwrap();
+        assert_eq!((tret, status), (2.0, SolveStatus::Success));
+        let x = (-1f64).exp();
+        assert_nearly_eq!(ida.get_yy().to_owned(), array![x, x], 1e-6);
+    }
 
-        let f = Lorenz63::default();
-        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
+    /// Sets up the idaHeat2D_bnd example on a 10x10 grid, with the linear solver `ls`
+    fn heat2d_ida<LS: LinearSolver<Heat2D>>(ls: LS) -> Ida<Heat2D, LS> {
+        let mut f = Heat2D::new(10);
+        let (uu, up) = f.initial_values();
+        let mut ida = Ida::with_linear_solver(f, uu, up, ls);
+        ida.ss_tolerances(0.0, 1e-3).unwrap();
+        ida.set_id(f.id()).unwrap();
+        ida.ida_suppressalg = true;
+        ida
+    }
 
-        // Set preconditions:
-        ida.ida_tn = 15295021.33422961;
-        ida.ida_ns = 1;
-        ida.ida_kk = 5;
-        ida.ida_hh = 1656116.685489699;
-        ida.ida_phi.assign(&phi_before);
-        ida.ida_psi.assign(&psi_before);
-        ida.ida_cvals.assign(&cvals_before);
-        ida.ida_beta.assign(&beta_before);
+    #[test]
+    fn test_solve_heat2d_band() {
+        let mu = Heat2D::new(10).bandwidth();
+        let mut band = heat2d_ida(Band::new(mu, mu));
+        let mut dense = heat2d_ida(Dense::new());
+
+        // Reference values of max(u) from the output of the Sundials idaHeat2D_bnd example
+        for &(tout, umax) in &[(0.01, 8.24106e-01), (0.02, 6.88134e-01)] {
+            band.solve(tout, Task::Normal).unwrap();
+            dense.solve(tout, Task::Normal).unwrap();
+            assert_nearly_eq!(band.get_yy().to_owned(), dense.get_yy().to_owned(), 1e-12);
+
+            let max = band.get_yy().fold(0.0f64, |max, &u| max.max(u.abs()));
+            assert_nearly_eq!(max, umax, 1e-4);
+        }
+        assert_eq!(band.ida_nst, dense.ida_nst);
+    }
 
-        // Call the function under test
-        ida.restore(saved_t);
+    #[test]
+    fn test_solve_heat2d_sparse() {
+        let mut sparse = heat2d_ida(SparseLu::new());
+        let mut dense = heat2d_ida(Dense::new());
+
+        for &tout in &[0.01, 0.02, 0.04] {
+            sparse.solve(tout, Task::Normal).unwrap();
+            dense.solve(tout, Task::Normal).unwrap();
+            assert_nearly_eq!(sparse.get_yy().to_owned(), dense.get_yy().to_owned(), 1e-12);
+        }
+        assert_eq!(sparse.ida_nst, dense.ida_nst);
+        assert_eq!(sparse.ida_nsetups, dense.ida_nsetups);
+    }
 
-        assert_nearly_eq!(ida.ida_tn, saved_t);
-        assert_eq!(ida.ida_ns, 1);
-        assert_eq!(ida.ida_kk, 5);
-        assert_nearly_eq!(ida.ida_cvals, cvals_after, 1e-6);
-        assert_nearly_eq!(ida.ida_beta, beta_after, 1e-6);
-        assert_nearly_eq!(ida.ida_psi, psi_after, 1e-6);
-        assert_nearly_eq!(ida.ida_phi, phi_after, 1e-6);
+    /// Sets up the idaHeat2D_bnd example as in `heat2d_ida()`, without analytic Jacobians
+    fn dq_heat2d_ida<LS: LinearSolver<Wrapped<Heat2D>>>(ls: LS) -> Ida<Wrapped<Heat2D>, LS> {
+        let mut inner = Heat2D::new(10);
+        let (uu, up) = inner.initial_values();
+        let mut ida = Ida::with_linear_solver(Wrapped::dq(inner), uu, up, ls);
+        ida.ss_tolerances(0.0, 1e-3).unwrap();
+        ida.set_id(inner.id()).unwrap();
+        ida.ida_suppressalg = true;
+        ida
     }
 
     #[test]
-    fn test_complete_step() {
-        let err_k = 0.1022533962984153;
-        let err_km1 = 0.3638660854770704;
-        let ida_phi = array![
-            [0.0000001057015204, 0.0000000000004228, 0.9999998942980568,],
-            [-0.0000000330821964, -0.0000000000001323, 0.0000000330823287,],
-            [0.0000000186752739, 0.0000000000000747, -0.0000000186753488,],
-            [-0.0000000199565018, -0.0000000000000798, 0.0000000199565809,],
-            [0.0000000012851942, 0.0000000000000051, -0.0000000012851948,],
-            [-0.0000000002242367, -0.0000000000000009, 0.0000000002242247,],
-        ];
-        let ida_ee = array![-0.0000000051560075, -0.0000000000000206, 0.0000000051560285,];
-        let ida_ewt = array![
-            99894410.0897681862115860,
-            999999.9999577193520963,
-            9900.9911352019826154,
-        ];
-        let kk = 2;
-        let kused = 2;
-        let knew = 2;
-        let phase = 1;
-        let hh = 3774022770.1406540870666504;
-        let hused = 4313148194.5176315307617188;
-        let rr = 0.8750041964562566;
-        let hmax_inv = 0.0000000000000000;
-        let nst = 357;
-        let maxord = 5;
+    fn test_solve_heat2d_compressed_dq() {
+        let mu = Heat2D::new(10).bandwidth();
+        let mut band = dq_heat2d_ida(Band::new(mu, mu));
+        let mut sparse = dq_heat2d_ida(SparseLu::new());
+        let mut dense = heat2d_ida(Dense::new());
+
+        for &tout in &[0.01, 0.02, 0.04] {
+            band.solve(tout, Task::Normal).unwrap();
+            sparse.solve(tout, Task::Normal).unwrap();
+            dense.solve(tout, Task::Normal).unwrap();
+            assert_nearly_eq!(band.get_yy().to_owned(), dense.get_yy().to_owned(), 1e-6);
+            assert_nearly_eq!(sparse.get_yy().to_owned(), dense.get_yy().to_owned(), 1e-6);
+        }
 
-        // Set preconditions:
+        // Each setup takes one residual call per color group instead of one per column: the
+        // 5-point stencil needs at most 10 groups with coloring, and 2*m + 1 with the band.
+        assert_eq!(sparse.ida_nre, sparse.f.nres);
+        assert!(sparse.ida_nre <= sparse.ida_nni + 10 * sparse.ida_nsetups);
+        assert_eq!(band.ida_nre, band.f.nres);
+        assert_eq!(band.ida_nre, band.ida_nni + 21 * band.ida_nsetups);
+    }
+
+    #[test]
+    fn test_solve_heat2d_spgmr() {
+        let mut spgmr = heat2d_ida(Spgmr::new());
+        let mut dense = heat2d_ida(Dense::new());
+
+        // The solutions agree to within the integration tolerance
+        for &tout in &[0.01, 0.02, 0.04] {
+            spgmr.solve(tout, Task::Normal).unwrap();
+            dense.solve(tout, Task::Normal).unwrap();
+            assert_nearly_eq!(spgmr.get_yy().to_owned(), dense.get_yy().to_owned(), 1e-3);
+        }
+        assert!(spgmr.ida_nli >= spgmr.ida_nni);
+        assert_eq!(dense.ida_nli, 0);
+        assert_eq!(dense.ida_ncfl, 0);
+        assert_eq!(spgmr.ida_ls.num_jtimes(), spgmr.ida_ls.num_res_evals());
+        assert_eq!(spgmr.ida_npe, 0);
+        assert_eq!(spgmr.ida_nps, 0);
+    }
+
+    #[test]
+    fn test_solve_heat2d_spgmr_reduced() {
+        // With a small Krylov subspace and no restarts, GMRES often only reduces the residual
+        let mut ls = Spgmr::new();
+        ls.set_maxl(3);
+        ls.set_max_restarts(0);
+        let mut spgmr = heat2d_ida(ls);
+        let mut dense = heat2d_ida(Dense::new());
+
+        spgmr.solve(0.04, Task::Normal).unwrap();
+        dense.solve(0.04, Task::Normal).unwrap();
+        assert_nearly_eq!(spgmr.get_yy().to_owned(), dense.get_yy().to_owned(), 1e-3);
+
+        // A reduced residual on the first Newton iteration is accepted rather than failing the
+        // step, so most linear convergence failures do not cause a nonlinear one
+        assert!(spgmr.ida_ncfl > 2 * spgmr.ida_ncfn);
+    }
+
+    #[test]
+    fn test_solve_heat2d_preconditioned() {
+        let mut ilu = heat2d_ida(Spgmr::with_preconditioner(Ilu0::new()));
+        let mut bj = heat2d_ida(Spgmr::with_preconditioner(BlockJacobi::new(10)));
+        let mut spgmr = heat2d_ida(Spgmr::new());
+        let mut dense = heat2d_ida(Dense::new());
+
+        // The solutions agree to within the integration tolerance
+        for &tout in &[0.01, 0.02, 0.04] {
+            ilu.solve(tout, Task::Normal).unwrap();
+            bj.solve(tout, Task::Normal).unwrap();
+            spgmr.solve(tout, Task::Normal).unwrap();
+            dense.solve(tout, Task::Normal).unwrap();
+            assert_nearly_eq!(ilu.get_yy().to_owned(), dense.get_yy().to_owned(), 2e-3);
+            assert_nearly_eq!(bj.get_yy().to_owned(), dense.get_yy().to_owned(), 2e-3);
+        }
+
+        // The preconditioner is set up along with the linear solver, and applied once per
+        // linear iteration plus once for the initial residual.
+        let stats = |ida_npe, ida_nps, ida_nsetups, ida_nli, ida_nni| {
+            assert_eq!(ida_npe, ida_nsetups);
+            assert_eq!(ida_nps, ida_nli + ida_nni);
+        };
+        stats(
+            ilu.ida_npe,
+            ilu.ida_nps,
+            ilu.ida_nsetups,
+            ilu.ida_nli,
+            ilu.ida_nni,
+        );
+        stats(
+            bj.ida_npe,
+            bj.ida_nps,
+            bj.ida_nsetups,
+            bj.ida_nli,
+            bj.ida_nni,
+        );
+        // Preconditioning reduces the number of linear iterations
+        assert!(ilu.ida_nli < spgmr.ida_nli);
+        assert!(bj.ida_nli < spgmr.ida_nli);
+    }
+
+    #[test]
+    fn test_solve_heat2d_bbd() {
+        // Two subdomains of 5 grid rows, keeping the tridiagonal part of each block
+        let mut f = Heat2D::new(10);
+        f.nsub = 2;
+        let m = f.mgrid;
+        let (uu, up) = f.initial_values();
+        let mut bbd =
+            Ida::with_linear_solver(f, uu, up, Spgmr::with_preconditioner(Bbd::new(m, m, 1, 1)));
+        bbd.ss_tolerances(0.0, 1e-3).unwrap();
+        bbd.set_id(f.id()).unwrap();
+        bbd.ida_suppressalg = true;
+
+        let mut spgmr = heat2d_ida(Spgmr::new());
+        let mut dense = heat2d_ida(Dense::new());
+
+        for &tout in &[0.01, 0.02, 0.04] {
+            bbd.solve(tout, Task::Normal).unwrap();
+            spgmr.solve(tout, Task::Normal).unwrap();
+            dense.solve(tout, Task::Normal).unwrap();
+            assert_nearly_eq!(bbd.get_yy().to_owned(), dense.get_yy().to_owned(), 2e-3);
+        }
+
+        // Each setup takes min(2*m + 1, 50) + 1 evaluations of G on each subdomain
+        assert_eq!(bbd.ida_npe, bbd.ida_nsetups);
+        assert_eq!(
+            bbd.ida_ls.prec().unwrap().num_gfn_evals(),
+            bbd.ida_npe * 2 * 22
+        );
+        assert!(bbd.ida_nli < spgmr.ida_nli);
+    }
+
+    #[test]
+    fn test_ewt_set() {
         let f = Lorenz63::default();
-        let mut ida = Ida::new(f, array![0., 0., 0.], array![0., 0., 0.]);
-        ida.ida_nst = nst;
-        ida.ida_kk = kk;
-        ida.ida_hh = hh;
-        ida.ida_rr = rr;
-        ida.ida_kused = kused;
-        ida.ida_hused = hused;
-        ida.ida_knew = knew;
-        ida.ida_maxord = maxord;
-        ida.ida_phase = phase;
-        ida.ida_hmax_inv = hmax_inv;
-        ida.ida_ee.assign(&ida_ee);
-        ida.ida_phi.assign(&ida_phi);
-        ida.ida_ewt.assign(&ida_ewt);
-        //ida.ida_Xvecs.assign(&ida_Xvecs);
-        //ida.ida_Zvecs.assign(&ida_Zvecs);
+        let mut ida = Ida::new(f, array![1., -2., 0.], array![0., 0., 0.]);
 
-        ida.complete_step(err_k, err_km1);
+        // No tolerances specified
+        match ida.ewt_set().unwrap_err().downcast::<IdaError>() {
+            Ok(IdaError::IllegalInput { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
 
-        let ida_phi = array![
-            [0.0000000861385903, 0.0000000000003446, 0.9999999138610652,],
-            [-0.0000000195629300, -0.0000000000000783, 0.0000000195630084,],
-            [0.0000000135192664, 0.0000000000000541, -0.0000000135193203,],
-            [-0.0000000051560075, -0.0000000000000206, 0.0000000051560285,],
-            [0.0000000012851942, 0.0000000000000051, -0.0000000012851948,],
-            [-0.0000000002242367, -0.0000000000000009, 0.0000000002242247,],
-        ];
-        let ida_ee = array![-0.0000000051560075, -0.0000000000000206, 0.0000000051560285,];
-        let ida_ewt = array![
-            99894410.0897681862115860,
-            999999.9999577193520963,
-            9900.9911352019826154,
-        ];
-        let kk = 2;
-        let kused = 2;
-        let knew = 2;
-        let phase = 1;
-        let hh = 3774022770.1406540870666504;
-        let hused = 3774022770.1406540870666504;
-        let rr = 1.6970448397793398;
-        let hmax_inv = 0.0000000000000000;
-        let nst = 358;
-        let maxord = 5;
+        ida.ss_tolerances(1e-3, 1e-6).unwrap();
+        ida.ewt_set().unwrap();
+        let ewt = array![1. / (1e-3 + 1e-6), 1. / (2e-3 + 1e-6), 1e6];
+        assert_nearly_eq!(ida.ida_ewt, ewt, 1e-6);
+
+        ida.sv_tolerances(1e-3, array![1e-6, 1e-2, 1e-12]).unwrap();
+        ida.ewt_set().unwrap();
+        let ewt = array![1. / (1e-3 + 1e-6), 1. / (2e-3 + 1e-2), 1e12];
+        assert_nearly_eq!(ida.ida_ewt, ewt, 1e-6);
+
+        ida.wf_tolerances(|_, y, mut ewt| {
+            ewt.assign(&y.mapv(|y| 1. / (1. + y * y)));
+            Ok(())
+        });
+        ida.ewt_set().unwrap();
+        assert_nearly_eq!(ida.ida_ewt, array![0.5, 0.2, 1.], 1e-12);
+
+        // A zero or negative weight is illegal
+        ida.ss_tolerances(1e-3, 0.).unwrap();
+        match ida.ewt_set().unwrap_err().downcast::<IdaError>() {
+            Ok(IdaError::BadErrorWeightVector {}) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        ida.wf_tolerances(|_, _, mut ewt| {
+            ewt.fill(-1.);
+            Ok(())
+        });
+        match ida
+            .solve(1.0, Task::Normal)
+            .unwrap_err()
+            .downcast::<IdaError>()
+        {
+            Ok(IdaError::BadErrorWeightVector {}) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
 
-        assert_eq!(ida.ida_nst, nst);
-        assert_eq!(ida.ida_kk, kk);
-        assert_eq!(ida.ida_hh, hh);
-        assert_nearly_eq!(ida.ida_rr, rr, 1e-6);
-        assert_eq!(ida.ida_kused, kused);
-        assert_eq!(ida.ida_hused, hused);
-        assert_eq!(ida.ida_knew, knew);
-        assert_eq!(ida.ida_maxord, maxord);
-        assert_eq!(ida.ida_phase, phase);
-        assert_eq!(ida.ida_hmax_inv, hmax_inv);
-        assert_nearly_eq!(ida.ida_ee, ida_ee, 1e-6);
-        assert_nearly_eq!(ida.ida_phi, ida_phi, 1e-6);
-        assert_nearly_eq!(ida.ida_ewt, ida_ewt, 1e-6);
+        // Negative tolerances are rejected
+        assert!(ida.ss_tolerances(-1., 1.).is_err());
+        assert!(ida.ss_tolerances(1., -1.).is_err());
+        assert!(ida.sv_tolerances(1., array![1., -1., 1.]).is_err());
+        assert!(ida.sv_tolerances(1., array![1., 1.]).is_err());
+    }
+
+    #[test]
+    fn test_calc_ic_ya_ydp_init() {
+        let y0 = array![1., 2., 3.];
+        let f = Lorenz63::default();
+        let mut yp = Array::zeros(3);
+        f.rhs(&y0, &mut yp);
+
+        // All components are differential, so y is kept and y' = f(y) is computed.
+        let mut ida = Ida::new(f, y0.clone(), Array::zeros(3));
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+
+        let err = ida.calc_ic(IcOption::YaYdpInit, 0.1).unwrap_err();
+        match err.downcast::<IdaError>().unwrap() {
+            IdaError::IllegalInput { .. } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        ida.set_id(array![true, true, true]).unwrap();
+        ida.calc_ic(IcOption::YaYdpInit, 0.1).unwrap();
+        assert_eq!(ida.get_yy(), y0);
+        assert_nearly_eq!(ida.get_yp().to_owned(), yp, 1e-6);
+
+        // The consistent values are used to start the integration.
+        ida.solve(0.1, Task::Normal).unwrap();
+        let y = lorenz63_rk4(&f, &y0, 0.1, 10000);
+        assert_nearly_eq!(ida.get_yy().to_owned(), y, 1e-4);
+    }
+
+    #[test]
+    fn test_calc_ic_y_init() {
+        let y = array![1., 2., 3.];
+        let f = Lorenz63::default();
+        let mut yp0 = Array::zeros(3);
+        f.rhs(&y, &mut yp0);
+
+        // Given y' = f(y), recover y from a nearby initial guess.
+        let mut ida = Ida::new(f, array![1.1, 2.1, 2.9], yp0.clone());
+        ida.ss_tolerances(1e-8, 1e-10).unwrap();
+        ida.calc_ic(IcOption::YInit, 0.1).unwrap();
+        assert_nearly_eq!(ida.get_yy().to_owned(), y, 1e-6);
+        assert_eq!(ida.get_yp(), yp0);
+        assert!(ida.ida_nni > 0);
+
+        let err = ida.calc_ic(IcOption::YInit, 0.0).unwrap_err();
+        match err.downcast::<IdaError>().unwrap() {
+            IdaError::IllegalInput { .. } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
     }
 
     #[test]
@@ -1629,8 +5526,8 @@ mod tests {
         ida.ida_phi.assign(&ida_phi);
         ida.ida_psi.assign(&ida_psi);
 
-        let mut yret = Array::zeros((3));
-        let mut ypret = Array::zeros((3));
+        let mut yret = Array::zeros(3);
+        let mut ypret = Array::zeros(3);
 
         ida.get_solution(t, &mut yret, &mut ypret).unwrap();
 
@@ -1,6 +1,16 @@
+// `failure_derive` expands to impls nested inside a const block
+#![allow(non_local_definitions)]
+
+pub mod dual;
+pub mod heat2d;
 pub mod ida;
+pub mod linear;
 pub mod lorenz63;
+pub mod roberts;
 pub mod traits;
 
+#[cfg(test)]
+mod testing;
+
 #[cfg(test)]
 mod tests {}
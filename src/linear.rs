@@ -0,0 +1,150 @@
+//! Linear solvers for the Newton iteration
+//!
+//! Each Newton iteration in `Ida` solves a linear system with the iteration matrix
+//! `J = dF/dy + cj*dF/dy'`. The solver is set up (e.g. the matrix is evaluated and factored)
+//! whenever the nonlinear solver requests it, and the factorization is reused for the solves
+//! until the next setup. Iterative solvers such as `Spgmr` instead solve each system only to
+//! a tolerance tied to that of the Newton iteration.
+
+use ndarray::*;
+
+use failure::Fail;
+
+use crate::ida::Constraint;
+use crate::traits::*;
+
+pub mod band;
+pub mod bbd;
+pub mod check;
+pub mod coloring;
+pub mod dense;
+pub mod pattern;
+pub mod precond;
+pub mod sparse;
+pub mod spgmr;
+
+pub use self::band::{Band, BandMatrix};
+pub use self::bbd::Bbd;
+pub use self::check::{check_jacobian, JacobianCheck, JacobianEntry};
+pub use self::coloring::CompressedDq;
+pub use self::dense::Dense;
+pub use self::pattern::{JacobianPattern, Pattern};
+pub use self::precond::{BlockJacobi, Ilu0, ModelPrec, NoPrec, Preconditioner};
+pub use self::sparse::{SparseLu, SparseMatrix};
+pub use self::spgmr::Spgmr;
+
+/// Errors returned by the `LinearSolver` routines
+#[derive(Debug, Fail)]
+pub enum LinearSolverError {
+    /// A recoverable error, e.g. a singular iteration matrix. The solver retries the step with a
+    /// smaller step size.
+    #[fail(display = "The linear solver had a recoverable error")]
+    Recoverable {},
+
+    /// A non-recoverable error, which stops the integration.
+    #[fail(display = "The linear solver had a non-recoverable error")]
+    Fatal {},
+
+    /// An iterative solver did not converge, but reduced the norm of the residual
+    /// (SUNLS_RES_REDUCED). The approximate solution is accepted on the first Newton iteration,
+    /// and is a recoverable error otherwise.
+    #[fail(display = "The linear solver did not converge, but reduced the residual")]
+    ResidualReduced {},
+}
+
+/// The current state of the nonlinear iteration, passed to the `LinearSolver` routines
+#[derive(Debug)]
+pub struct LinearContext<'a, T> {
+    /// current value of t
+    pub tt: T,
+    /// scalar cj in the iteration matrix `dF/dy + cj*dF/dy'`
+    pub cj: T,
+    /// current step size
+    pub hh: T,
+    /// current y vector
+    pub yy: ArrayView1<'a, T>,
+    /// current y' vector
+    pub yp: ArrayView1<'a, T>,
+    /// current residual F(tt, yy, yp)
+    pub rr: ArrayView1<'a, T>,
+    /// error weight vector
+    pub ewt: ArrayView1<'a, T>,
+    /// tolerance of the Newton iteration, which iterative solvers scale for their own
+    /// convergence test
+    pub eps_newt: T,
+    /// inequality constraints on the components of y, if any are set
+    pub constraints: Option<ArrayView1<'a, Constraint>>,
+}
+
+impl<'a, T: num_traits::Float> LinearContext<'a, T> {
+    /// Increment to y_j for the difference quotient Jacobians (as in idaLsDenseDQJac):
+    /// `max(sqrt(uround) * max(|y_j|, |h*y'_j|), 1/ewt_j)`, with the sign of h*y'_j, or the
+    /// opposite sign if the perturbed y_j would violate its constraint.
+    pub(crate) fn dq_increment(&self, j: usize) -> T {
+        let (yj, ypj) = (self.yy[j], self.yp[j]);
+        let srur = T::epsilon().sqrt();
+        let mut inc = (srur * yj.abs().max((self.hh * ypj).abs())).max(self.ewt[j].recip());
+        if self.hh * ypj < T::zero() {
+            inc = -inc;
+        }
+        if let Some(constraints) = self.constraints {
+            if !constraints[j].is_satisfied(yj + inc) {
+                inc = -inc;
+            }
+        }
+        (yj + inc) - yj
+    }
+}
+
+/// Linear solver interface (linit, lsetup and lsolve in IDA)
+pub trait LinearSolver<F: IdaModel>: Clone + std::fmt::Debug {
+    /// Initializes the solver for the problem given by `model`, before the integration starts.
+    fn init(&mut self, model: &F) -> Result<(), LinearSolverError>;
+
+    /// Prepares for subsequent calls to `solve()` with the iteration matrix at `ctx`, for example
+    /// by evaluating and factoring it.
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError>;
+
+    /// Solves the linear system `J x = b` with the iteration matrix from the last `setup()`,
+    /// overwriting b with the solution x.
+    fn solve(
+        &mut self,
+        model: &mut F,
+        b: ArrayViewMut1<F::Scalar>,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError>;
+
+    /// Whether the solution of `solve()` is to be scaled by `2/(1 + cj/cjold)` when the solver
+    /// works with the iteration matrix for a previous value cjold of cj. This is the case for
+    /// solvers that evaluate the matrix in `setup()`, whereas matrix-free solvers use the current
+    /// cj in `solve()`.
+    fn scale_correction(&self) -> bool {
+        true
+    }
+
+    /// Number of linear iterations performed by the last call to `solve()` (SUNLinSolNumIters).
+    /// Direct solvers perform none.
+    fn num_iters(&self) -> usize {
+        0
+    }
+
+    /// Number of residual evaluations performed by the last call to `setup()`, e.g. for a
+    /// difference quotient Jacobian
+    fn num_setup_res_evals(&self) -> usize {
+        0
+    }
+
+    /// Number of preconditioner setups performed by the last call to `setup()`
+    fn num_prec_setups(&self) -> usize {
+        0
+    }
+
+    /// Number of preconditioner solves performed by the last call to `solve()`
+    fn num_prec_solves(&self) -> usize {
+        0
+    }
+}
@@ -0,0 +1,347 @@
+//! Banded direct linear solver, using LU factorization with partial pivoting
+
+use ndarray::*;
+
+use super::{CompressedDq, LinearContext, LinearSolver, LinearSolverError};
+use crate::traits::*;
+
+/// Banded matrix storage (SUNBandMatrix)
+///
+/// Only the elements A[i,j] with `-mu <= i - j <= ml` are stored. Each column additionally has
+/// room for `smu - mu` elements above the band, used for the fill-in of the LU factorization.
+#[derive(Clone, Debug)]
+pub struct BandMatrix<T> {
+    /// upper half-bandwidth
+    mu: usize,
+    /// lower half-bandwidth
+    ml: usize,
+    /// storage upper bandwidth, `min(n - 1, mu + ml)`
+    smu: usize,
+    /// data[[j, i - j + smu]] = A[i, j]
+    data: Array<T, Ix2>,
+}
+
+impl<T: num_traits::Float> BandMatrix<T> {
+    /// Creates a new n by n zero matrix with upper and lower half-bandwidths mu and ml.
+    pub fn new(n: usize, mu: usize, ml: usize) -> Self {
+        let smu = (mu + ml).min(n.saturating_sub(1));
+        BandMatrix {
+            mu,
+            ml,
+            smu,
+            data: Array::zeros((n, smu + ml + 1)),
+        }
+    }
+
+    /// Number of rows and columns
+    pub fn size(&self) -> usize {
+        self.data.rows()
+    }
+
+    /// Upper half-bandwidth
+    pub fn mu(&self) -> usize {
+        self.mu
+    }
+
+    /// Lower half-bandwidth
+    pub fn ml(&self) -> usize {
+        self.ml
+    }
+
+    /// Returns true if A[i,j] lies within the band
+    pub fn in_band(&self, i: usize, j: usize) -> bool {
+        i < self.size() && j < self.size() && i + self.mu >= j && j + self.ml >= i
+    }
+
+    /// Returns A[i,j], or zero outside of the band
+    pub fn get(&self, i: usize, j: usize) -> T {
+        if self.in_band(i, j) {
+            self.data[[j, i + self.smu - j]]
+        } else {
+            T::zero()
+        }
+    }
+
+    /// Sets A[i,j] = v
+    ///
+    /// *Panics* if A[i,j] lies outside of the band.
+    pub fn set(&mut self, i: usize, j: usize, v: T) {
+        assert!(
+            self.in_band(i, j),
+            "A[{}, {}] is outside of the band (mu = {}, ml = {})",
+            i,
+            j,
+            self.mu,
+            self.ml
+        );
+        self.data[[j, i + self.smu - j]] = v;
+    }
+
+    /// Sets all elements to zero
+    pub fn fill_zero(&mut self) {
+        self.data.fill(T::zero());
+    }
+
+    /// Returns the product `A x`
+    pub fn dot<S: Data<Elem = T>>(&self, x: &ArrayBase<S, Ix1>) -> Array<T, Ix1> {
+        let n = self.size();
+        let mut y = Array::zeros(n);
+        for j in 0..n {
+            for i in j.saturating_sub(self.mu)..n.min(j + self.ml + 1) {
+                y[i] = y[i] + self.get(i, j) * x[j];
+            }
+        }
+        y
+    }
+
+    /// LU factorization with partial pivoting in place (bandGBTRF). The row pivots are stored
+    /// in `p`, and the multipliers are stored negated below the diagonal.
+    ///
+    /// Returns the (1-based) column of the first zero pivot as an error.
+    pub(crate) fn gbtrf(&mut self, p: &mut Array<usize, Ix1>) -> Result<(), usize> {
+        let n = self.size();
+        let (smu, ml) = (self.smu, self.ml);
+        let a = &mut self.data;
+
+        // zero out the first smu - mu rows of the storage
+        for c in 0..n {
+            for r in 0..smu - self.mu {
+                a[[c, r]] = T::zero();
+            }
+        }
+
+        // k = elimination step number
+        for k in 0..n.saturating_sub(1) {
+            let last_row_k = (n - 1).min(k + ml);
+
+            // find l = pivot row number
+            let mut l = k;
+            let mut max = a[[k, smu]].abs();
+            for i in k + 1..=last_row_k {
+                if a[[k, i - k + smu]].abs() > max {
+                    l = i;
+                    max = a[[k, i - k + smu]].abs();
+                }
+            }
+            p[k] = l;
+
+            // check for zero pivot element
+            if a[[k, l - k + smu]] == T::zero() {
+                return Err(k + 1);
+            }
+
+            // swap a(l,k) and a(k,k) if necessary
+            let swap = l != k;
+            if swap {
+                a.swap([k, l - k + smu], [k, smu]);
+            }
+
+            // Scale the elements below the diagonal in column k by -1.0 / a(k,k).
+            let mult = -a[[k, smu]].recip();
+            for i in k + 1..=last_row_k {
+                a[[k, i - k + smu]] = a[[k, i - k + smu]] * mult;
+            }
+
+            // row_i -= [a(i,k)/a(k,k)] row_k, i=k+1, ..., last_row_k, where row k is the pivot
+            // row after swapping with row l.
+            let last_col_k = (k + smu).min(n - 1);
+            for j in k + 1..=last_col_k {
+                let storage_l = l + smu - j;
+                let storage_k = k + smu - j;
+                let a_kj = a[[j, storage_l]];
+
+                // Swap the elements a(k,j) and a(l,j) if l != k.
+                if swap {
+                    a[[j, storage_l]] = a[[j, storage_k]];
+                    a[[j, storage_k]] = a_kj;
+                }
+
+                // a(i,j) = a(i,j) - [a(i,k)/a(k,k)]*a(k,j)
+                if a_kj != T::zero() {
+                    for i in k + 1..=last_row_k {
+                        a[[j, i + smu - j]] = a[[j, i + smu - j]] + a_kj * a[[k, i - k + smu]];
+                    }
+                }
+            }
+        }
+
+        // set the last pivot row to be n-1 and check for a zero pivot
+        if n > 0 {
+            p[n - 1] = n - 1;
+            if a[[n - 1, smu]] == T::zero() {
+                return Err(n);
+            }
+        }
+        Ok(())
+    }
+
+    /// Solves `A x = b` using the LU factors computed by `gbtrf()`, overwriting b with the
+    /// solution x (bandGBTRS).
+    pub(crate) fn gbtrs<S: DataMut<Elem = T>>(
+        &self,
+        p: &Array<usize, Ix1>,
+        b: &mut ArrayBase<S, Ix1>,
+    ) {
+        let n = self.size();
+        let (smu, ml) = (self.smu, self.ml);
+        let a = &self.data;
+
+        // Solve Ly = Pb, store solution y in b
+        for k in 0..n.saturating_sub(1) {
+            let l = p[k];
+            let mult = b[l];
+            if l != k {
+                b[l] = b[k];
+                b[k] = mult;
+            }
+            let last_row_k = (n - 1).min(k + ml);
+            for i in k + 1..=last_row_k {
+                b[i] = b[i] + mult * a[[k, i - k + smu]];
+            }
+        }
+
+        // Solve Ux = y, store solution x in b
+        for k in (0..n).rev() {
+            let first_row_k = k.saturating_sub(smu);
+            b[k] = b[k] / a[[k, smu]];
+            let mult = -b[k];
+            for i in first_row_k..k {
+                b[i] = b[i] + mult * a[[k, i + smu - k]];
+            }
+        }
+    }
+}
+
+/// Banded LU linear solver (SUNLinSol_Band)
+///
+/// The iteration matrix is evaluated by `BandJacobian::jacobian_band()`, or approximated by
+/// difference quotients of `IdaModel::residual()` if the model does not supply it, and factored
+/// in place.
+#[derive(Clone, Debug)]
+pub struct Band<T> {
+    /// iteration matrix, overwritten by its LU factors in `setup()`
+    mat: BandMatrix<T>,
+    /// row pivots of the LU factorization
+    pivots: Array<usize, Ix1>,
+    /// difference quotient approximation of the iteration matrix
+    dq: CompressedDq<T>,
+    /// number of residual calls by the last call to `setup()`
+    nre: usize,
+}
+
+impl<T: num_traits::Float> Band<T> {
+    /// Creates a new solver for an iteration matrix with upper and lower half-bandwidths mu and
+    /// ml. The storage is allocated by `init()`.
+    pub fn new(mu: usize, ml: usize) -> Self {
+        Band {
+            mat: BandMatrix::new(0, mu, ml),
+            pivots: Array::zeros(0),
+            dq: CompressedDq::banded(0, mu, ml),
+            nre: 0,
+        }
+    }
+}
+
+impl<F: BandJacobian> LinearSolver<F> for Band<F::Scalar>
+where
+    F::Scalar: std::fmt::Debug,
+{
+    fn init(&mut self, model: &F) -> Result<(), LinearSolverError> {
+        let n = model.model_size();
+        if self.mat.mu() >= n.max(1) || self.mat.ml() >= n.max(1) {
+            return Err(LinearSolverError::Fatal {});
+        }
+        self.mat = BandMatrix::new(n, self.mat.mu(), self.mat.ml());
+        self.pivots = Array::zeros(n);
+        self.dq = CompressedDq::banded(n, self.mat.mu(), self.mat.ml());
+        Ok(())
+    }
+
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.mat.fill_zero();
+        self.nre = 0;
+        let retval =
+            match model.jacobian_band(ctx.tt, ctx.cj, &ctx.yy, &ctx.yp, &ctx.rr, &mut self.mat) {
+                Some(retval) => retval,
+                None => {
+                    let mat = &mut self.mat;
+                    let retval = self.dq.jacobian(model, ctx, |i, j, v| mat.set(i, j, v));
+                    self.nre = self.dq.num_res_evals();
+                    retval
+                }
+            };
+        match retval {
+            Ok(()) => {}
+            Err(ModelError::Recoverable {}) => return Err(LinearSolverError::Recoverable {}),
+            Err(ModelError::Fatal {}) => return Err(LinearSolverError::Fatal {}),
+        }
+        self.mat
+            .gbtrf(&mut self.pivots)
+            .map_err(|_| LinearSolverError::Recoverable {})
+    }
+
+    fn solve(
+        &mut self,
+        _model: &mut F,
+        mut b: ArrayViewMut1<F::Scalar>,
+        _ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.mat.gbtrs(&self.pivots, &mut b);
+        Ok(())
+    }
+
+    fn num_setup_res_evals(&self) -> usize {
+        self.nre
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nearly_eq::*;
+
+    /// Tridiagonal-plus matrix with mu = 1, ml = 2, where pivoting is required
+    fn test_matrix(n: usize) -> BandMatrix<f64> {
+        let mut a = BandMatrix::new(n, 1, 2);
+        for j in 0..n {
+            for i in j.saturating_sub(1)..n.min(j + 3) {
+                a.set(i, j, if i == j { 0.1 } else { (i + 2 * j + 1) as f64 });
+            }
+        }
+        a
+    }
+
+    #[test]
+    fn test_band_lu() {
+        let n = 7;
+        let a = test_matrix(n);
+        let x = Array::from_shape_fn(n, |i| 1.0 - 0.5 * i as f64);
+        let mut b = a.dot(&x);
+
+        let mut lu = a.clone();
+        let mut p = Array::zeros(n);
+        lu.gbtrf(&mut p).unwrap();
+        assert!(p.iter().enumerate().any(|(k, &pk)| pk != k));
+        lu.gbtrs(&p, &mut b);
+        assert_nearly_eq!(b, x, 1e-12);
+    }
+
+    #[test]
+    fn test_band_matrix() {
+        let mut a = BandMatrix::new(4, 1, 0);
+        a.set(0, 1, 2.0);
+        a.set(3, 3, 1.0);
+        assert!(a.in_band(0, 1));
+        assert!(!a.in_band(1, 0));
+        assert_eq!(a.get(0, 1), 2.0);
+        assert_eq!(a.get(1, 0), 0.0);
+        assert_eq!(a.dot(&array![1., 1., 1., 1.]), array![2., 0., 0., 1.]);
+
+        let mut p = Array::zeros(4);
+        assert_eq!(a.gbtrf(&mut p), Err(1));
+    }
+}
@@ -0,0 +1,224 @@
+//! Consistency check of model-supplied Jacobians against difference quotients
+
+use std::cmp::Ordering;
+
+use ndarray::*;
+
+use crate::traits::*;
+
+/// Comparison of one element of the iteration matrix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JacobianEntry<T> {
+    /// row index
+    pub row: usize,
+    /// column index
+    pub col: usize,
+    /// value from `IdaModel::jacobian()`
+    pub analytic: T,
+    /// central difference approximation
+    pub approx: T,
+    /// |analytic - approx|
+    pub abs_err: T,
+    /// abs_err relative to max(|analytic|, |approx|, floor), where the floor is sqrt(uround)
+    /// times the largest element of the matrix
+    pub rel_err: T,
+}
+
+/// Result of `check_jacobian()`
+#[derive(Clone, Debug)]
+pub struct JacobianCheck<T> {
+    /// all elements of the iteration matrix, ordered by decreasing rel_err, with NaN first
+    entries: Vec<JacobianEntry<T>>,
+}
+
+impl<T: num_traits::Float> JacobianCheck<T> {
+    /// The (at most) n elements with the largest relative errors, worst first. Elements with a NaN
+    /// relative error, from a NaN in the Jacobian or its approximation, are the worst.
+    pub fn worst(&self, n: usize) -> &[JacobianEntry<T>] {
+        &self.entries[..n.min(self.entries.len())]
+    }
+
+    /// Largest relative error of any element, or NaN if any relative error is NaN
+    pub fn max_rel_err(&self) -> T {
+        self.entries.first().map_or(T::zero(), |e| e.rel_err)
+    }
+
+    /// Largest absolute error of any element, or NaN if any absolute error is NaN
+    pub fn max_abs_err(&self) -> T {
+        self.entries.iter().fold(T::zero(), |max, e| {
+            if max.is_nan() || e.abs_err.is_nan() {
+                T::nan()
+            } else {
+                max.max(e.abs_err)
+            }
+        })
+    }
+
+    /// Whether every element agrees with its approximation to within the relative tolerance rtol
+    pub fn is_consistent(&self, rtol: T) -> bool {
+        self.max_rel_err() <= rtol
+    }
+}
+
+/// Compares the iteration matrix `dF/dy + cj*dF/dy'` from `IdaModel::jacobian()` at
+/// (tt, yy, yp) against a central difference approximation of the residual.
+///
+/// Column j is approximated by perturbing y_j by ±h_j and y'_j by ±cj*h_j together, with
+/// `h_j = uround^(1/3) * max(|y_j|, 1)`, which balances the O(h²) truncation error against the
+/// round-off error. Agreement to a relative error of about `uround^(1/2)` is to be expected
+/// for a correct Jacobian of a smooth residual, whereas a wrong element typically shows a
+/// relative error of order 1.
+///
+/// Returns `None` if the model does not supply the Jacobian.
+pub fn check_jacobian<F, T, S1, S2>(
+    model: &mut F,
+    tt: T,
+    cj: T,
+    yy: &ArrayBase<S1, Ix1>,
+    yp: &ArrayBase<S2, Ix1>,
+) -> Option<Result<JacobianCheck<T>, ModelError>>
+where
+    F: IdaModel<Scalar = T>,
+    T: num_traits::Float,
+    S1: Data<Elem = T>,
+    S2: Data<Elem = T>,
+{
+    let n = model.model_size();
+    let mut rr = Array::zeros(n);
+    if let Err(err) = model.residual(tt, yy, yp, &mut rr) {
+        return Some(Err(err));
+    }
+
+    let mut jac = Array::zeros((n, n));
+    if let Err(err) = model.jacobian(tt, cj, yy, yp, &rr, &mut jac)? {
+        return Some(Err(err));
+    }
+
+    Some(central_differences(model, tt, cj, yy, yp).map(|approx| {
+        let floor = T::epsilon().sqrt() * jac.fold(T::zero(), |max, &a| max.max(a.abs()));
+        let mut entries: Vec<_> = jac
+            .indexed_iter()
+            .map(|((row, col), &a)| {
+                let d = approx[[row, col]];
+                let abs_err = (a - d).abs();
+                let scale = a.abs().max(d.abs()).max(floor);
+                JacobianEntry {
+                    row,
+                    col,
+                    analytic: a,
+                    approx: d,
+                    abs_err,
+                    rel_err: if scale > T::zero() {
+                        abs_err / scale
+                    } else {
+                        T::zero()
+                    },
+                }
+            })
+            .collect();
+        entries.sort_by(|a, b| {
+            b.rel_err
+                .is_nan()
+                .cmp(&a.rel_err.is_nan())
+                .then_with(|| b.rel_err.partial_cmp(&a.rel_err).unwrap_or(Ordering::Equal))
+        });
+        JacobianCheck { entries }
+    }))
+}
+
+/// Central difference approximation of the iteration matrix, column by column
+fn central_differences<F, T, S1, S2>(
+    model: &mut F,
+    tt: T,
+    cj: T,
+    yy: &ArrayBase<S1, Ix1>,
+    yp: &ArrayBase<S2, Ix1>,
+) -> Result<Array<T, Ix2>, ModelError>
+where
+    F: IdaModel<Scalar = T>,
+    T: num_traits::Float,
+    S1: Data<Elem = T>,
+    S2: Data<Elem = T>,
+{
+    let n = model.model_size();
+    let scale = T::epsilon().cbrt();
+    let two = T::one() + T::one();
+    let mut approx = Array::zeros((n, n));
+    let mut ytemp = yy.to_owned();
+    let mut yptemp = yp.to_owned();
+    let mut rplus = Array::zeros(n);
+    let mut rminus = Array::zeros(n);
+
+    for j in 0..n {
+        let (yj, ypj) = (yy[j], yp[j]);
+        // Use an increment that is exactly representable as a difference of y_j values
+        let inc = (yj + scale * yj.abs().max(T::one())) - yj;
+
+        ytemp[j] = yj + inc;
+        yptemp[j] = ypj + cj * inc;
+        model.residual(tt, &ytemp, &yptemp, &mut rplus)?;
+
+        ytemp[j] = yj - inc;
+        yptemp[j] = ypj - cj * inc;
+        model.residual(tt, &ytemp, &yptemp, &mut rminus)?;
+
+        ytemp[j] = yj;
+        yptemp[j] = ypj;
+        Zip::from(approx.column_mut(j))
+            .and(&rplus)
+            .and(&rminus)
+            .apply(|a, &rp, &rm| *a = (rp - rm) / (two * inc));
+    }
+    Ok(approx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorenz63::Lorenz63;
+    use crate::testing::Wrapped;
+
+    #[test]
+    fn test_check_jacobian() {
+        let (yy, yp) = (array![1.0, 2.0, 3.0], array![0.5, -1.0, 2.0]);
+
+        let mut f = Lorenz63::default();
+        let check = check_jacobian(&mut f, 0.0, 10.0, &yy, &yp)
+            .unwrap()
+            .unwrap();
+        assert!(check.is_consistent(1e-6));
+        assert_eq!(check.worst(20).len(), 9);
+
+        // A sign error in the (1, 2) element stands out, with a relative error of 2
+        let mut f =
+            Wrapped::with_jacobian(Lorenz63::default(), |mut jac| jac[[1, 2]] = -jac[[1, 2]]);
+        let check = check_jacobian(&mut f, 0.0, 10.0, &yy, &yp)
+            .unwrap()
+            .unwrap();
+        assert!(!check.is_consistent(1e-6));
+        let worst = check.worst(2);
+        assert_eq!((worst[0].row, worst[0].col), (1, 2));
+        assert_eq!(worst[0].analytic, -1.0);
+        assert!((worst[0].rel_err - 2.0).abs() < 1e-6);
+        assert!((worst[0].abs_err - 2.0).abs() < 1e-6);
+        assert!(worst[1].rel_err < 1e-6);
+        assert_eq!(check.max_abs_err(), worst[0].abs_err);
+
+        // A NaN element is reported as the worst, rather than aborting the check
+        let mut f = Wrapped::with_jacobian(Lorenz63::default(), |mut jac| jac[[2, 0]] = f64::NAN);
+        let check = check_jacobian(&mut f, 0.0, 10.0, &yy, &yp)
+            .unwrap()
+            .unwrap();
+        assert!(!check.is_consistent(1e-6));
+        let worst = check.worst(2);
+        assert_eq!((worst[0].row, worst[0].col), (2, 0));
+        assert!(worst[0].rel_err.is_nan());
+        assert!(worst[1].rel_err < 1e-6);
+        assert!(check.max_rel_err().is_nan());
+        assert!(check.max_abs_err().is_nan());
+
+        // Models without a Jacobian cannot be checked
+        let mut f = Wrapped::dq(Lorenz63::default());
+        assert!(check_jacobian(&mut f, 0.0, 10.0, &yy, &yp).is_none());
+    }
+}
@@ -0,0 +1,224 @@
+//! Column coloring for compressed difference quotient Jacobians
+//!
+//! Columns of the iteration matrix that have no non-zeros in a common row (structurally
+//! orthogonal columns) can be approximated by difference quotients from a single residual
+//! evaluation, by perturbing all of them at once (Curtis, Powell and Reid). Partitioning the
+//! columns into such groups is a coloring of the column intersection graph, and for typical
+//! sparse models it takes a small number of groups independent of the problem size.
+
+use ndarray::*;
+
+use super::{LinearContext, SparseMatrix};
+use crate::traits::*;
+
+/// Greedy coloring of the columns of the pattern of `a`, considering the columns in order and
+/// assigning each the smallest color not used by a column with a non-zero in a common row.
+///
+/// Returns the columns of each color.
+pub fn color_columns<T: num_traits::Float>(a: &SparseMatrix<T>) -> Vec<Vec<usize>> {
+    let n = a.size();
+    let colptrs = a.colptrs();
+    let rowvals = a.rowvals();
+
+    // Columns with a non-zero in each row
+    let mut row_cols = vec![Vec::new(); n];
+    for j in 0..n {
+        for &i in &rowvals[colptrs[j]..colptrs[j + 1]] {
+            row_cols[i].push(j);
+        }
+    }
+
+    let none = usize::MAX;
+    let mut color = vec![none; n];
+    // forbidden[c] == j if color c is used by a column intersecting column j
+    let mut forbidden: Vec<usize> = Vec::new();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+
+    for j in 0..n {
+        for &i in &rowvals[colptrs[j]..colptrs[j + 1]] {
+            for &k in &row_cols[i] {
+                if color[k] != none {
+                    forbidden[color[k]] = j;
+                }
+            }
+        }
+
+        let c = (0..groups.len())
+            .find(|&c| forbidden[c] != j)
+            .unwrap_or_else(|| {
+                groups.push(Vec::new());
+                forbidden.push(none);
+                groups.len() - 1
+            });
+        color[j] = c;
+        groups[c].push(j);
+    }
+
+    groups
+}
+
+/// Compressed difference quotient approximation of a sparse iteration matrix
+///
+/// Each group of structurally orthogonal columns is perturbed together, with the increments of
+/// `LinearContext::dq_increment()`, so that the approximation takes one residual evaluation per
+/// group instead of one per column.
+#[derive(Clone, Debug)]
+pub struct CompressedDq<T> {
+    /// column pointers of the pattern
+    colptrs: Vec<usize>,
+    /// row indices of the pattern
+    rowvals: Vec<usize>,
+    /// groups of structurally orthogonal columns
+    groups: Vec<Vec<usize>>,
+    /// perturbed y
+    ytemp: Array<T, Ix1>,
+    /// perturbed y'
+    yptemp: Array<T, Ix1>,
+    /// residual at (ytemp, yptemp)
+    rtemp: Array<T, Ix1>,
+    /// number of residual calls by the last call to `jacobian()`
+    nre: usize,
+}
+
+impl<T: num_traits::Float> CompressedDq<T> {
+    /// Sets up the approximation for the pattern of `a`, with the groups from
+    /// `color_columns()`
+    pub fn new(a: &SparseMatrix<T>) -> Self {
+        Self::with_groups(
+            a.size(),
+            a.colptrs().to_vec(),
+            a.rowvals().to_vec(),
+            color_columns(a),
+        )
+    }
+
+    /// Sets up the approximation for an n by n banded matrix with half-bandwidths mu and ml.
+    /// Columns j with the same `j mod (mu + ml + 1)` are grouped together, as in
+    /// idaLsBandDQJac.
+    pub fn banded(n: usize, mu: usize, ml: usize) -> Self {
+        let mut colptrs = vec![0];
+        let mut rowvals = Vec::new();
+        for j in 0..n {
+            rowvals.extend(j.saturating_sub(mu)..(j + ml + 1).min(n));
+            colptrs.push(rowvals.len());
+        }
+
+        let width = mu + ml + 1;
+        let groups = (0..width.min(n))
+            .map(|group| (group..n).step_by(width).collect())
+            .collect();
+        Self::with_groups(n, colptrs, rowvals, groups)
+    }
+
+    fn with_groups(
+        n: usize,
+        colptrs: Vec<usize>,
+        rowvals: Vec<usize>,
+        groups: Vec<Vec<usize>>,
+    ) -> Self {
+        CompressedDq {
+            colptrs,
+            rowvals,
+            groups,
+            ytemp: Array::zeros(n),
+            yptemp: Array::zeros(n),
+            rtemp: Array::zeros(n),
+            nre: 0,
+        }
+    }
+
+    /// Number of groups, i.e. residual calls per approximation
+    pub fn num_groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Number of residual calls by the last call to `jacobian()`
+    pub fn num_res_evals(&self) -> usize {
+        self.nre
+    }
+
+    /// Approximates the iteration matrix at `ctx` by difference quotients of the residual,
+    /// calling `set(i, j, value)` for each element of the pattern.
+    pub fn jacobian<F: IdaModel<Scalar = T>>(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<T>,
+        mut set: impl FnMut(usize, usize, T),
+    ) -> Result<(), ModelError> {
+        self.nre = 0;
+        self.ytemp.assign(&ctx.yy);
+        self.yptemp.assign(&ctx.yp);
+
+        for group in &self.groups {
+            // Perturb all columns in the group
+            for &j in group {
+                let inc = ctx.dq_increment(j);
+                self.ytemp[j] = ctx.yy[j] + inc;
+                self.yptemp[j] = ctx.yp[j] + ctx.cj * inc;
+            }
+
+            let retval = model.residual(ctx.tt, &self.ytemp, &self.yptemp, &mut self.rtemp);
+            self.nre += 1;
+            retval?;
+
+            // Restore the columns, and form the difference quotients in the rows of each column
+            for &j in group {
+                self.ytemp[j] = ctx.yy[j];
+                self.yptemp[j] = ctx.yp[j];
+                let inc = ctx.dq_increment(j);
+                for &i in &self.rowvals[self.colptrs[j]..self.colptrs[j + 1]] {
+                    set(i, j, (self.rtemp[i] - ctx.rr[i]) / inc);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_columns() {
+        // 5-point stencil on a 10 by 10 grid
+        let m: usize = 10;
+        let mut pattern = Vec::new();
+        for loc in 0..m * m {
+            for &k in &[
+                loc.wrapping_sub(m),
+                loc.wrapping_sub(1),
+                loc,
+                loc + 1,
+                loc + m,
+            ] {
+                if k < m * m {
+                    pattern.push((loc, k));
+                }
+            }
+        }
+        let a = SparseMatrix::<f64>::from_pattern(m * m, pattern);
+        let groups = color_columns(&a);
+        assert!(groups.len() <= 10);
+
+        // Every column has exactly one color, and the columns of a color share no rows
+        let mut cols: Vec<usize> = groups.iter().flatten().cloned().collect();
+        cols.sort();
+        assert_eq!(cols, (0..m * m).collect::<Vec<_>>());
+        for group in &groups {
+            let mut rows: Vec<usize> = group
+                .iter()
+                .flat_map(|&j| a.rowvals()[a.colptrs()[j]..a.colptrs()[j + 1]].to_vec())
+                .collect();
+            let nrows = rows.len();
+            rows.sort();
+            rows.dedup();
+            assert_eq!(rows.len(), nrows);
+        }
+
+        // A tridiagonal matrix takes 3 colors
+        let tri = CompressedDq::<f64>::banded(10, 1, 1);
+        assert_eq!(tri.num_groups(), 3);
+    }
+}
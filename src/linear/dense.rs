@@ -0,0 +1,304 @@
+//! Dense direct linear solver, using LU factorization with partial pivoting
+
+use ndarray::*;
+
+use super::{LinearContext, LinearSolver, LinearSolverError};
+use crate::traits::*;
+
+/// Dense LU linear solver (SUNLinSol_Dense)
+///
+/// The iteration matrix is evaluated by `IdaModel::jacobian()`, or approximated by difference
+/// quotients of `IdaModel::residual()` if the model does not supply it, and factored in place.
+#[derive(Clone, Debug)]
+pub struct Dense<T> {
+    /// iteration matrix, overwritten by its LU factors in `setup()`
+    mat: Array<T, Ix2>,
+    /// row pivots of the LU factorization
+    pivots: Array<usize, Ix1>,
+    /// perturbed y for the difference quotients
+    ytemp: Array<T, Ix1>,
+    /// perturbed y' for the difference quotients
+    yptemp: Array<T, Ix1>,
+    /// residual at (ytemp, yptemp)
+    rtemp: Array<T, Ix1>,
+    /// number of residual calls by the last call to `setup()`
+    nre: usize,
+}
+
+impl<T: num_traits::Float> Default for Dense<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: num_traits::Float> Dense<T> {
+    /// Creates a new solver. The storage is allocated by `init()`.
+    pub fn new() -> Self {
+        Dense {
+            mat: Array::zeros((0, 0)),
+            pivots: Array::zeros(0),
+            ytemp: Array::zeros(0),
+            yptemp: Array::zeros(0),
+            rtemp: Array::zeros(0),
+            nre: 0,
+        }
+    }
+
+    /// Approximates the iteration matrix column by column with difference quotients of the
+    /// residual (idaLsDenseDQJac):
+    ///
+    /// `jac[.., j] = (F(tt, yy + inc e_j, yp + cj*inc e_j) - rr) / inc`,
+    ///
+    /// with the increment inc from `LinearContext::dq_increment()`.
+    fn dq_jac<F: IdaModel<Scalar = T>>(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<T>,
+    ) -> Result<(), ModelError> {
+        self.ytemp.assign(&ctx.yy);
+        self.yptemp.assign(&ctx.yp);
+
+        for j in 0..self.mat.cols() {
+            let (yj, ypj) = (ctx.yy[j], ctx.yp[j]);
+            let inc = ctx.dq_increment(j);
+
+            self.ytemp[j] = yj + inc;
+            self.yptemp[j] = ypj + ctx.cj * inc;
+            let retval = model.residual(ctx.tt, &self.ytemp, &self.yptemp, &mut self.rtemp);
+            self.nre += 1;
+            retval?;
+
+            Zip::from(self.mat.column_mut(j))
+                .and(&self.rtemp)
+                .and(&ctx.rr)
+                .apply(|jac, &rtemp, &rr| *jac = (rtemp - rr) / inc);
+
+            self.ytemp[j] = yj;
+            self.yptemp[j] = ypj;
+        }
+
+        Ok(())
+    }
+
+    /// LU factorization with partial pivoting of `mat` in place
+    fn getrf(&mut self) -> Result<(), LinearSolverError> {
+        getrf(&mut self.mat, &mut self.pivots)
+    }
+
+    /// Solves `A x = b` using the LU factors in `mat`, overwriting b with the solution x
+    fn getrs<S: DataMut<Elem = T>>(&self, b: &mut ArrayBase<S, Ix1>) {
+        getrs(&self.mat, &self.pivots, b)
+    }
+}
+
+impl<F: IdaModel> LinearSolver<F> for Dense<F::Scalar>
+where
+    F::Scalar: std::fmt::Debug,
+{
+    fn init(&mut self, model: &F) -> Result<(), LinearSolverError> {
+        let n = model.model_size();
+        self.mat = Array::zeros((n, n));
+        self.pivots = Array::zeros(n);
+        self.ytemp = Array::zeros(n);
+        self.yptemp = Array::zeros(n);
+        self.rtemp = Array::zeros(n);
+        Ok(())
+    }
+
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.nre = 0;
+        // mat holds the LU factors from the previous setup
+        self.mat.fill(<F::Scalar as num_traits::Zero>::zero());
+        let retval = match model.jacobian(ctx.tt, ctx.cj, &ctx.yy, &ctx.yp, &ctx.rr, &mut self.mat)
+        {
+            Some(retval) => retval,
+            None => self.dq_jac(model, ctx),
+        };
+        match retval {
+            Ok(()) => {}
+            Err(ModelError::Recoverable {}) => return Err(LinearSolverError::Recoverable {}),
+            Err(ModelError::Fatal {}) => return Err(LinearSolverError::Fatal {}),
+        }
+        self.getrf()
+    }
+
+    fn solve(
+        &mut self,
+        _model: &mut F,
+        mut b: ArrayViewMut1<F::Scalar>,
+        _ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.getrs(&mut b);
+        Ok(())
+    }
+
+    fn num_setup_res_evals(&self) -> usize {
+        self.nre
+    }
+}
+
+/// LU factorization with partial pivoting of `mat` in place (denseGETRF).
+///
+/// A zero pivot is reported as a recoverable failure, since a smaller step size (larger cj)
+/// makes the iteration matrix better conditioned.
+pub(crate) fn getrf<T: num_traits::Float>(
+    mat: &mut Array<T, Ix2>,
+    pivots: &mut Array<usize, Ix1>,
+) -> Result<(), LinearSolverError> {
+    let n = mat.rows();
+    for k in 0..n {
+        // Find the pivot row l
+        let mut l = k;
+        for i in k + 1..n {
+            if mat[[i, k]].abs() > mat[[l, k]].abs() {
+                l = i;
+            }
+        }
+        pivots[k] = l;
+
+        if mat[[l, k]] == T::zero() {
+            return Err(LinearSolverError::Recoverable {});
+        }
+
+        // Swap rows k and l
+        if l != k {
+            for j in 0..n {
+                mat.swap([k, j], [l, j]);
+            }
+        }
+
+        // Scale the elements below the diagonal in column k by 1/a(k,k)
+        let mult = mat[[k, k]].recip();
+        for i in k + 1..n {
+            mat[[i, k]] = mat[[i, k]] * mult;
+        }
+
+        // Row-reduce the remaining submatrix
+        for j in k + 1..n {
+            let a_kj = mat[[k, j]];
+            if a_kj != T::zero() {
+                for i in k + 1..n {
+                    mat[[i, j]] = mat[[i, j]] - a_kj * mat[[i, k]];
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Solves `A x = b` using the LU factors computed by `getrf()`, overwriting b with the
+/// solution x (denseGETRS).
+pub(crate) fn getrs<T: num_traits::Float, S: DataMut<Elem = T>>(
+    mat: &Array<T, Ix2>,
+    pivots: &Array<usize, Ix1>,
+    b: &mut ArrayBase<S, Ix1>,
+) {
+    let n = mat.rows();
+
+    // Permute b according to the row pivots
+    for k in 0..n {
+        let pk = pivots[k];
+        if pk != k {
+            b.swap(k, pk);
+        }
+    }
+
+    // Solve Ly = b, L unit lower triangular
+    for k in 0..n {
+        let bk = b[k];
+        for i in k + 1..n {
+            b[i] = b[i] - mat[[i, k]] * bk;
+        }
+    }
+
+    // Solve Ux = y, U upper triangular
+    for k in (0..n).rev() {
+        b[k] = b[k] / mat[[k, k]];
+        let bk = b[k];
+        for i in 0..k {
+            b[i] = b[i] - mat[[i, k]] * bk;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorenz63::Lorenz63;
+    use crate::testing::Wrapped;
+    use nearly_eq::*;
+
+    #[test]
+    fn test_dense_lu() {
+        let a = array![[1., 2., 0.], [4., 1., -1.], [2., 0., 3.]];
+        let x = array![1., -2., 0.5];
+
+        let mut lu = Dense::new();
+        lu.mat = a.clone();
+        lu.pivots = Array::zeros(3);
+        lu.getrf().unwrap();
+        assert_eq!(lu.pivots, array![1, 1, 2]);
+
+        let mut b = a.dot(&x);
+        lu.getrs(&mut b);
+        assert_nearly_eq!(b, x, 1e-14);
+    }
+
+    #[test]
+    fn test_dense_dq_jacobian() {
+        let mut model = Wrapped::dq(Lorenz63::default());
+        let yy = array![1.0, 2.0, 3.0];
+        let yp = array![0.5, -0.5, 1.0];
+        let ewt = array![1e4, 1e4, 1e4];
+        let cj = 20.0;
+        let mut rr = Array::zeros(3);
+        model.residual(0.0, &yy, &yp, &mut rr).unwrap();
+        let ctx = LinearContext {
+            tt: 0.0,
+            cj,
+            hh: 0.05,
+            yy: yy.view(),
+            yp: yp.view(),
+            rr: rr.view(),
+            ewt: ewt.view(),
+            eps_newt: 0.33,
+            constraints: None,
+        };
+
+        let mut jac = Array::zeros((3, 3));
+        model
+            .inner
+            .jacobian(0.0, cj, &yy, &yp, &rr, &mut jac)
+            .unwrap()
+            .unwrap();
+
+        let mut lu = Dense::new();
+        lu.init(&model).unwrap();
+        lu.setup(&mut model, &ctx).unwrap();
+        assert_eq!(
+            LinearSolver::<Wrapped<Lorenz63>>::num_setup_res_evals(&lu),
+            3
+        );
+
+        let x = array![1., -2., 0.5];
+        let mut b = jac.dot(&x);
+        lu.getrs(&mut b);
+        assert_nearly_eq!(b, x, 1e-6);
+    }
+
+    #[test]
+    fn test_dense_singular() {
+        let mut lu = Dense::new();
+        lu.mat = array![[1., 2.], [2., 4.]];
+        lu.pivots = Array::zeros(2);
+        match lu.getrf() {
+            Err(LinearSolverError::Recoverable {}) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Detection of the sparsity patterns of dF/dy and dF/dy'
+//!
+//! The patterns are found by probing: each component of y (and of y') is perturbed in turn, and
+//! the components of the residual that change are recorded. A derivative that happens to vanish
+//! at a particular point would be missed, so the probing is repeated at randomly perturbed base
+//! points around the given one, and the patterns are the union over all probes.
+
+use std::collections::BTreeSet;
+
+use ndarray::*;
+
+use crate::traits::*;
+
+/// Structural pattern of an n by n matrix, in compressed sparse column form
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    /// number of rows and columns
+    n: usize,
+    /// index into rowvals of the first entry of each column, and the number of entries
+    colptrs: Vec<usize>,
+    /// row index of each entry, sorted within each column
+    rowvals: Vec<usize>,
+}
+
+impl Pattern {
+    /// Creates a pattern from the set of row indices in each column
+    fn from_columns(cols: Vec<BTreeSet<usize>>) -> Self {
+        let mut colptrs = Vec::with_capacity(cols.len() + 1);
+        let mut rowvals = Vec::new();
+        colptrs.push(0);
+        for col in &cols {
+            rowvals.extend(col);
+            colptrs.push(rowvals.len());
+        }
+        Pattern {
+            n: cols.len(),
+            colptrs,
+            rowvals,
+        }
+    }
+
+    /// Number of rows and columns
+    pub fn size(&self) -> usize {
+        self.n
+    }
+
+    /// Number of entries in the pattern
+    pub fn nnz(&self) -> usize {
+        self.rowvals.len()
+    }
+
+    /// Index of the first entry of each column, of length n + 1
+    pub fn colptrs(&self) -> &[usize] {
+        &self.colptrs
+    }
+
+    /// Row index of each entry
+    pub fn rowvals(&self) -> &[usize] {
+        &self.rowvals
+    }
+
+    /// Whether (i, j) is in the pattern
+    pub fn contains(&self, i: usize, j: usize) -> bool {
+        self.rowvals[self.colptrs[j]..self.colptrs[j + 1]]
+            .binary_search(&i)
+            .is_ok()
+    }
+
+    /// The (row, col) positions in the pattern, by column
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..self.n).flat_map(move |j| {
+            self.rowvals[self.colptrs[j]..self.colptrs[j + 1]]
+                .iter()
+                .map(move |&i| (i, j))
+        })
+    }
+
+    /// The pattern as a dense boolean matrix
+    pub fn to_dense(&self) -> Array<bool, Ix2> {
+        let mut dense = Array::from_elem((self.n, self.n), false);
+        for (i, j) in self.iter() {
+            dense[[i, j]] = true;
+        }
+        dense
+    }
+}
+
+/// Structural patterns of dF/dy and dF/dy' of a model
+#[derive(Clone, Debug, PartialEq)]
+pub struct JacobianPattern {
+    /// pattern of dF/dy
+    dfdy: Pattern,
+    /// pattern of dF/dy'
+    dfdyp: Pattern,
+}
+
+impl JacobianPattern {
+    /// Detects the patterns of the residual of `model` around (tt, yy, yp), by probing at
+    /// (yy, yp) and at `nprobes - 1` further base points with random relative perturbations. Each
+    /// probe takes `2n + 1` residual evaluations.
+    ///
+    /// *Panics* if nprobes is 0.
+    pub fn detect<F, T, S1, S2>(
+        model: &mut F,
+        tt: T,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        nprobes: usize,
+    ) -> Result<Self, ModelError>
+    where
+        F: IdaModel<Scalar = T>,
+        T: num_traits::Float,
+        S1: Data<Elem = T>,
+        S2: Data<Elem = T>,
+    {
+        assert!(nprobes > 0, "nprobes must be positive");
+        let n = model.model_size();
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        // Perturbations of relative size eps^(1/4), large enough to survive rounding in the
+        // residual, by a random factor in [1, 2)
+        let scale = T::epsilon().sqrt().sqrt();
+        let mut increment = |x: T| scale * (T::one() + x.abs()) * (T::one() + rng.sample());
+
+        let mut dfdy = vec![BTreeSet::new(); n];
+        let mut dfdyp = vec![BTreeSet::new(); n];
+        let mut y0 = yy.to_owned();
+        let mut yp0 = yp.to_owned();
+        let mut r0 = Array::zeros(n);
+        let mut rr = Array::zeros(n);
+
+        for probe in 0..nprobes {
+            if probe > 0 {
+                for j in 0..n {
+                    y0[j] = yy[j] + increment(yy[j]);
+                    yp0[j] = yp[j] + increment(yp[j]);
+                }
+            }
+            model.residual(tt, &y0, &yp0, &mut r0)?;
+
+            for j in 0..n {
+                let yj = y0[j];
+                y0[j] = yj + increment(yj);
+                model.residual(tt, &y0, &yp0, &mut rr)?;
+                y0[j] = yj;
+                dfdy[j].extend(changed_rows(&r0, &rr));
+
+                let ypj = yp0[j];
+                yp0[j] = ypj + increment(ypj);
+                model.residual(tt, &y0, &yp0, &mut rr)?;
+                yp0[j] = ypj;
+                dfdyp[j].extend(changed_rows(&r0, &rr));
+            }
+        }
+
+        Ok(JacobianPattern {
+            dfdy: Pattern::from_columns(dfdy),
+            dfdyp: Pattern::from_columns(dfdyp),
+        })
+    }
+
+    /// Pattern of dF/dy
+    pub fn dfdy(&self) -> &Pattern {
+        &self.dfdy
+    }
+
+    /// Pattern of dF/dy'
+    pub fn dfdyp(&self) -> &Pattern {
+        &self.dfdyp
+    }
+
+    /// The (row, col) positions of the iteration matrix `dF/dy + cj*dF/dy'`, i.e. the union of
+    /// both patterns, e.g. for `SparseJacobian::sparsity_pattern()`
+    pub fn iteration_pattern(&self) -> Vec<(usize, usize)> {
+        let mut pattern: Vec<_> = self.dfdy.iter().chain(self.dfdyp.iter()).collect();
+        pattern.sort_by_key(|&(i, j)| (j, i));
+        pattern.dedup();
+        pattern
+    }
+
+    /// Upper and lower half-bandwidths of the iteration matrix, e.g. for the `Band` solver
+    pub fn bandwidths(&self) -> (usize, usize) {
+        self.iteration_pattern()
+            .into_iter()
+            .fold((0, 0), |(mu, ml), (i, j)| {
+                (mu.max(j.saturating_sub(i)), ml.max(i.saturating_sub(j)))
+            })
+    }
+
+    /// Differential (`true`) and algebraic (`false`) components, for `Ida::set_id()`. Component
+    /// j is algebraic if column j of dF/dy' is structurally zero.
+    pub fn id(&self) -> Array<bool, Ix1> {
+        let colptrs = self.dfdyp.colptrs();
+        Array::from_shape_fn(self.dfdyp.size(), |j| colptrs[j + 1] > colptrs[j])
+    }
+}
+
+/// Rows in which the residuals r0 and rr differ (where both are NaN they are considered equal)
+fn changed_rows<'a, T: num_traits::Float>(
+    r0: &'a Array<T, Ix1>,
+    rr: &'a Array<T, Ix1>,
+) -> impl Iterator<Item = usize> + 'a {
+    (0..r0.len()).filter(move |&i| r0[i] != rr[i] && !(r0[i].is_nan() && rr[i].is_nan()))
+}
+
+/// Minimal xorshift64 generator for the probing perturbations
+struct XorShift(u64);
+
+impl XorShift {
+    /// A sample uniformly distributed in [0, 1)
+    fn sample<T: num_traits::Float>(&mut self) -> T {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        T::from((self.0 >> 11) as f64 / (1u64 << 53) as f64).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heat2d::Heat2D;
+    use crate::roberts::Roberts;
+
+    #[test]
+    fn test_detect_pattern() {
+        // At y = (1, 0, 0) the term 1e4*y2*y3 has vanishing derivatives, which only the
+        // perturbed probes detect.
+        let mut roberts = Roberts::default();
+        let (yy, yp) = (array![1., 0., 0.], array![-0.04, 0.04, 0.]);
+        let detected = JacobianPattern::detect(&mut roberts, 0.0, &yy, &yp, 3).unwrap();
+        assert_eq!(detected.dfdy().to_dense(), Array::from_elem((3, 3), true));
+        assert_eq!(
+            detected.dfdyp().iter().collect::<Vec<_>>(),
+            vec![(0, 0), (1, 1)]
+        );
+        assert_eq!(detected.id(), array![true, true, false]);
+
+        // The heat equation has the 5-point stencil in the interior, and u' only there
+        let mut heat = Heat2D::new(10);
+        let (uu, up) = heat.initial_values();
+        let detected = JacobianPattern::detect(&mut heat, 0.0, &uu, &up, 2).unwrap();
+        let mut pattern = heat.sparsity_pattern();
+        pattern.sort_by_key(|&(i, j)| (j, i));
+        assert_eq!(detected.iteration_pattern(), pattern);
+        assert_eq!(detected.bandwidths(), (10, 10));
+        assert_eq!(detected.id(), heat.id());
+        assert!(detected.dfdyp().contains(11, 11));
+        assert!(!detected.dfdyp().contains(0, 0));
+    }
+}
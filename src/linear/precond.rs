@@ -0,0 +1,498 @@
+//! Preconditioners for the Krylov linear solvers
+//!
+//! A preconditioner `P` approximates the iteration matrix `J = dF/dy + cj*dF/dy'` and is
+//! applied on the left, i.e. the Krylov solver iterates on `P^-1 J x = P^-1 b`. It is set up
+//! along with the linear solver, with the current cj, and then applied in each Krylov iteration.
+
+use ndarray::*;
+
+use super::dense::{getrf, getrs};
+use super::{CompressedDq, LinearContext, LinearSolverError, SparseMatrix};
+use crate::traits::*;
+
+/// Preconditioner interface (psetup and psolve in IDA)
+pub trait Preconditioner<F: IdaModel>: Clone + std::fmt::Debug {
+    /// Initializes the preconditioner for the problem given by `model`, before the integration
+    /// starts.
+    fn init(&mut self, _model: &F) -> Result<(), LinearSolverError> {
+        Ok(())
+    }
+
+    /// Evaluates and preprocesses the preconditioner for the iteration matrix at `ctx`. This is
+    /// called by the linear solver setup, so the preconditioner is updated whenever the
+    /// iteration matrix (and cj) is.
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError>;
+
+    /// Solves `P z = r` with the preconditioner from the last `setup()`, overwriting r with the
+    /// solution z.
+    fn solve(
+        &mut self,
+        model: &mut F,
+        r: ArrayViewMut1<F::Scalar>,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError>;
+
+    /// Number of residual evaluations performed by the last call to `setup()`, e.g. for a
+    /// difference quotient Jacobian
+    fn num_setup_res_evals(&self) -> usize {
+        0
+    }
+}
+
+/// The identity preconditioner, i.e. no preconditioning
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoPrec {}
+
+impl<F: IdaModel> Preconditioner<F> for NoPrec {
+    fn setup(
+        &mut self,
+        _model: &mut F,
+        _ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        Ok(())
+    }
+
+    fn solve(
+        &mut self,
+        _model: &mut F,
+        _r: ArrayViewMut1<F::Scalar>,
+        _ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        Ok(())
+    }
+}
+
+/// Preconditioner supplied by the model through `ModelPreconditioner`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModelPrec {}
+
+impl<F: ModelPreconditioner> Preconditioner<F> for ModelPrec {
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        match model.prec_setup(ctx.tt, ctx.cj, &ctx.yy, &ctx.yp, &ctx.rr) {
+            Ok(()) => Ok(()),
+            Err(ModelError::Recoverable {}) => Err(LinearSolverError::Recoverable {}),
+            Err(ModelError::Fatal {}) => Err(LinearSolverError::Fatal {}),
+        }
+    }
+
+    fn solve(
+        &mut self,
+        model: &mut F,
+        mut r: ArrayViewMut1<F::Scalar>,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        match model.prec_solve(ctx.tt, ctx.cj, &ctx.yy, &ctx.yp, &ctx.rr, &mut r) {
+            Ok(()) => Ok(()),
+            Err(ModelError::Recoverable {}) => Err(LinearSolverError::Recoverable {}),
+            Err(ModelError::Fatal {}) => Err(LinearSolverError::Fatal {}),
+        }
+    }
+}
+
+/// Fills `mat` with the iteration matrix at `ctx` from `SparseJacobian::jacobian_sparse()`, or
+/// with its approximation by `dq` if the model does not supply it. The number of residual calls
+/// is stored in `nre`.
+fn sparse_jacobian<F: SparseJacobian>(
+    model: &mut F,
+    ctx: &LinearContext<F::Scalar>,
+    mat: &mut SparseMatrix<F::Scalar>,
+    dq: &mut CompressedDq<F::Scalar>,
+    nre: &mut usize,
+) -> Result<(), LinearSolverError> {
+    mat.fill_zero();
+    *nre = 0;
+    let retval = match model.jacobian_sparse(ctx.tt, ctx.cj, &ctx.yy, &ctx.yp, &ctx.rr, mat) {
+        Some(retval) => retval,
+        None => {
+            let retval = dq.jacobian(model, ctx, |i, j, v| mat.set(i, j, v));
+            *nre = dq.num_res_evals();
+            retval
+        }
+    };
+    match retval {
+        Ok(()) => Ok(()),
+        Err(ModelError::Recoverable {}) => Err(LinearSolverError::Recoverable {}),
+        Err(ModelError::Fatal {}) => Err(LinearSolverError::Fatal {}),
+    }
+}
+
+/// Block-Jacobi preconditioner
+///
+/// The sparse iteration matrix is evaluated by `SparseJacobian::jacobian_sparse()` (or its
+/// difference quotient approximation), and `P` is its block diagonal with blocks of a fixed size
+/// (the last block may be smaller). The blocks are factored with dense LU.
+#[derive(Clone, Debug)]
+pub struct BlockJacobi<T> {
+    /// block size
+    bs: usize,
+    /// iteration matrix
+    mat: SparseMatrix<T>,
+    /// diagonal blocks of mat, overwritten by their LU factors in `setup()`
+    blocks: Vec<Array<T, Ix2>>,
+    /// row pivots of the LU factorizations of the blocks
+    pivots: Vec<Array<usize, Ix1>>,
+    /// difference quotient approximation of the iteration matrix
+    dq: CompressedDq<T>,
+    /// number of residual calls by the last call to `setup()`
+    nre: usize,
+}
+
+impl<T: num_traits::Float> BlockJacobi<T> {
+    /// Creates a new preconditioner with blocks of size `bs`. The storage is allocated by
+    /// `init()`.
+    pub fn new(bs: usize) -> Self {
+        BlockJacobi {
+            bs,
+            mat: SparseMatrix::from_pattern(0, None),
+            blocks: Vec::new(),
+            pivots: Vec::new(),
+            dq: CompressedDq::banded(0, 0, 0),
+            nre: 0,
+        }
+    }
+
+    /// Allocates the blocks for the size of `mat`
+    fn analyze(&mut self, mat: SparseMatrix<T>) {
+        let n = mat.size();
+        let bs = self.bs;
+        let sizes = (0..n).step_by(bs).map(|start| bs.min(n - start));
+        self.blocks = sizes.clone().map(|m| Array::zeros((m, m))).collect();
+        self.pivots = sizes.map(Array::zeros).collect();
+        self.dq = CompressedDq::new(&mat);
+        self.mat = mat;
+    }
+
+    /// Copies the diagonal blocks of mat and factors them
+    fn factor(&mut self) -> Result<(), LinearSolverError> {
+        for block in &mut self.blocks {
+            block.fill(T::zero());
+        }
+
+        let colptrs = self.mat.colptrs();
+        let rowvals = self.mat.rowvals();
+        let data = self.mat.data();
+        for j in 0..self.mat.size() {
+            let k = j / self.bs;
+            let start = k * self.bs;
+            for p in colptrs[j]..colptrs[j + 1] {
+                let i = rowvals[p];
+                if i / self.bs == k {
+                    self.blocks[k][[i - start, j - start]] = data[p];
+                }
+            }
+        }
+
+        for (block, pivots) in self.blocks.iter_mut().zip(self.pivots.iter_mut()) {
+            getrf(block, pivots)?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: SparseJacobian> Preconditioner<F> for BlockJacobi<F::Scalar>
+where
+    F::Scalar: std::fmt::Debug,
+{
+    fn init(&mut self, model: &F) -> Result<(), LinearSolverError> {
+        let n = model.model_size();
+        let pattern = model.sparsity_pattern();
+        if self.bs == 0 || pattern.iter().any(|&(i, j)| i >= n || j >= n) {
+            return Err(LinearSolverError::Fatal {});
+        }
+        self.analyze(SparseMatrix::from_pattern(n, pattern));
+        Ok(())
+    }
+
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        sparse_jacobian(model, ctx, &mut self.mat, &mut self.dq, &mut self.nre)?;
+        self.factor()
+    }
+
+    fn solve(
+        &mut self,
+        _model: &mut F,
+        mut r: ArrayViewMut1<F::Scalar>,
+        _ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        for (k, (block, pivots)) in self.blocks.iter().zip(self.pivots.iter()).enumerate() {
+            let start = k * self.bs;
+            getrs(
+                block,
+                pivots,
+                &mut r.slice_mut(s![start..start + block.rows()]),
+            );
+        }
+        Ok(())
+    }
+
+    fn num_setup_res_evals(&self) -> usize {
+        self.nre
+    }
+}
+
+/// Marks an unused entry of `Ilu0::pos`
+const NONE: usize = usize::MAX;
+
+/// Incomplete LU preconditioner without fill-in (ILU(0))
+///
+/// The sparse iteration matrix is evaluated by `SparseJacobian::jacobian_sparse()` (or its
+/// difference quotient approximation), and factored as `P = L U` with L unit lower triangular,
+/// where L and U keep the sparsity pattern of the matrix (with its diagonal added). There is no
+/// pivoting, so a zero pivot is a recoverable failure.
+#[derive(Clone, Debug)]
+pub struct Ilu0<T> {
+    /// iteration matrix
+    mat: SparseMatrix<T>,
+    /// incomplete LU factors, with the pattern of mat
+    lu: Vec<T>,
+    /// positions of the diagonal elements in lu
+    diag: Vec<usize>,
+    /// positions in lu of the elements of the current column, by row (NONE if not in the
+    /// pattern)
+    pos: Vec<usize>,
+    /// difference quotient approximation of the iteration matrix
+    dq: CompressedDq<T>,
+    /// number of residual calls by the last call to `setup()`
+    nre: usize,
+}
+
+impl<T: num_traits::Float> Default for Ilu0<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: num_traits::Float> Ilu0<T> {
+    /// Creates a new preconditioner. The storage is allocated by `init()`.
+    pub fn new() -> Self {
+        Ilu0 {
+            mat: SparseMatrix::from_pattern(0, None),
+            lu: Vec::new(),
+            diag: Vec::new(),
+            pos: Vec::new(),
+            dq: CompressedDq::banded(0, 0, 0),
+            nre: 0,
+        }
+    }
+
+    /// Allocates the factors for the pattern of `mat`
+    fn analyze(&mut self, mat: SparseMatrix<T>) {
+        let n = mat.size();
+        let colptrs = mat.colptrs();
+        let rowvals = mat.rowvals();
+        self.diag = (0..n)
+            .map(|j| {
+                (colptrs[j]..colptrs[j + 1])
+                    .find(|&p| rowvals[p] == j)
+                    .unwrap()
+            })
+            .collect();
+        self.lu = vec![T::zero(); mat.nnz()];
+        self.pos = vec![NONE; n];
+        self.dq = CompressedDq::new(&mat);
+        self.mat = mat;
+    }
+
+    /// Computes the incomplete LU factors of mat column by column (left-looking), dropping all
+    /// fill-in outside of the pattern.
+    fn factor(&mut self) -> Result<(), LinearSolverError> {
+        let colptrs = self.mat.colptrs();
+        let rowvals = self.mat.rowvals();
+        let diag = &self.diag;
+        let pos = &mut self.pos;
+        let lu = &mut self.lu;
+        lu.copy_from_slice(self.mat.data());
+
+        for j in 0..self.mat.size() {
+            for p in colptrs[j]..colptrs[j + 1] {
+                pos[rowvals[p]] = p;
+            }
+
+            // u(k,j) = a(k,j) - sum_{m<k} l(k,m) u(m,j), for k < j in increasing order
+            for p in colptrs[j]..diag[j] {
+                let k = rowvals[p];
+                let ukj = lu[p];
+                if ukj != T::zero() {
+                    for q in diag[k] + 1..colptrs[k + 1] {
+                        let pi = pos[rowvals[q]];
+                        if pi != NONE {
+                            lu[pi] = lu[pi] - lu[q] * ukj;
+                        }
+                    }
+                }
+            }
+
+            for p in colptrs[j]..colptrs[j + 1] {
+                pos[rowvals[p]] = NONE;
+            }
+
+            // l(i,j) = a(i,j) / u(j,j), for i > j
+            let ujj = lu[diag[j]];
+            if ujj == T::zero() {
+                return Err(LinearSolverError::Recoverable {});
+            }
+            for l in &mut lu[diag[j] + 1..colptrs[j + 1]] {
+                *l = *l / ujj;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Solves `L U x = b` using the factors computed by `factor()`, overwriting b with the
+    /// solution x.
+    fn solve_factored<S: DataMut<Elem = T>>(&self, b: &mut ArrayBase<S, Ix1>) {
+        let n = self.mat.size();
+        let colptrs = self.mat.colptrs();
+        let rowvals = self.mat.rowvals();
+
+        // Solve L y = b, L unit lower triangular
+        for j in 0..n {
+            let bj = b[j];
+            for p in self.diag[j] + 1..colptrs[j + 1] {
+                b[rowvals[p]] = b[rowvals[p]] - self.lu[p] * bj;
+            }
+        }
+
+        // Solve U x = y, U upper triangular
+        for j in (0..n).rev() {
+            b[j] = b[j] / self.lu[self.diag[j]];
+            let bj = b[j];
+            for p in colptrs[j]..self.diag[j] {
+                b[rowvals[p]] = b[rowvals[p]] - self.lu[p] * bj;
+            }
+        }
+    }
+}
+
+impl<F: SparseJacobian> Preconditioner<F> for Ilu0<F::Scalar>
+where
+    F::Scalar: std::fmt::Debug,
+{
+    fn init(&mut self, model: &F) -> Result<(), LinearSolverError> {
+        let n = model.model_size();
+        let pattern = model.sparsity_pattern();
+        if pattern.iter().any(|&(i, j)| i >= n || j >= n) {
+            return Err(LinearSolverError::Fatal {});
+        }
+        // The diagonal is always part of the factors
+        let pattern = pattern.into_iter().chain((0..n).map(|i| (i, i)));
+        self.analyze(SparseMatrix::from_pattern(n, pattern));
+        Ok(())
+    }
+
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        sparse_jacobian(model, ctx, &mut self.mat, &mut self.dq, &mut self.nre)?;
+        self.factor()
+    }
+
+    fn solve(
+        &mut self,
+        _model: &mut F,
+        mut r: ArrayViewMut1<F::Scalar>,
+        _ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.solve_factored(&mut r);
+        Ok(())
+    }
+
+    fn num_setup_res_evals(&self) -> usize {
+        self.nre
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nearly_eq::*;
+
+    /// Tridiagonal matrix, for which ILU(0) is the exact LU factorization
+    fn tridiagonal(n: usize) -> SparseMatrix<f64> {
+        let pattern =
+            (0..n).flat_map(|i| vec![(i, i), (i, i.saturating_sub(1)), (i.saturating_sub(1), i)]);
+        let mut a = SparseMatrix::from_pattern(n, pattern);
+        for i in 0..n {
+            a.set(i, i, 4.0 + i as f64);
+            if i > 0 {
+                a.set(i, i - 1, -1.0);
+                a.set(i - 1, i, 2.0);
+            }
+        }
+        a
+    }
+
+    #[test]
+    fn test_ilu0() {
+        let a = tridiagonal(6);
+        let x = array![1., -2., 0.5, 3., 0., -1.];
+
+        let mut ilu = Ilu0::new();
+        ilu.analyze(a.clone());
+        ilu.factor().unwrap();
+        let mut b = a.dot(&x);
+        ilu.solve_factored(&mut b);
+        assert_nearly_eq!(b, x, 1e-14);
+
+        // The fill-in at (1, 2) and (2, 1) is dropped, so that
+        // L U = [4 1 1; 2 4 0.5; 2 0.5 4] with L = [1 0 0; 0.5 1 0; 0.5 0 1]
+        let pattern = vec![(0, 0), (1, 1), (2, 2), (0, 1), (0, 2), (1, 0), (2, 0)];
+        let mut a = SparseMatrix::from_pattern(3, pattern);
+        a.set(0, 0, 4.);
+        a.set(1, 1, 4.);
+        a.set(2, 2, 4.);
+        a.set(0, 1, 1.);
+        a.set(0, 2, 1.);
+        a.set(1, 0, 2.);
+        a.set(2, 0, 2.);
+        ilu.analyze(a);
+        ilu.factor().unwrap();
+        let mut b = array![5., -1., 9.5];
+        ilu.solve_factored(&mut b);
+        assert_nearly_eq!(b, array![1., -1., 2.], 1e-14);
+    }
+
+    #[test]
+    fn test_block_jacobi() {
+        let a = tridiagonal(5);
+        let x = array![1., -2., 0.5, 3., -1.];
+
+        // P keeps the blocks [0, 1], [2, 3] and [4]
+        let mut b = Array::zeros(5);
+        for i in 0..5 {
+            for j in 0..5 {
+                if i / 2 == j / 2 && a.in_pattern(i, j) {
+                    b[i] += a.get(i, j) * x[j];
+                }
+            }
+        }
+
+        let mut bj = BlockJacobi::new(2);
+        bj.analyze(a);
+        bj.factor().unwrap();
+        assert_eq!(bj.blocks.len(), 3);
+        for (k, (block, pivots)) in bj.blocks.iter().zip(bj.pivots.iter()).enumerate() {
+            getrs(
+                block,
+                pivots,
+                &mut b.slice_mut(s![2 * k..2 * k + block.rows()]),
+            );
+        }
+        assert_nearly_eq!(b, x, 1e-14);
+    }
+}
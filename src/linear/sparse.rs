@@ -0,0 +1,577 @@
+//! Sparse direct linear solver, using a left-looking LU factorization with threshold partial
+//! pivoting, after a fill-reducing ordering of the columns
+//!
+//! The factorization follows `cs_lu` from CSparse (T. Davis, "Direct Methods for Sparse Linear
+//! Systems", SIAM 2006).
+
+use std::collections::{BTreeSet, BinaryHeap};
+
+use ndarray::*;
+
+use super::{CompressedDq, LinearContext, LinearSolver, LinearSolverError};
+use crate::traits::*;
+
+/// Threshold for preferring the diagonal entry as pivot, relative to the largest candidate
+const PIVOT_TOL: f64 = 0.001;
+
+/// Compressed-sparse-column matrix storage (SUNSparseMatrix with CSC_MAT)
+///
+/// The sparsity pattern is fixed on construction, and only entries within the pattern may be set.
+#[derive(Clone, Debug)]
+pub struct SparseMatrix<T> {
+    /// number of rows and columns
+    n: usize,
+    /// index of the first entry of each column in `rowvals` and `data`, of length n + 1
+    colptrs: Vec<usize>,
+    /// row indices of the entries, sorted within each column
+    rowvals: Vec<usize>,
+    /// values of the entries
+    data: Vec<T>,
+}
+
+impl<T: num_traits::Float> SparseMatrix<T> {
+    /// Creates a new n by n zero matrix with non-zeros allowed at the (row, col) positions in
+    /// `pattern`. Duplicate positions are ignored.
+    ///
+    /// *Panics* if a position is out of range.
+    pub fn from_pattern(n: usize, pattern: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        let mut cols = vec![BTreeSet::new(); n];
+        for (i, j) in pattern {
+            assert!(
+                i < n && j < n,
+                "({}, {}) is out of range for n = {}",
+                i,
+                j,
+                n
+            );
+            cols[j].insert(i);
+        }
+
+        let mut colptrs = Vec::with_capacity(n + 1);
+        let mut rowvals = Vec::new();
+        colptrs.push(0);
+        for col in cols {
+            rowvals.extend(col);
+            colptrs.push(rowvals.len());
+        }
+
+        SparseMatrix {
+            n,
+            colptrs,
+            data: vec![T::zero(); rowvals.len()],
+            rowvals,
+        }
+    }
+
+    /// Number of rows and columns
+    pub fn size(&self) -> usize {
+        self.n
+    }
+
+    /// Number of entries in the sparsity pattern
+    pub fn nnz(&self) -> usize {
+        self.rowvals.len()
+    }
+
+    /// Index of the first entry of each column, of length n + 1
+    pub fn colptrs(&self) -> &[usize] {
+        &self.colptrs
+    }
+
+    /// Row indices of the entries
+    pub fn rowvals(&self) -> &[usize] {
+        &self.rowvals
+    }
+
+    /// Values of the entries
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    /// Mutable values of the entries
+    pub fn data_mut(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    /// Index of A[i,j] in `data`, if it is in the pattern
+    fn position(&self, i: usize, j: usize) -> Option<usize> {
+        if j >= self.n {
+            return None;
+        }
+        let start = self.colptrs[j];
+        self.rowvals[start..self.colptrs[j + 1]]
+            .binary_search(&i)
+            .ok()
+            .map(|p| start + p)
+    }
+
+    /// Returns true if A[i,j] is in the sparsity pattern
+    pub fn in_pattern(&self, i: usize, j: usize) -> bool {
+        self.position(i, j).is_some()
+    }
+
+    /// Returns A[i,j], or zero outside of the pattern
+    pub fn get(&self, i: usize, j: usize) -> T {
+        self.position(i, j).map_or_else(T::zero, |p| self.data[p])
+    }
+
+    /// Sets A[i,j] = v
+    ///
+    /// *Panics* if A[i,j] is not in the sparsity pattern.
+    pub fn set(&mut self, i: usize, j: usize, v: T) {
+        let p = self
+            .position(i, j)
+            .unwrap_or_else(|| panic!("A[{}, {}] is not in the sparsity pattern", i, j));
+        self.data[p] = v;
+    }
+
+    /// Sets all entries to zero
+    pub fn fill_zero(&mut self) {
+        for v in &mut self.data {
+            *v = T::zero();
+        }
+    }
+
+    /// Returns the product `A x`
+    pub fn dot<S: Data<Elem = T>>(&self, x: &ArrayBase<S, Ix1>) -> Array<T, Ix1> {
+        let mut y = Array::zeros(self.n);
+        for j in 0..self.n {
+            for p in self.colptrs[j]..self.colptrs[j + 1] {
+                let i = self.rowvals[p];
+                y[i] = y[i] + self.data[p] * x[j];
+            }
+        }
+        y
+    }
+}
+
+/// Computes a fill-reducing ordering of the columns of a sparse matrix, by the minimum degree
+/// heuristic on the pattern of A + A^T. Returns q, where q[k] is the k-th column to eliminate.
+pub fn minimum_degree<T>(a: &SparseMatrix<T>) -> Vec<usize> {
+    let n = a.n;
+
+    // Build the symmetric adjacency graph, without self-loops
+    let mut adj = vec![BTreeSet::new(); n];
+    for j in 0..n {
+        for &i in &a.rowvals[a.colptrs[j]..a.colptrs[j + 1]] {
+            if i != j {
+                adj[i].insert(j);
+                adj[j].insert(i);
+            }
+        }
+    }
+
+    // Min-heap of (degree, node), with stale entries skipped when popped
+    let mut heap: BinaryHeap<_> = (0..n)
+        .map(|v| std::cmp::Reverse((adj[v].len(), v)))
+        .collect();
+    let mut eliminated = vec![false; n];
+    let mut q = Vec::with_capacity(n);
+
+    while let Some(std::cmp::Reverse((degree, v))) = heap.pop() {
+        if eliminated[v] || degree != adj[v].len() {
+            continue;
+        }
+        eliminated[v] = true;
+        q.push(v);
+
+        // Eliminating v connects all of its neighbors into a clique
+        let neighbors = std::mem::take(&mut adj[v]);
+        for &u in &neighbors {
+            adj[u].remove(&v);
+            for &w in &neighbors {
+                if w != u {
+                    adj[u].insert(w);
+                }
+            }
+            heap.push(std::cmp::Reverse((adj[u].len(), u)));
+        }
+    }
+
+    q
+}
+
+/// Column-compressed factor storage, grown one column at a time
+#[derive(Clone, Debug)]
+struct Factor<T> {
+    colptrs: Vec<usize>,
+    rowvals: Vec<usize>,
+    data: Vec<T>,
+}
+
+impl<T> Factor<T> {
+    fn with_capacity(n: usize, nnz: usize) -> Self {
+        Factor {
+            colptrs: Vec::with_capacity(n + 1),
+            rowvals: Vec::with_capacity(nnz),
+            data: Vec::with_capacity(nnz),
+        }
+    }
+
+    fn push(&mut self, i: usize, v: T) {
+        self.rowvals.push(i);
+        self.data.push(v);
+    }
+}
+
+/// Sparse LU linear solver (similar to SUNLinSol_KLU)
+///
+/// The column ordering (the symbolic factorization) is computed once from the sparsity pattern
+/// in `init()`, and reused by the numeric factorization in each `setup()`. The iteration matrix
+/// is evaluated by `SparseJacobian::jacobian_sparse()`, or approximated by compressed difference
+/// quotients of `IdaModel::residual()` if the model does not supply it.
+#[derive(Clone, Debug)]
+pub struct SparseLu<T> {
+    /// iteration matrix
+    mat: SparseMatrix<T>,
+    /// fill-reducing column ordering
+    q: Vec<usize>,
+    /// unit lower triangular factor, with the unit diagonal stored first in each column
+    l: Factor<T>,
+    /// upper triangular factor, with the diagonal stored last in each column
+    u: Factor<T>,
+    /// inverse row permutation: row i of the matrix is row pinv[i] of the factors
+    pinv: Vec<usize>,
+    /// difference quotient approximation of the iteration matrix
+    dq: CompressedDq<T>,
+    /// number of residual calls by the last call to `setup()`
+    nre: usize,
+}
+
+impl<T: num_traits::Float> Default for SparseLu<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: num_traits::Float> SparseLu<T> {
+    /// Creates a new solver. The storage and column ordering are set up by `init()`.
+    pub fn new() -> Self {
+        SparseLu {
+            mat: SparseMatrix::from_pattern(0, None),
+            q: Vec::new(),
+            l: Factor::with_capacity(0, 0),
+            u: Factor::with_capacity(0, 0),
+            pinv: Vec::new(),
+            dq: CompressedDq::banded(0, 0, 0),
+            nre: 0,
+        }
+    }
+
+    /// Sets the sparsity pattern of the iteration matrix, and computes the column ordering and
+    /// the column coloring for the difference quotients.
+    fn analyze(&mut self, mat: SparseMatrix<T>) {
+        self.q = minimum_degree(&mat);
+        self.dq = CompressedDq::new(&mat);
+        self.mat = mat;
+    }
+
+    /// Numeric LU factorization of `mat` with the column ordering q (cs_lu).
+    ///
+    /// Returns an error if the matrix is singular.
+    fn factor(&mut self) -> Result<(), ()> {
+        let n = self.mat.n;
+        let a = &self.mat;
+
+        // Reserve space for the factors from the size of the last factorization
+        let lnz = self.l.rowvals.len().max(a.nnz() + n);
+        let unz = self.u.rowvals.len().max(a.nnz() + n);
+        let mut l = Factor::with_capacity(n, lnz);
+        let mut u = Factor::with_capacity(n, unz);
+
+        let none = usize::MAX;
+        let mut pinv = vec![none; n];
+        let mut x = vec![T::zero(); n];
+        let mut xi = vec![0; n];
+        let mut pstack = vec![0; n];
+        let mut marked = vec![false; n];
+
+        for k in 0..n {
+            // Triangular solve: x = L \ A(:,col)
+            l.colptrs.push(l.rowvals.len());
+            u.colptrs.push(u.rowvals.len());
+            let col = self.q[k];
+
+            // xi[top..n] = Reach(A(:,col)), in topological order
+            let mut top = n;
+            for &i in &a.rowvals[a.colptrs[col]..a.colptrs[col + 1]] {
+                if !marked[i] {
+                    top = dfs(i, &l, top, &mut xi, &mut pstack, &mut marked, &pinv);
+                }
+            }
+            for &j in &xi[top..n] {
+                marked[j] = false;
+            }
+
+            for &j in &xi[top..n] {
+                x[j] = T::zero();
+            }
+            for p in a.colptrs[col]..a.colptrs[col + 1] {
+                x[a.rowvals[p]] = a.data[p];
+            }
+            for &j in &xi[top..n] {
+                let jj = pinv[j];
+                if jj == none {
+                    continue;
+                }
+                // L(j,j) = 1 is the first entry of column jj
+                let xj = x[j];
+                for p in l.colptrs[jj] + 1..l.colptrs[jj + 1] {
+                    let i = l.rowvals[p];
+                    x[i] = x[i] - l.data[p] * xj;
+                }
+            }
+
+            // Find pivot
+            let mut ipiv = none;
+            let mut amax = -T::one();
+            for &i in &xi[top..n] {
+                if pinv[i] == none {
+                    let t = x[i].abs();
+                    if t > amax {
+                        amax = t;
+                        ipiv = i;
+                    }
+                } else {
+                    // x(i) is the entry U(pinv[i],k)
+                    u.push(pinv[i], x[i]);
+                }
+            }
+            if ipiv == none || amax <= T::zero() {
+                return Err(());
+            }
+            // Prefer the diagonal entry, to keep the fill predicted by the ordering
+            if pinv[col] == none && x[col].abs() >= amax * T::from(PIVOT_TOL).unwrap() {
+                ipiv = col;
+            }
+
+            // Divide by pivot
+            let pivot = x[ipiv];
+            u.push(k, pivot);
+            pinv[ipiv] = k;
+            l.push(ipiv, T::one());
+            for &i in &xi[top..n] {
+                if pinv[i] == none {
+                    l.push(i, x[i] / pivot);
+                }
+                x[i] = T::zero();
+            }
+        }
+
+        // Finalize L and U, and fix the row indices of L for the final pinv
+        l.colptrs.push(l.rowvals.len());
+        u.colptrs.push(u.rowvals.len());
+        for i in &mut l.rowvals {
+            *i = pinv[*i];
+        }
+
+        self.l = l;
+        self.u = u;
+        self.pinv = pinv;
+        Ok(())
+    }
+
+    /// Solves `A x = b` using the factors computed by `factor()`, overwriting b with the
+    /// solution x (cs_lusol).
+    fn solve_factored<S: DataMut<Elem = T>>(&self, b: &mut ArrayBase<S, Ix1>) {
+        let n = self.mat.n;
+        let (l, u) = (&self.l, &self.u);
+
+        // x = P b
+        let mut x = vec![T::zero(); n];
+        for i in 0..n {
+            x[self.pinv[i]] = b[i];
+        }
+
+        // x = L \ x
+        for j in 0..n {
+            let xj = x[j];
+            for p in l.colptrs[j] + 1..l.colptrs[j + 1] {
+                x[l.rowvals[p]] = x[l.rowvals[p]] - l.data[p] * xj;
+            }
+        }
+
+        // x = U \ x
+        for j in (0..n).rev() {
+            let last = u.colptrs[j + 1] - 1;
+            x[j] = x[j] / u.data[last];
+            let xj = x[j];
+            for p in u.colptrs[j]..last {
+                x[u.rowvals[p]] = x[u.rowvals[p]] - u.data[p] * xj;
+            }
+        }
+
+        // b = Q x
+        for k in 0..n {
+            b[self.q[k]] = x[k];
+        }
+    }
+}
+
+/// Depth-first search from node j in the graph of the partial factor L, pushing the reached
+/// nodes onto xi[..top] in topological order (cs_dfs). Returns the new top.
+fn dfs<T>(
+    j: usize,
+    l: &Factor<T>,
+    mut top: usize,
+    xi: &mut [usize],
+    pstack: &mut [usize],
+    marked: &mut [bool],
+    pinv: &[usize],
+) -> usize {
+    let mut stack = vec![j];
+    while let Some(&j) = stack.last() {
+        let head = stack.len() - 1;
+        let jnew = pinv[j];
+        let (p1, p2) = if jnew == usize::MAX {
+            (0, 0)
+        } else {
+            (l.colptrs[jnew], l.colptrs[jnew + 1])
+        };
+        if !marked[j] {
+            marked[j] = true;
+            pstack[head] = p1;
+        }
+
+        // Descend into the first unmarked neighbor, or finish node j
+        match (pstack[head]..p2).find(|&p| !marked[l.rowvals[p]]) {
+            Some(p) => {
+                pstack[head] = p;
+                stack.push(l.rowvals[p]);
+            }
+            None => {
+                stack.pop();
+                top -= 1;
+                xi[top] = j;
+            }
+        }
+    }
+    top
+}
+
+impl<F: SparseJacobian> LinearSolver<F> for SparseLu<F::Scalar>
+where
+    F::Scalar: std::fmt::Debug,
+{
+    fn init(&mut self, model: &F) -> Result<(), LinearSolverError> {
+        let n = model.model_size();
+        let pattern = model.sparsity_pattern();
+        if pattern.iter().any(|&(i, j)| i >= n || j >= n) {
+            return Err(LinearSolverError::Fatal {});
+        }
+        self.analyze(SparseMatrix::from_pattern(n, pattern));
+        Ok(())
+    }
+
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.mat.fill_zero();
+        self.nre = 0;
+        let retval =
+            match model.jacobian_sparse(ctx.tt, ctx.cj, &ctx.yy, &ctx.yp, &ctx.rr, &mut self.mat) {
+                Some(retval) => retval,
+                None => {
+                    let mat = &mut self.mat;
+                    let retval = self.dq.jacobian(model, ctx, |i, j, v| mat.set(i, j, v));
+                    self.nre = self.dq.num_res_evals();
+                    retval
+                }
+            };
+        match retval {
+            Ok(()) => {}
+            Err(ModelError::Recoverable {}) => return Err(LinearSolverError::Recoverable {}),
+            Err(ModelError::Fatal {}) => return Err(LinearSolverError::Fatal {}),
+        }
+        self.factor().map_err(|_| LinearSolverError::Recoverable {})
+    }
+
+    fn solve(
+        &mut self,
+        _model: &mut F,
+        mut b: ArrayViewMut1<F::Scalar>,
+        _ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.solve_factored(&mut b);
+        Ok(())
+    }
+
+    fn num_setup_res_evals(&self) -> usize {
+        self.nre
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nearly_eq::*;
+
+    /// Arrow matrix, which fills in completely unless the dense row and column are ordered last
+    fn arrow(n: usize) -> SparseMatrix<f64> {
+        let pattern = (0..n).flat_map(|i| vec![(i, i), (0, i), (i, 0)]);
+        let mut a = SparseMatrix::from_pattern(n, pattern);
+        for i in 1..n {
+            a.set(i, i, 2.0 + i as f64);
+            a.set(0, i, 1.0);
+            a.set(i, 0, -1.0);
+        }
+        a.set(0, 0, 1.0);
+        a
+    }
+
+    #[test]
+    fn test_sparse_matrix() {
+        let mut a = SparseMatrix::from_pattern(3, vec![(0, 0), (2, 0), (1, 1), (2, 0)]);
+        assert_eq!(a.nnz(), 3);
+        assert_eq!(a.colptrs(), &[0, 2, 3, 3]);
+        assert_eq!(a.rowvals(), &[0, 2, 1]);
+        a.set(2, 0, 3.0);
+        assert_eq!(a.get(2, 0), 3.0);
+        assert_eq!(a.get(0, 2), 0.0);
+        assert!(!a.in_pattern(0, 2));
+        assert_eq!(a.dot(&array![1., 1., 1.]), array![0., 0., 3.]);
+    }
+
+    #[test]
+    fn test_minimum_degree() {
+        let q = minimum_degree(&arrow(6));
+        assert_eq!(q.len(), 6);
+        assert_eq!(q[0], 1);
+        assert!(q[..4].iter().all(|&k| k != 0));
+    }
+
+    #[test]
+    fn test_sparse_lu() {
+        let n = 6;
+        let a = arrow(n);
+        let x = Array::from_shape_fn(n, |i| 1.0 - 0.5 * i as f64);
+        let mut b = a.dot(&x);
+
+        let mut lu = SparseLu::new();
+        lu.analyze(a.clone());
+        lu.factor().unwrap();
+        // No fill-in with a good ordering
+        assert_eq!(lu.l.rowvals.len() + lu.u.rowvals.len(), a.nnz() + n);
+        lu.solve_factored(&mut b);
+        assert_nearly_eq!(b, x, 1e-12);
+
+        // A zero diagonal requires off-diagonal pivoting
+        let mut a = SparseMatrix::from_pattern(3, vec![(0, 1), (1, 0), (1, 1), (2, 2)]);
+        a.set(0, 1, 2.0);
+        a.set(1, 0, 1.0);
+        a.set(1, 1, 1.0);
+        a.set(2, 2, 4.0);
+        let mut b = array![2., 2., 4.];
+        lu.analyze(a);
+        lu.factor().unwrap();
+        lu.solve_factored(&mut b);
+        assert_nearly_eq!(b, array![1., 1., 1.], 1e-14);
+
+        // Singular matrix
+        let a = SparseMatrix::from_pattern(2, vec![(0, 0), (1, 0)]);
+        lu.analyze(a);
+        assert!(lu.factor().is_err());
+    }
+}
@@ -0,0 +1,509 @@
+//! Scaled, preconditioned GMRES iterative linear solver, using Jacobian-vector products
+
+use ndarray::*;
+
+use super::{LinearContext, LinearSolver, LinearSolverError, NoPrec, Preconditioner};
+use crate::traits::*;
+
+/// default maximum dimension of the Krylov subspace (SUNSPGMR_MAXL_DEFAULT)
+const MAXL_DEFAULT: usize = 5;
+/// default factor between the linear and nonlinear convergence tolerances (IDALS_EPLIN)
+const EPLIN: f64 = 0.05;
+
+/// Scaled GMRES linear solver (SUNLinSol_SPGMR)
+///
+/// The system `J x = b` is solved matrix-free: products of the iteration matrix with a vector
+/// are computed by `IdaModel::jac_times_vec()`, or approximated by difference quotients of
+/// `IdaModel::residual()` if the model does not supply them. The system is preconditioned on the
+/// left by an optional `Preconditioner` P, and the vectors are scaled by the error weights. The
+/// iteration stops once the weighted RMS norm of the preconditioned residual `P^-1 (b - J x)`
+/// drops below `eplifac * eps_newt`. Failing that is a recoverable error.
+#[derive(Clone, Debug)]
+pub struct Spgmr<T, P = NoPrec> {
+    /// maximum dimension of the Krylov subspace
+    maxl: usize,
+    /// maximum number of restarts
+    max_restarts: usize,
+    /// factor between the linear and nonlinear convergence tolerances
+    eplifac: T,
+    /// preconditioner, if any
+    prec: Option<P>,
+    /// orthonormal basis of the Krylov subspace, one vector per row
+    vv: Array<T, Ix2>,
+    /// Hessenberg matrix of the Arnoldi process, reduced to upper triangular form in place
+    hes: Array<T, Ix2>,
+    /// Givens rotations (cos, sin) applied to hes
+    givens: Array<T, Ix2>,
+    /// right-hand side of the least squares problem for the Krylov coefficients
+    gg: Array<T, Ix1>,
+    /// perturbed y for difference quotient Jacobian-vector products
+    ytemp: Array<T, Ix1>,
+    /// perturbed y' for difference quotient Jacobian-vector products
+    yptemp: Array<T, Ix1>,
+    /// number of linear iterations performed by the last call to `solve()`
+    nli: usize,
+    /// number of preconditioner setups performed by the last call to `setup()`
+    npe: usize,
+    /// number of preconditioner solves performed by the last call to `solve()`
+    nps: usize,
+    /// number of Jacobian-vector products
+    njtimes: u64,
+    /// number of residual calls for difference quotient Jacobian-vector products
+    nre_dq: u64,
+}
+
+impl<T: num_traits::Float> Default for Spgmr<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: num_traits::Float> Spgmr<T> {
+    /// Creates a new solver without preconditioning, with the default Krylov subspace dimension
+    /// (5) and no restarts. The storage is allocated by `init()`.
+    pub fn new() -> Self {
+        Self::build(None)
+    }
+}
+
+impl<T: num_traits::Float, P> Spgmr<T, P> {
+    /// Creates a new solver preconditioned by `prec`, with the default Krylov subspace dimension
+    /// (5) and no restarts. The storage is allocated by `init()`.
+    pub fn with_preconditioner(prec: P) -> Self {
+        Self::build(Some(prec))
+    }
+
+    fn build(prec: Option<P>) -> Self {
+        Spgmr {
+            maxl: MAXL_DEFAULT,
+            max_restarts: 0,
+            eplifac: T::from(EPLIN).unwrap(),
+            prec,
+            vv: Array::zeros((0, 0)),
+            hes: Array::zeros((0, 0)),
+            givens: Array::zeros((0, 0)),
+            gg: Array::zeros(0),
+            ytemp: Array::zeros(0),
+            yptemp: Array::zeros(0),
+            nli: 0,
+            npe: 0,
+            nps: 0,
+            njtimes: 0,
+            nre_dq: 0,
+        }
+    }
+
+    /// Sets the maximum dimension of the Krylov subspace (SUNLinSol_SPGMRSetMaxl). A value of 0
+    /// selects the default.
+    pub fn set_maxl(&mut self, maxl: usize) {
+        self.maxl = if maxl == 0 { MAXL_DEFAULT } else { maxl };
+    }
+
+    /// Sets the maximum number of GMRES restarts (SUNLinSol_SPGMRSetMaxRestarts).
+    pub fn set_max_restarts(&mut self, max_restarts: usize) {
+        self.max_restarts = max_restarts;
+    }
+
+    /// Sets the factor between the linear and nonlinear convergence tolerances (IDASetEpsLin).
+    /// A value of 0 selects the default (0.05).
+    pub fn set_eps_lin(&mut self, eplifac: T) {
+        assert!(eplifac >= T::zero(), "eplifac must be non-negative");
+        self.eplifac = if eplifac == T::zero() {
+            T::from(EPLIN).unwrap()
+        } else {
+            eplifac
+        };
+    }
+
+    /// Number of Jacobian-vector products computed
+    pub fn num_jtimes(&self) -> u64 {
+        self.njtimes
+    }
+
+    /// Number of residual calls made for difference quotient Jacobian-vector products
+    pub fn num_res_evals(&self) -> u64 {
+        self.nre_dq
+    }
+
+    /// The preconditioner, if any
+    pub fn prec(&self) -> Option<&P> {
+        self.prec.as_ref()
+    }
+
+    /// Computes `jv = J v` with the model's Jacobian-vector product, or with a difference
+    /// quotient of the residual if the model does not supply one (idaLsDQJtimes):
+    ///
+    /// `jv = (F(tt, yy + sig*v, yp + cj*sig*v) - rr) / sig`, with `sig = 1 / ||v||_wrms`.
+    fn jtimes<F: IdaModel<Scalar = T>>(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<T>,
+        v: &Array<T, Ix1>,
+        jv: &mut Array<T, Ix1>,
+    ) -> Result<(), LinearSolverError> {
+        self.njtimes += 1;
+
+        let retval = match model.jac_times_vec(ctx.tt, ctx.cj, &ctx.yy, &ctx.yp, v, jv) {
+            Some(retval) => retval,
+            None => {
+                let sig = wrms_norm(v, &ctx.ewt).recip();
+
+                Zip::from(&mut self.ytemp)
+                    .and(&mut self.yptemp)
+                    .and(&ctx.yy)
+                    .and(&ctx.yp)
+                    .and(v)
+                    .apply(|ytemp, yptemp, &yy, &yp, &v| {
+                        *ytemp = yy + sig * v;
+                        *yptemp = yp + ctx.cj * sig * v;
+                    });
+
+                let retval = model.residual(ctx.tt, &self.ytemp, &self.yptemp, jv);
+                self.nre_dq += 1;
+
+                if retval.is_ok() {
+                    Zip::from(jv)
+                        .and(&ctx.rr)
+                        .apply(|jv, &rr| *jv = (*jv - rr) / sig);
+                }
+                retval
+            }
+        };
+
+        retval.map_err(|err| match err {
+            ModelError::Recoverable {} => LinearSolverError::Recoverable {},
+            ModelError::Fatal {} => LinearSolverError::Fatal {},
+        })
+    }
+
+    /// Solves `P z = r` in place with the preconditioner, if any
+    fn psolve<F: IdaModel<Scalar = T>>(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<T>,
+        r: &mut Array<T, Ix1>,
+    ) -> Result<(), LinearSolverError>
+    where
+        P: Preconditioner<F>,
+    {
+        if let Some(prec) = &mut self.prec {
+            self.nps += 1;
+            prec.solve(model, r.view_mut(), ctx)?;
+        }
+        Ok(())
+    }
+
+    /// GMRES with modified Gram-Schmidt orthogonalization, starting from the initial guess
+    /// x = 0. All vectors are scaled by the error weights, so that the 2-norm of a scaled vector
+    /// is sqrt(n) times its weighted RMS norm.
+    ///
+    /// If the iteration does not converge, the last iterate is returned in b, with
+    /// `LinearSolverError::ResidualReduced` if its residual is smaller than the initial one.
+    fn gmres<F: IdaModel<Scalar = T>>(
+        &mut self,
+        model: &mut F,
+        mut b: ArrayViewMut1<T>,
+        ctx: &LinearContext<T>,
+    ) -> Result<(), LinearSolverError>
+    where
+        P: Preconditioner<F>,
+    {
+        let n = b.len();
+        let zero = T::zero();
+        let ewt = &ctx.ewt;
+
+        // Convergence test on the 2-norm of the scaled residual
+        let delta = self.eplifac * ctx.eps_newt * T::from(n).unwrap().sqrt();
+
+        let mut xx = Array::zeros(n);
+        let mut vtemp = Array::zeros(n);
+        let mut jv = Array::zeros(n);
+
+        // Initial residual r0 = P^-1 (b - J x0) = P^-1 b
+        self.nli = 0;
+        self.nps = 0;
+        let mut rr = b.to_owned();
+        self.psolve(model, ctx, &mut rr)?;
+
+        // Norms of the initial and of the current scaled residual
+        let mut r_norm = zero;
+        let mut rho = zero;
+        for ntries in 0..=self.max_restarts {
+            // v0 = S r / ||S r||
+            let mut v0 = self.vv.row_mut(0);
+            Zip::from(&mut v0)
+                .and(&rr)
+                .and(ewt)
+                .apply(|v, &r, &w| *v = r * w);
+            let beta = norm2(&v0);
+            if beta <= delta {
+                b.assign(&xx);
+                return Ok(());
+            }
+            v0.mapv_inplace(|v| v / beta);
+
+            self.gg.fill(zero);
+            self.gg[0] = beta;
+            rho = beta;
+            if ntries == 0 {
+                r_norm = beta;
+            }
+
+            // Arnoldi process, building up the Krylov subspace one vector at a time
+            let mut krydim = 0;
+            for l in 0..self.maxl {
+                self.nli += 1;
+                krydim = l + 1;
+
+                // vtemp = S^-1 v_l, jv = P^-1 J vtemp, v_{l+1} = S jv
+                Zip::from(&mut vtemp)
+                    .and(self.vv.row(l))
+                    .and(ewt)
+                    .apply(|vt, &v, &w| *vt = v / w);
+                self.jtimes(model, ctx, &vtemp, &mut jv)?;
+                self.psolve(model, ctx, &mut jv)?;
+                Zip::from(&mut jv).and(ewt).apply(|jv, &w| *jv = *jv * w);
+
+                // Orthogonalize against the previous basis vectors (modified Gram-Schmidt)
+                for i in 0..=l {
+                    let vi = self.vv.row(i);
+                    let hil = jv
+                        .iter()
+                        .zip(vi.iter())
+                        .fold(zero, |acc, (&a, &b)| acc + a * b);
+                    Zip::from(&mut jv)
+                        .and(&vi)
+                        .apply(|jv, &v| *jv = *jv - hil * v);
+                    self.hes[[i, l]] = hil;
+                }
+                let hnorm = norm2(&jv);
+                self.hes[[l + 1, l]] = hnorm;
+
+                // Apply the previous Givens rotations to the new column of hes
+                for i in 0..l {
+                    let (c, s) = (self.givens[[i, 0]], self.givens[[i, 1]]);
+                    let (h0, h1) = (self.hes[[i, l]], self.hes[[i + 1, l]]);
+                    self.hes[[i, l]] = c * h0 - s * h1;
+                    self.hes[[i + 1, l]] = s * h0 + c * h1;
+                }
+
+                // Compute and apply the rotation zeroing out hes[l+1, l]
+                let (h0, h1) = (self.hes[[l, l]], self.hes[[l + 1, l]]);
+                let r = h0.hypot(h1);
+                if r == zero {
+                    // Breakdown with a singular Hessenberg matrix
+                    b.assign(&xx);
+                    return Err(LinearSolverError::Recoverable {});
+                }
+                let (c, s) = (h0 / r, -h1 / r);
+                self.givens[[l, 0]] = c;
+                self.givens[[l, 1]] = s;
+                self.hes[[l, l]] = r;
+                self.hes[[l + 1, l]] = zero;
+                self.gg[l + 1] = s * self.gg[l];
+                self.gg[l] = c * self.gg[l];
+
+                rho = self.gg[l + 1].abs();
+                if rho <= delta || hnorm == zero {
+                    break;
+                }
+
+                let mut vnext = self.vv.row_mut(l + 1);
+                vnext.assign(&jv);
+                vnext.mapv_inplace(|v| v / hnorm);
+            }
+
+            // Solve the triangular system hes y = gg for the Krylov coefficients y, in place in gg
+            for k in (0..krydim).rev() {
+                let mut yk = self.gg[k];
+                for j in k + 1..krydim {
+                    yk = yk - self.hes[[k, j]] * self.gg[j];
+                }
+                self.gg[k] = yk / self.hes[[k, k]];
+            }
+
+            // x = x + S^-1 V y
+            vtemp.fill(zero);
+            for k in 0..krydim {
+                let yk = self.gg[k];
+                Zip::from(&mut vtemp)
+                    .and(self.vv.row(k))
+                    .apply(|vt, &v| *vt = *vt + yk * v);
+            }
+            Zip::from(&mut xx)
+                .and(&vtemp)
+                .and(ewt)
+                .apply(|x, &v, &w| *x = *x + v / w);
+
+            if rho <= delta {
+                b.assign(&xx);
+                return Ok(());
+            }
+
+            // Restart with the residual r = P^-1 (b - J x)
+            if ntries < self.max_restarts {
+                self.jtimes(model, ctx, &xx, &mut jv)?;
+                Zip::from(&mut rr)
+                    .and(&b)
+                    .and(&jv)
+                    .apply(|r, &b, &jv| *r = b - jv);
+                self.psolve(model, ctx, &mut rr)?;
+            }
+        }
+
+        // Not converged to the requested tolerance
+        b.assign(&xx);
+        if rho < r_norm {
+            Err(LinearSolverError::ResidualReduced {})
+        } else {
+            Err(LinearSolverError::Recoverable {})
+        }
+    }
+}
+
+impl<F: IdaModel, P: Preconditioner<F>> LinearSolver<F> for Spgmr<F::Scalar, P>
+where
+    F::Scalar: std::fmt::Debug,
+{
+    fn init(&mut self, model: &F) -> Result<(), LinearSolverError> {
+        let n = model.model_size();
+        self.vv = Array::zeros((self.maxl + 1, n));
+        self.hes = Array::zeros((self.maxl + 1, self.maxl));
+        self.givens = Array::zeros((self.maxl, 2));
+        self.gg = Array::zeros(self.maxl + 1);
+        self.ytemp = Array::zeros(n);
+        self.yptemp = Array::zeros(n);
+        match &mut self.prec {
+            Some(prec) => prec.init(model),
+            None => Ok(()),
+        }
+    }
+
+    fn setup(
+        &mut self,
+        model: &mut F,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        // No matrix to evaluate, since the Jacobian-vector products are computed on demand.
+        match &mut self.prec {
+            Some(prec) => {
+                self.npe = 1;
+                prec.setup(model, ctx)
+            }
+            None => {
+                self.npe = 0;
+                Ok(())
+            }
+        }
+    }
+
+    fn solve(
+        &mut self,
+        model: &mut F,
+        b: ArrayViewMut1<F::Scalar>,
+        ctx: &LinearContext<F::Scalar>,
+    ) -> Result<(), LinearSolverError> {
+        self.gmres(model, b, ctx)
+    }
+
+    fn num_iters(&self) -> usize {
+        self.nli
+    }
+
+    /// The products with the iteration matrix use the current cj, so only the preconditioner is
+    /// out of date.
+    fn scale_correction(&self) -> bool {
+        false
+    }
+
+    fn num_setup_res_evals(&self) -> usize {
+        self.prec
+            .as_ref()
+            .map_or(0, |prec| prec.num_setup_res_evals())
+    }
+
+    fn num_prec_setups(&self) -> usize {
+        self.npe
+    }
+
+    fn num_prec_solves(&self) -> usize {
+        self.nps
+    }
+}
+
+/// 2-norm of a vector
+fn norm2<T: num_traits::Float, S: Data<Elem = T>>(v: &ArrayBase<S, Ix1>) -> T {
+    v.iter().fold(T::zero(), |acc, &x| acc + x * x).sqrt()
+}
+
+/// Weighted root-mean-square norm of a vector
+fn wrms_norm<T: num_traits::Float, S1: Data<Elem = T>, S2: Data<Elem = T>>(
+    v: &ArrayBase<S1, Ix1>,
+    w: &ArrayBase<S2, Ix1>,
+) -> T {
+    let sum = v
+        .iter()
+        .zip(w.iter())
+        .fold(T::zero(), |acc, (&v, &w)| acc + (v * w).powi(2));
+    (sum / T::from(v.len()).unwrap()).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorenz63::Lorenz63;
+    use nearly_eq::*;
+
+    #[test]
+    fn test_spgmr_solve() {
+        let mut model = Lorenz63::default();
+        let yy = array![1.0, 2.0, 3.0];
+        let yp = array![0.5, -0.5, 1.0];
+        let ewt = array![1e6, 1e7, 1e8];
+        let cj = 2.0;
+        let mut rr = Array::zeros(3);
+        model.residual(0.0, &yy, &yp, &mut rr).unwrap();
+
+        let ctx = LinearContext {
+            tt: 0.0,
+            cj,
+            hh: 0.01,
+            yy: yy.view(),
+            yp: yp.view(),
+            rr: rr.view(),
+            ewt: ewt.view(),
+            eps_newt: 0.33,
+            constraints: None,
+        };
+
+        let mut jac = Array::zeros((3, 3));
+        model
+            .jacobian(0.0, cj, &yy, &yp, &rr, &mut jac)
+            .unwrap()
+            .unwrap();
+
+        let mut ls = Spgmr::new();
+        ls.init(&model).unwrap();
+        ls.setup(&mut model, &ctx).unwrap();
+
+        let b = array![1.0, -2.0, 0.5];
+        let mut x = b.clone();
+        ls.solve(&mut model, x.view_mut(), &ctx).unwrap();
+
+        // The Krylov subspace spans the whole space after n iterations
+        assert!(LinearSolver::<Lorenz63>::num_iters(&ls) <= 3);
+        assert_nearly_eq!(jac.dot(&x), b, 1e-4);
+        assert_eq!(ls.num_jtimes(), ls.num_res_evals());
+
+        // A single iteration does not converge, but reduces the weighted residual
+        ls.set_maxl(1);
+        ls.set_max_restarts(0);
+        let mut x = b.clone();
+        match ls.solve(&mut model, x.view_mut(), &ctx) {
+            Err(LinearSolverError::ResidualReduced {}) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let r = &b - &jac.dot(&x);
+        assert!((&r * &ewt).dot(&(&r * &ewt)) < (&b * &ewt).dot(&(&b * &ewt)));
+    }
+}
@@ -24,7 +24,27 @@ impl Default for Lorenz63 {
 
 impl Lorenz63 {
     pub fn new(p: f64, r: f64, b: f64) -> Self {
-        Lorenz63 { p: p, r: r, b: b }
+        Lorenz63 { p, r, b }
+    }
+
+    /// Calculate the right hand side `yp = f(yy)` of the explicit system
+    pub fn rhs<T, S1, S2>(&self, yy: &ArrayBase<S1, Ix1>, yp: &mut ArrayBase<S2, Ix1>)
+    where
+        T: num_traits::Float,
+        S1: Data<Elem = T>,
+        S2: DataMut<Elem = T>,
+    {
+        let (p, r, b) = (
+            T::from(self.p).unwrap(),
+            T::from(self.r).unwrap(),
+            T::from(self.b).unwrap(),
+        );
+        let x = yy[0];
+        let y = yy[1];
+        let z = yy[2];
+        yp[0] = p * (y - x);
+        yp[1] = x * (r - z) - y;
+        yp[2] = x * y - b * z;
     }
 }
 
@@ -38,28 +58,70 @@ impl ModelSpec for Lorenz63 {
 }
 
 impl IdaModel for Lorenz63 {
-    fn residual<'a, S>(&mut self, v: &'a mut ArrayBase<S, Ix1>) -> &'a mut ArrayBase<S, Ix1>
+    fn residual<S1, S2, S3>(
+        &mut self,
+        _tt: f64,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
     where
-        S: DataMut<Elem = Self::Scalar>,
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>,
     {
-        let x = v[0];
-        let y = v[1];
-        let z = v[2];
-        v[0] = self.p * (y - x);
-        v[1] = x * (self.r - z) - y;
-        v[2] = x * y - self.b * z;
-        v
+        // F(y, y') = y' - f(y)
+        self.rhs(yy, rr);
+        rr.zip_mut_with(yp, |r, &yp| *r = yp - *r);
+        Ok(())
     }
 
-    fn jacobian<S>(
+    fn jacobian<S1, S2, S3, S4>(
         &mut self,
+        _tt: f64,
         cj: Self::Scalar,
-        yy: &ArrayView<S, Self::Dim>,
-        yp: &ArrayView<S, Self::Dim>,
-    ) -> ()
+        yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        jac: &mut ArrayBase<S4, Ix2>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>,
+    {
+        // F(y, y') = y' - f(y), so the iteration matrix is cj*I - df/dy
+        let x = yy[0];
+        let y = yy[1];
+        let z = yy[2];
+        jac.assign(&array![
+            [cj + self.p, -self.p, 0.0],
+            [z - self.r, cj + 1.0, x],
+            [-y, -x, cj + self.b],
+        ]);
+        Some(Ok(()))
+    }
+}
+
+impl GenericResidual for Lorenz63 {
+    fn residual_generic<T>(
+        &mut self,
+        _tt: T,
+        yy: ArrayView1<T>,
+        yp: ArrayView1<T>,
+        mut rr: ArrayViewMut1<T>,
+    ) -> Result<(), ModelError>
     where
-        S: DataMut<Elem = Self::Scalar>,
+        T: num_traits::Float
+            + num_traits::FloatConst
+            + num_traits::NumAssignRef
+            + ScalarOperand
+            + std::fmt::Debug,
     {
-        unimplemented!();
+        // F(y, y') = y' - f(y)
+        self.rhs(&yy, &mut rr);
+        rr.zip_mut_with(&yp, |r, &yp| *r = yp - *r);
+        Ok(())
     }
 }
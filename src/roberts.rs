@@ -0,0 +1,62 @@
+//! Robertson chemical kinetics problem, a stiff index-1 DAE
+//! (the `idaRoberts_dns` example from Sundials)
+
+use ndarray::*;
+
+use crate::traits::*;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Roberts {}
+
+impl ModelSpec for Roberts {
+    type Scalar = f64;
+    type Dim = Ix1;
+
+    fn model_size(&self) -> usize {
+        3
+    }
+}
+
+impl IdaModel for Roberts {
+    fn residual<S1, S2, S3>(
+        &mut self,
+        _tt: f64,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>,
+    {
+        rr[0] = -0.04 * yy[0] + 1.0e4 * yy[1] * yy[2];
+        rr[1] = -rr[0] - 3.0e7 * yy[1] * yy[1] - yp[1];
+        rr[0] -= yp[0];
+        rr[2] = yy[0] + yy[1] + yy[2] - 1.0;
+        Ok(())
+    }
+
+    fn jacobian<S1, S2, S3, S4>(
+        &mut self,
+        _tt: f64,
+        cj: Self::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        jac: &mut ArrayBase<S4, Ix2>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>,
+    {
+        jac.assign(&array![
+            [-0.04 - cj, 1.0e4 * yy[2], 1.0e4 * yy[1]],
+            [0.04, -1.0e4 * yy[2] - 6.0e7 * yy[1] - cj, -1.0e4 * yy[1]],
+            [1.0, 1.0, 1.0],
+        ]);
+        Some(Ok(()))
+    }
+}
@@ -0,0 +1,97 @@
+//! Model wrappers shared by the unit tests
+
+use ndarray::*;
+
+use crate::traits::*;
+
+/// Wraps a model, delegating the residual (and the sparsity pattern) to it, and counting the
+/// residual calls. The Jacobian of the inner model is either hidden, so that the solvers fall
+/// back to difference quotients, or passed through a modifying function, e.g. to introduce
+/// an error.
+#[derive(Clone, Debug)]
+pub struct Wrapped<M: ModelSpec> {
+    /// the wrapped model
+    pub inner: M,
+    /// applied to the Jacobian of the inner model, or `None` to hide the Jacobian
+    pub jacobian: Option<fn(ArrayViewMut2<M::Scalar>)>,
+    /// number of residual calls
+    pub nres: u64,
+}
+
+impl<M: ModelSpec> Wrapped<M> {
+    /// Wraps `inner` without its Jacobians
+    pub fn dq(inner: M) -> Self {
+        Wrapped {
+            inner,
+            jacobian: None,
+            nres: 0,
+        }
+    }
+
+    /// Wraps `inner` with its dense Jacobian modified by `jacobian`
+    pub fn with_jacobian(inner: M, jacobian: fn(ArrayViewMut2<M::Scalar>)) -> Self {
+        Wrapped {
+            inner,
+            jacobian: Some(jacobian),
+            nres: 0,
+        }
+    }
+}
+
+impl<M: ModelSpec> ModelSpec for Wrapped<M> {
+    type Scalar = M::Scalar;
+    type Dim = M::Dim;
+
+    fn model_size(&self) -> usize {
+        self.inner.model_size()
+    }
+}
+
+impl<M: IdaModel> IdaModel for Wrapped<M> {
+    fn residual<S1, S2, S3>(
+        &mut self,
+        tt: M::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = M::Scalar>,
+        S2: Data<Elem = M::Scalar>,
+        S3: DataMut<Elem = M::Scalar>,
+    {
+        self.nres += 1;
+        self.inner.residual(tt, yy, yp, rr)
+    }
+
+    fn jacobian<S1, S2, S3, S4>(
+        &mut self,
+        tt: M::Scalar,
+        cj: M::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &ArrayBase<S3, Ix1>,
+        jac: &mut ArrayBase<S4, Ix2>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = M::Scalar>,
+        S2: Data<Elem = M::Scalar>,
+        S3: Data<Elem = M::Scalar>,
+        S4: DataMut<Elem = M::Scalar>,
+    {
+        let modify = self.jacobian?;
+        let retval = self.inner.jacobian(tt, cj, yy, yp, rr, jac)?;
+        if retval.is_ok() {
+            modify(jac.view_mut());
+        }
+        Some(retval)
+    }
+}
+
+impl<M: BandJacobian> BandJacobian for Wrapped<M> {}
+
+impl<M: SparseJacobian> SparseJacobian for Wrapped<M> {
+    fn sparsity_pattern(&self) -> Vec<(usize, usize)> {
+        self.inner.sparsity_pattern()
+    }
+}
@@ -2,6 +2,10 @@
 
 use ndarray::*;
 
+use failure::Fail;
+
+use crate::linear::{BandMatrix, SparseMatrix};
+
 /// Model specification
 pub trait ModelSpec: Clone {
     type Scalar: num_traits::Float;
@@ -9,22 +13,241 @@ pub trait ModelSpec: Clone {
     fn model_size(&self) -> <Ix1 as Dimension>::Pattern;
 }
 
-/// Core implementation for explicit schemes
+/// Errors returned by the user-supplied model functions
+#[derive(Debug, Fail)]
+pub enum ModelError {
+    /// A recoverable error (IDA_RES_RECVR), e.g. the model was evaluated at an invalid state. The
+    /// solver may retry with a smaller step size.
+    #[fail(display = "The model function had a recoverable error")]
+    Recoverable {},
+
+    /// A non-recoverable error, which stops the integration.
+    #[fail(display = "The model function had a non-recoverable error")]
+    Fatal {},
+}
+
+/// Core implementation of an implicit DAE system F(t, y, y') = 0
 pub trait IdaModel: ModelSpec {
-    /// Calculate right hand side (rhs) of Explicit from current state
-    fn residual<'a, S>(&mut self, v: &'a mut ArrayBase<S, Ix1>) -> &'a mut ArrayBase<S, Ix1>
+    /// Calculate the residual `rr = F(tt, yy, yp)`
+    fn residual<S1, S2, S3>(
+        &mut self,
+        tt: Self::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
     where
-        S: DataMut<Elem = Self::Scalar>;
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>;
 
-    /// Calculate the Jacobian
-    fn jacobian<S>(
+    /// Calculate the iteration matrix `jac = dF/dy + cj*dF/dy'` at (tt, yy, yp), where rr holds
+    /// the current residual F(tt, yy, yp), for the `Dense` linear solver. `jac` is zeroed before
+    /// the call.
+    ///
+    /// Returns `None` if the model does not supply the Jacobian, in which case it is approximated
+    /// column by column by difference quotients of `residual()`.
+    fn jacobian<S1, S2, S3, S4>(
         &mut self,
+        _tt: Self::Scalar,
+        _cj: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        _jac: &mut ArrayBase<S4, Ix2>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>,
+    {
+        None
+    }
+
+    /// Calculate the product `jv = (dF/dy + cj*dF/dy') v` of the iteration matrix at
+    /// (tt, yy, yp) with the vector v, for matrix-free linear solvers such as `Spgmr`.
+    ///
+    /// Returns `None` if the model does not supply the product, in which case it is approximated
+    /// by difference quotients of `residual()`.
+    fn jac_times_vec<S1, S2, S3, S4>(
+        &mut self,
+        _tt: Self::Scalar,
+        _cj: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _v: &ArrayBase<S3, Ix1>,
+        _jv: &mut ArrayBase<S4, Ix1>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>,
+    {
+        None
+    }
+
+    /// Number of root functions g_i(t, y, y'), whose zero crossings are located by
+    /// `Ida::solve()`
+    fn num_roots(&self) -> usize {
+        0
+    }
+
+    /// Calculate the root functions `gout = g(tt, yy, yp)`, for models with `num_roots() > 0`
+    fn roots<S1, S2, S3>(
+        &mut self,
+        _tt: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _gout: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>,
+    {
+        Ok(())
+    }
+}
+
+/// Models whose residual is written generically over the scalar type, so that it can be
+/// evaluated with `dual::Dual` numbers to compute exact Jacobians, e.g. by wrapping the model in
+/// `dual::AutoJacobian`.
+pub trait GenericResidual: ModelSpec {
+    /// Calculate the residual `rr = F(tt, yy, yp)` with scalar type T. Model parameters of type
+    /// `Self::Scalar` can be converted with `T::from()`.
+    fn residual_generic<T>(
+        &mut self,
+        tt: T,
+        yy: ArrayView1<T>,
+        yp: ArrayView1<T>,
+        rr: ArrayViewMut1<T>,
+    ) -> Result<(), ModelError>
+    where
+        T: num_traits::Float
+            + num_traits::FloatConst
+            + num_traits::NumAssignRef
+            + ScalarOperand
+            + std::fmt::Debug;
+}
+
+/// Models with a banded iteration matrix, for use with the `Band` linear solver
+pub trait BandJacobian: IdaModel {
+    /// Calculate the iteration matrix `jac = dF/dy + cj*dF/dy'` at (tt, yy, yp), where rr holds
+    /// the current residual F(tt, yy, yp). `jac` is zeroed before the call, and only elements
+    /// within its band may be set.
+    ///
+    /// Returns `None` if the model does not supply the Jacobian, in which case it is approximated
+    /// by difference quotients of `residual()`, perturbing columns that are more than the
+    /// bandwidth apart together.
+    fn jacobian_band<S1, S2, S3>(
+        &mut self,
+        _tt: Self::Scalar,
+        _cj: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        _jac: &mut BandMatrix<Self::Scalar>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+    {
+        None
+    }
+}
+
+/// Models with a sparse iteration matrix, for use with the `SparseLu` linear solver and the
+/// sparse preconditioners
+pub trait SparseJacobian: IdaModel {
+    /// The (row, col) positions of all possible non-zeros of the iteration matrix
+    fn sparsity_pattern(&self) -> Vec<(usize, usize)>;
+
+    /// Calculate the iteration matrix `jac = dF/dy + cj*dF/dy'` at (tt, yy, yp), where rr holds
+    /// the current residual F(tt, yy, yp). `jac` has the pattern given by `sparsity_pattern()`,
+    /// and is zeroed before the call.
+    ///
+    /// Returns `None` if the model does not supply the Jacobian, in which case it is approximated
+    /// by difference quotients of `residual()`, perturbing each group of a column coloring of the
+    /// pattern together.
+    fn jacobian_sparse<S1, S2, S3>(
+        &mut self,
+        _tt: Self::Scalar,
+        _cj: Self::Scalar,
+        _yy: &ArrayBase<S1, Ix1>,
+        _yp: &ArrayBase<S2, Ix1>,
+        _rr: &ArrayBase<S3, Ix1>,
+        _jac: &mut SparseMatrix<Self::Scalar>,
+    ) -> Option<Result<(), ModelError>>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+    {
+        None
+    }
+}
+
+/// Models that supply their own preconditioner `P ~ dF/dy + cj*dF/dy'` for the Krylov linear
+/// solvers, for use with `ModelPrec`
+pub trait ModelPreconditioner: IdaModel {
+    /// Evaluate and preprocess the preconditioner at (tt, yy, yp) with the current cj, where rr
+    /// holds the current residual F(tt, yy, yp)
+    fn prec_setup<S1, S2, S3>(
+        &mut self,
+        tt: Self::Scalar,
+        cj: Self::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>;
+
+    /// Solve `P z = r` with the preconditioner from the last `prec_setup()`, overwriting zvec
+    /// (which holds r on entry) with the solution z
+    fn prec_solve<S1, S2, S3, S4>(
+        &mut self,
+        tt: Self::Scalar,
         cj: Self::Scalar,
-        yy: &ArrayView<S, Ix1>,
-        yp: &ArrayView<S, Ix1>,
-    ) -> ()
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        rr: &ArrayBase<S3, Ix1>,
+        zvec: &mut ArrayBase<S4, Ix1>,
+    ) -> Result<(), ModelError>
+    where
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: Data<Elem = Self::Scalar>,
+        S4: DataMut<Elem = Self::Scalar>;
+}
+
+/// Models decomposed into subdomains, which supply a local approximation G of the residual on
+/// each subdomain, for use with the `Bbd` preconditioner (IDABBDLocalFn)
+pub trait LocalResidual: IdaModel {
+    /// Sizes of the subdomains, which partition the components in order
+    fn subdomain_sizes(&self) -> Vec<usize>;
+
+    /// Calculate `gval = G(tt, yy, yp)` on subdomain `block`, an approximation of the residual F
+    /// on the components of the subdomain. yy and yp hold all components, so that any values
+    /// from neighbouring subdomains that G depends on are available, whereas gval holds just
+    /// the components of the subdomain.
+    fn local_residual<S1, S2, S3>(
+        &mut self,
+        block: usize,
+        tt: Self::Scalar,
+        yy: &ArrayBase<S1, Ix1>,
+        yp: &ArrayBase<S2, Ix1>,
+        gval: &mut ArrayBase<S3, Ix1>,
+    ) -> Result<(), ModelError>
     where
-        S: DataMut<Elem = Self::Scalar>;
+        S1: Data<Elem = Self::Scalar>,
+        S2: Data<Elem = Self::Scalar>,
+        S3: DataMut<Elem = Self::Scalar>;
 }
 
 /// Constants for Ida